@@ -61,3 +61,97 @@ Create the name of the service account to use
 {{- default "default" .Values.serviceAccount.name }}
 {{- end }}
 {{- end }}
+
+{{/*
+Environment of the exporter container, shared by the cronjob and the daemon deployment.
+*/}}
+{{- define "jarvis-tesla-exporter.env" -}}
+- name: RUST_LOG
+  value: {{ .Values.logLevel }}
+- name: TIMEOUT_SECONDS
+  valueFrom:
+    configMapKeyRef:
+      key: timeout-seconds
+      name: {{ include "jarvis-tesla-exporter.fullname" . }}
+- name: TESLA_API_CONNECT_TIMEOUT_SECONDS
+  valueFrom:
+    configMapKeyRef:
+      key: tesla-api-connect-timeout-seconds
+      name: {{ include "jarvis-tesla-exporter.fullname" . }}
+- name: TESLA_API_REQUEST_TIMEOUT_SECONDS
+  valueFrom:
+    configMapKeyRef:
+      key: tesla-api-request-timeout-seconds
+      name: {{ include "jarvis-tesla-exporter.fullname" . }}
+- name: TESLA_API_VEHICLE_DATA_TIMEOUT_SECONDS
+  valueFrom:
+    configMapKeyRef:
+      key: tesla-api-vehicle-data-timeout-seconds
+      name: {{ include "jarvis-tesla-exporter.fullname" . }}
+- name: TESLA_API_STREAMING_TIMEOUT_SECONDS
+  valueFrom:
+    configMapKeyRef:
+      key: tesla-api-streaming-timeout-seconds
+      name: {{ include "jarvis-tesla-exporter.fullname" . }}
+- name: TESLA_API_WAKE_UP_TIMEOUT_SECONDS
+  valueFrom:
+    configMapKeyRef:
+      key: tesla-api-wake-up-timeout-seconds
+      name: {{ include "jarvis-tesla-exporter.fullname" . }}
+{{- with .Values.config.teslaApi.recordCassette }}
+- name: TESLA_API_RECORD_CASSETTE
+  value: {{ . }}
+{{- end }}
+- name: NATS_HOST
+  valueFrom:
+    configMapKeyRef:
+      name: {{ include "jarvis-tesla-exporter.fullname" . }}
+      key: nats-host
+- name: NATS_SUBJECT
+  valueFrom:
+    configMapKeyRef:
+      name: {{ include "jarvis-tesla-exporter.fullname" . }}
+      key: nats-subject
+- name: NATS_EVENTS_SUBJECT
+  valueFrom:
+    configMapKeyRef:
+      name: {{ include "jarvis-tesla-exporter.fullname" . }}
+      key: nats-events-subject
+- name: MQTT_HOST
+  valueFrom:
+    configMapKeyRef:
+      name: {{ include "jarvis-tesla-exporter.fullname" . }}
+      key: mqtt-host
+- name: MQTT_PORT
+  valueFrom:
+    configMapKeyRef:
+      name: {{ include "jarvis-tesla-exporter.fullname" . }}
+      key: mqtt-port
+- name: MQTT_TOPIC_PREFIX
+  valueFrom:
+    configMapKeyRef:
+      name: {{ include "jarvis-tesla-exporter.fullname" . }}
+      key: mqtt-topic-prefix
+- name: MQTT_DISCOVERY_PREFIX
+  valueFrom:
+    configMapKeyRef:
+      name: {{ include "jarvis-tesla-exporter.fullname" . }}
+      key: mqtt-discovery-prefix
+{{- with .Values.config.mqtt.credentialsSecretName }}
+- name: MQTT_USERNAME
+  valueFrom:
+    secretKeyRef:
+      name: {{ . }}
+      key: username
+- name: MQTT_PASSWORD
+  valueFrom:
+    secretKeyRef:
+      name: {{ . }}
+      key: password
+{{- end }}
+- name: MEASUREMENT_FILE_CONFIG_MAP_NAME
+  value: {{ include "jarvis-tesla-exporter.fullname" . }}
+{{- with .Values.config.extraEnv }}
+{{ toYaml . }}
+{{- end }}
+{{- end }}
@@ -0,0 +1,203 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+const MAX_OBSERVATIONS: usize = 30;
+// extrapolating from a low state of charge amplifies the rounding in the reported battery level
+const MIN_BATTERY_LEVEL: f64 = 50.0;
+// a cold battery reports less rated range than it can deliver once warmed up
+const MIN_OUTSIDE_TEMPERATURE: f64 = 10.0;
+const MAX_DEVIATION_FROM_MEDIAN: f64 = 0.05;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BatteryObservation {
+    pub observed_at: DateTime<Utc>,
+    /// odometer in meters, used to record a single observation per finished charge
+    pub odometer: f64,
+    /// state of charge in percent
+    pub battery_level: f64,
+    /// rated range in meters
+    pub battery_range: f64,
+    /// outside temperature in degrees celsius
+    pub outside_temperature: Option<f64>,
+    pub battery_heater_on: bool,
+}
+
+impl BatteryObservation {
+    /// rated range in meters extrapolated to a 100% charged battery
+    pub fn full_range(&self) -> f64 {
+        self.battery_range / self.battery_level * 100.0
+    }
+
+    fn is_usable(&self) -> bool {
+        if self.battery_level < MIN_BATTERY_LEVEL || self.battery_level > 100.0 {
+            return false;
+        }
+
+        if self.battery_heater_on {
+            return false;
+        }
+
+        if let Some(outside_temperature) = self.outside_temperature {
+            if outside_temperature < MIN_OUTSIDE_TEMPERATURE {
+                return false;
+            }
+        }
+
+        self.battery_range > 0.0
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BatteryHistory {
+    #[serde(default)]
+    pub observations: Vec<BatteryObservation>,
+    /// first estimated full range in meters, the baseline for degradation
+    #[serde(default)]
+    pub initial_full_range: Option<f64>,
+}
+
+impl BatteryHistory {
+    /// Adds an observation taken after a charge finished; returns false if it was discarded.
+    pub fn add_observation(&mut self, observation: BatteryObservation) -> bool {
+        if !observation.is_usable() {
+            return false;
+        }
+
+        if let Some(last_observation) = self.observations.last() {
+            if (last_observation.odometer - observation.odometer).abs() < 1.0 {
+                // car hasn't moved since, so this is still the same charge
+                return false;
+            }
+        }
+
+        self.observations.push(observation);
+        if self.observations.len() > MAX_OBSERVATIONS {
+            let excess = self.observations.len() - MAX_OBSERVATIONS;
+            self.observations.drain(..excess);
+        }
+
+        if self.initial_full_range.is_none() {
+            self.initial_full_range = self.estimated_full_range();
+        }
+
+        true
+    }
+
+    /// Averages the full range of all observations within 5% of the median, to ignore outliers.
+    pub fn estimated_full_range(&self) -> Option<f64> {
+        let mut full_ranges: Vec<f64> = self
+            .observations
+            .iter()
+            .map(BatteryObservation::full_range)
+            .collect();
+
+        if full_ranges.is_empty() {
+            return None;
+        }
+
+        full_ranges.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        let middle = full_ranges.len() / 2;
+        let median = if full_ranges.len() % 2 == 0 {
+            (full_ranges[middle - 1] + full_ranges[middle]) / 2.0
+        } else {
+            full_ranges[middle]
+        };
+
+        let inliers: Vec<f64> = full_ranges
+            .into_iter()
+            .filter(|r| ((r - median) / median).abs() <= MAX_DEVIATION_FROM_MEDIAN)
+            .collect();
+
+        if inliers.is_empty() {
+            return Some(median);
+        }
+
+        Some(inliers.iter().sum::<f64>() / inliers.len() as f64)
+    }
+
+    /// Loss of estimated full range in percent compared to the first estimate.
+    pub fn degradation_percentage(&self) -> Option<f64> {
+        match (self.initial_full_range, self.estimated_full_range()) {
+            (Some(initial_full_range), Some(estimated_full_range)) if initial_full_range > 0.0 => {
+                Some((1.0 - estimated_full_range / initial_full_range) * 100.0)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observation(odometer: f64, battery_level: f64, battery_range: f64) -> BatteryObservation {
+        BatteryObservation {
+            observed_at: Utc::now(),
+            odometer,
+            battery_level,
+            battery_range,
+            outside_temperature: Some(18.0),
+            battery_heater_on: false,
+        }
+    }
+
+    #[test]
+    fn add_observation_extrapolates_full_range() {
+        let mut history = BatteryHistory::default();
+
+        // act
+        let added = history.add_observation(observation(1000.0, 80.0, 400000.0));
+
+        assert!(added);
+        assert_eq!(history.estimated_full_range(), Some(500000.0));
+        assert_eq!(history.initial_full_range, Some(500000.0));
+        assert_eq!(history.degradation_percentage(), Some(0.0));
+    }
+
+    #[test]
+    fn add_observation_discards_low_level_cold_and_duplicate_observations() {
+        let mut history = BatteryHistory::default();
+        history.add_observation(observation(1000.0, 80.0, 400000.0));
+
+        let mut cold_observation = observation(2000.0, 80.0, 360000.0);
+        cold_observation.outside_temperature = Some(-5.0);
+
+        // act
+        assert!(!history.add_observation(observation(1500.0, 30.0, 150000.0)));
+        assert!(!history.add_observation(cold_observation));
+        assert!(!history.add_observation(observation(1000.0, 90.0, 450000.0)));
+
+        assert_eq!(history.observations.len(), 1);
+    }
+
+    #[test]
+    fn estimated_full_range_ignores_outliers() {
+        let mut history = BatteryHistory::default();
+        history.add_observation(observation(1000.0, 80.0, 400000.0));
+        history.add_observation(observation(2000.0, 80.0, 396000.0));
+        history.add_observation(observation(3000.0, 80.0, 392000.0));
+        history.add_observation(observation(4000.0, 80.0, 300000.0));
+
+        // act
+        let estimated_full_range = history.estimated_full_range().unwrap();
+
+        assert_eq!(estimated_full_range, 495000.0);
+        assert!((history.degradation_percentage().unwrap() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn add_observation_keeps_a_rolling_history() {
+        let mut history = BatteryHistory::default();
+
+        // act
+        for i in 0..40 {
+            history.add_observation(observation(1000.0 * i as f64, 80.0, 400000.0));
+        }
+
+        assert_eq!(history.observations.len(), MAX_OBSERVATIONS);
+        assert_eq!(history.observations[0].odometer, 10000.0);
+    }
+}
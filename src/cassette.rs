@@ -0,0 +1,408 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+const REDACTED: &str = "REDACTED";
+
+/// Keys whose values are replaced, in request and response bodies as well as streaming frames.
+const SECRET_KEYS: &[&str] = &[
+    "access_token",
+    "refresh_token",
+    "id_token",
+    "token",
+    "tokens",
+    "code",
+    "code_verifier",
+];
+
+const COORDINATE_KEYS: &[&str] = &[
+    "latitude",
+    "longitude",
+    "native_latitude",
+    "native_longitude",
+    "corrected_latitude",
+    "corrected_longitude",
+    "active_route_latitude",
+    "active_route_longitude",
+];
+
+// positions of est_lat and est_lng in the value of a streaming `data:update` frame
+const STREAMING_COORDINATE_INDICES: &[usize] = &[6, 7];
+
+/// Api traffic of a run, with tokens and coordinates redacted so it can be attached to a bug report.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Interaction {
+    Http(HttpInteraction),
+    WebSocket(WebSocketInteraction),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpInteraction {
+    pub method: String,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_body: Option<Value>,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_seconds: Option<u64>,
+    /// json bodies are stored as json for readability, anything else as a string
+    pub response_body: Value,
+}
+
+impl HttpInteraction {
+    pub fn response_body(&self) -> String {
+        match &self.response_body {
+            Value::String(body) => body.clone(),
+            body => body.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WebSocketInteraction {
+    pub url: String,
+    pub frames: Vec<WebSocketFrame>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WebSocketFrame {
+    pub direction: FrameDirection,
+    pub data: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum FrameDirection {
+    Sent,
+    Received,
+}
+
+/// Records the traffic of the `TeslaApiClient` into a cassette file, or serves a recorded
+/// cassette back instead of calling the api.
+pub enum CassetteMode {
+    Record {
+        path: String,
+        cassette: Mutex<Cassette>,
+    },
+    Replay {
+        cassette: Mutex<Cassette>,
+    },
+}
+
+impl CassetteMode {
+    pub fn record(path: String) -> Self {
+        info!("Recording api traffic to cassette {}", path);
+
+        CassetteMode::Record {
+            path,
+            cassette: Mutex::new(Cassette::default()),
+        }
+    }
+
+    pub fn replay(path: &str) -> Result<Self, Box<dyn Error>> {
+        info!("Replaying api traffic from cassette {}", path);
+
+        let cassette: Cassette = serde_json::from_str(&fs::read_to_string(path)?)?;
+
+        Ok(CassetteMode::Replay {
+            cassette: Mutex::new(cassette),
+        })
+    }
+
+    pub fn from_env() -> Result<Option<Self>, Box<dyn Error>> {
+        if let Ok(path) = env::var("TESLA_API_REPLAY_CASSETTE") {
+            return Ok(Some(Self::replay(&path)?));
+        }
+
+        Ok(env::var("TESLA_API_RECORD_CASSETTE").ok().map(Self::record))
+    }
+
+    pub fn is_replay(&self) -> bool {
+        matches!(self, CassetteMode::Replay { .. })
+    }
+
+    pub fn record_http(
+        &self,
+        method: &str,
+        url: &str,
+        request_body: Option<&[u8]>,
+        status: u16,
+        retry_after_seconds: Option<u64>,
+        response_body: &str,
+    ) {
+        self.record_change(|cassette| {
+            cassette
+                .interactions
+                .push(Interaction::Http(HttpInteraction {
+                    method: method.to_string(),
+                    url: url.to_string(),
+                    request_body: request_body.map(redacted_body),
+                    status,
+                    retry_after_seconds,
+                    response_body: redacted_body(response_body.as_bytes()),
+                }));
+        });
+    }
+
+    /// Takes the first recorded response to a request with this method and url, so polling the
+    /// same url gets the responses in the recorded order.
+    pub fn replay_http(&self, method: &str, url: &str) -> Option<HttpInteraction> {
+        self.take(|interaction| match interaction {
+            Interaction::Http(http) if http.method == method && http.url == url => {
+                Some(http.clone())
+            }
+            _ => None,
+        })
+    }
+
+    /// Starts recording a websocket connection, returning the index to record its frames with.
+    pub fn record_websocket(&self, url: &str) -> Option<usize> {
+        let mut index = None;
+        self.record_change(|cassette| {
+            index = Some(cassette.interactions.len());
+            cassette
+                .interactions
+                .push(Interaction::WebSocket(WebSocketInteraction {
+                    url: url.to_string(),
+                    frames: vec![],
+                }));
+        });
+
+        index
+    }
+
+    pub fn record_frame(&self, index: usize, direction: FrameDirection, data: &[u8]) {
+        self.record_change(|cassette| {
+            if let Some(Interaction::WebSocket(websocket)) = cassette.interactions.get_mut(index) {
+                websocket.frames.push(WebSocketFrame {
+                    direction,
+                    data: redacted_frame(data),
+                });
+            }
+        });
+    }
+
+    /// Received frames of the first recorded connection to this url.
+    pub fn replay_websocket(&self, url: &str) -> Option<Vec<Vec<u8>>> {
+        self.take(|interaction| match interaction {
+            Interaction::WebSocket(websocket) if websocket.url == url => Some(
+                websocket
+                    .frames
+                    .iter()
+                    .filter(|frame| frame.direction == FrameDirection::Received)
+                    .map(|frame| match &frame.data {
+                        Value::String(data) => data.clone().into_bytes(),
+                        data => data.to_string().into_bytes(),
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        })
+    }
+
+    /// Applies `change` and writes the cassette right away, so it survives an interrupted run.
+    fn record_change<F>(&self, change: F)
+    where
+        F: FnOnce(&mut Cassette),
+    {
+        if let CassetteMode::Record { path, cassette } = self {
+            let mut cassette = cassette.lock().unwrap();
+            change(&mut cassette);
+
+            let result = serde_json::to_string_pretty(&*cassette)
+                .map_err(|e| e.to_string())
+                .and_then(|json| fs::write(path, json).map_err(|e| e.to_string()));
+            if let Err(e) = result {
+                warn!("Failed writing cassette {}: {}", path, e);
+            }
+        }
+    }
+
+    fn take<T, F>(&self, matches: F) -> Option<T>
+    where
+        F: Fn(&Interaction) -> Option<T>,
+    {
+        if let CassetteMode::Replay { cassette } = self {
+            let mut cassette = cassette.lock().unwrap();
+            let (index, result) = cassette
+                .interactions
+                .iter()
+                .enumerate()
+                .find_map(|(index, interaction)| matches(interaction).map(|r| (index, r)))?;
+            cassette.interactions.remove(index);
+
+            return Some(result);
+        }
+
+        None
+    }
+}
+
+fn redacted_body(body: &[u8]) -> Value {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(mut value) => {
+            redact(&mut value);
+            value
+        }
+        Err(_) => Value::String(String::from_utf8_lossy(body).into_owned()),
+    }
+}
+
+fn redacted_frame(data: &[u8]) -> Value {
+    let mut value = redacted_body(data);
+
+    if value["msg_type"] == "data:update" {
+        if let Some(values) = value["value"].as_str() {
+            let values: Vec<&str> = values
+                .split(',')
+                .enumerate()
+                .map(|(index, value)| {
+                    if STREAMING_COORDINATE_INDICES.contains(&index) {
+                        "0.0"
+                    } else {
+                        value
+                    }
+                })
+                .collect();
+            value["value"] = Value::String(values.join(","));
+        }
+    }
+
+    value
+}
+
+/// Replaces secrets and coordinates, keeping the shape so the redacted body still deserializes.
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if SECRET_KEYS.contains(&key.as_str()) {
+                    redact_secret(value);
+                } else if COORDINATE_KEYS.contains(&key.as_str()) {
+                    if value.is_number() {
+                        *value = json!(0.0);
+                    }
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+fn redact_secret(value: &mut Value) {
+    match value {
+        Value::String(_) => *value = Value::String(REDACTED.to_string()),
+        Value::Array(values) => values.iter_mut().for_each(redact_secret),
+        Value::Object(map) => map.values_mut().for_each(redact_secret),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_http_redacts_tokens_and_coordinates() {
+        let path = env::temp_dir().join(format!("cassette-{}.json", std::process::id()));
+        let cassette_mode = CassetteMode::record(path.to_string_lossy().into_owned());
+
+        // act
+        cassette_mode.record_http(
+            "POST",
+            "https://auth.tesla.com/oauth2/v3/token",
+            Some(br#"{"grant_type":"refresh_token","refresh_token":"abcd"}"#),
+            200,
+            None,
+            r#"{"access_token":"efgh","expires_in":28800,"drive_state":{"latitude":52.37,"heading":154},"tokens":["a1","b2"]}"#,
+        );
+
+        let cassette: Cassette = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        match &cassette.interactions[0] {
+            Interaction::Http(http) => {
+                assert_eq!(
+                    http.request_body,
+                    Some(json!({"grant_type": "refresh_token", "refresh_token": "REDACTED"}))
+                );
+                assert_eq!(
+                    http.response_body,
+                    json!({
+                        "access_token": "REDACTED",
+                        "expires_in": 28800,
+                        "drive_state": {"latitude": 0.0, "heading": 154},
+                        "tokens": ["REDACTED", "REDACTED"]
+                    })
+                );
+            }
+            interaction => panic!("expected http interaction, got {:?}", interaction),
+        }
+    }
+
+    #[test]
+    fn redacted_frame_replaces_streaming_coordinates() {
+        // act
+        let frame = redacted_frame(
+            br#"{"msg_type":"data:update","tag":"1","value":"1687252000000,,12345.6,49,10,180,52.37,4.89,-7,,150,140,180"}"#,
+        );
+
+        assert_eq!(
+            frame["value"],
+            "1687252000000,,12345.6,49,10,180,0.0,0.0,-7,,150,140,180"
+        );
+    }
+
+    #[test]
+    fn replay_http_serves_responses_in_recorded_order() {
+        let interaction = |state: &str| {
+            Interaction::Http(HttpInteraction {
+                method: "POST".into(),
+                url: "https://owner-api.teslamotors.com/api/1/vehicles/1/wake_up".into(),
+                request_body: None,
+                status: 200,
+                retry_after_seconds: None,
+                response_body: json!({ "response": { "state": state } }),
+            })
+        };
+        let cassette_mode = CassetteMode::Replay {
+            cassette: Mutex::new(Cassette {
+                interactions: vec![interaction("asleep"), interaction("online")],
+            }),
+        };
+        let url = "https://owner-api.teslamotors.com/api/1/vehicles/1/wake_up";
+
+        // act
+        let responses: Vec<Option<String>> = (0..3)
+            .map(|_| {
+                cassette_mode
+                    .replay_http("POST", url)
+                    .map(|http| http.response_body())
+            })
+            .collect();
+
+        assert_eq!(
+            responses,
+            vec![
+                Some(r#"{"response":{"state":"asleep"}}"#.to_string()),
+                Some(r#"{"response":{"state":"online"}}"#.to_string()),
+                None
+            ]
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use crate::model::{TeslaBackend, TeslaRegion};
+use clap::{Args, Parser, Subcommand};
+use std::net::SocketAddr;
+
+/// Without a subcommand the exporter runs once, like it does in the cronjob.
+#[derive(Parser, Debug)]
+#[command(about = "Exports Tesla vehicle measurements to jarvis")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+// the command-key subcommand is named after the command key it generates, not after this enum
+#[allow(clippy::enum_variant_names)]
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Logs in to a Tesla account to obtain the initial refresh token
+    Login(LoginArgs),
+    /// Lists the vehicles of all configured accounts
+    Vehicles(ConfigArgs),
+    /// Prints the vehicle data of a vehicle as json
+    Data(VehicleArgs),
+    /// Prints the updates of the streaming api for a vehicle as json until interrupted
+    Stream(VehicleArgs),
+    /// Runs a single measurement and prints the measurements as json instead of publishing them
+    Measure(MeasureArgs),
+    /// Runs a measurement in the cluster like the exporter, but writes the measurements to stdout
+    /// or a file instead of publishing them and never stores any state
+    Shadow(ShadowArgs),
+    /// Keeps running the exporter at an interval instead of once
+    Daemon(DaemonArgs),
+    /// Adjusts the charging current of a vehicle to the solar surplus published by other exporters
+    Controller(ControllerArgs),
+    /// Generates the key pair for signed commands if missing and prints the public key to enroll
+    CommandKey(CommandKeyArgs),
+    /// Configures the vehicles of all accounts to stream fleet telemetry to the daemon's receiver
+    TelemetryConfig(TelemetryConfigArgs),
+    /// Prints the JSON Schema of the config file
+    ConfigSchema,
+}
+
+#[derive(Args, Debug)]
+pub struct ConfigArgs {
+    #[arg(long, default_value = "config.yaml")]
+    pub config: String,
+}
+
+#[derive(Args, Debug)]
+pub struct VehicleArgs {
+    #[command(flatten)]
+    pub config_args: ConfigArgs,
+    pub vin: String,
+}
+
+#[derive(Args, Debug)]
+pub struct MeasureArgs {
+    #[command(flatten)]
+    pub config_args: ConfigArgs,
+    #[arg(long, default_value = "vehicle-state.json")]
+    pub state_file: String,
+    /// json file with the measurements of an earlier run, to continue counters from
+    #[arg(long)]
+    pub last_measurements_file: Option<String>,
+    /// don't update the state file
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct LoginArgs {
+    #[arg(long, value_enum, default_value_t = TeslaBackend::OwnerApi)]
+    pub backend: TeslaBackend,
+    #[arg(long, value_enum, default_value_t = TeslaRegion::NorthAmerica)]
+    pub region: TeslaRegion,
+    /// client id of a registered fleet api application, defaults to the owner api one
+    #[arg(long)]
+    pub client_id: Option<String>,
+    /// client secret of the registered fleet api application, to exchange the code with
+    #[arg(long)]
+    pub client_secret: Option<String>,
+    /// defaults to the void callback page of the tesla auth server for the region
+    #[arg(long)]
+    pub redirect_uri: Option<String>,
+    /// write the refresh token into this kubernetes secret instead of printing it
+    #[arg(long)]
+    pub secret_name: Option<String>,
+    #[arg(long, default_value = "refresh-token")]
+    pub secret_key: String,
+    /// defaults to the namespace of the current kubernetes context
+    #[arg(long)]
+    pub namespace: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct ShadowArgs {
+    /// file to write the measurements to, - for stdout
+    #[arg(long, default_value = "-")]
+    pub output: String,
+    /// include the samples that differ from the last published measurements
+    #[arg(long)]
+    pub diff: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct DaemonArgs {
+    #[arg(long, default_value_t = 300)]
+    pub interval_seconds: u64,
+    /// serve prometheus metrics on this address, like 0.0.0.0:9090
+    #[arg(long)]
+    pub metrics_address: Option<SocketAddr>,
+    /// receive fleet telemetry on this address and use it instead of the streaming api
+    #[arg(long)]
+    pub fleet_telemetry_address: Option<SocketAddr>,
+    #[arg(long, default_value = "/fleet-telemetry/tls.crt")]
+    pub fleet_telemetry_cert_file: String,
+    #[arg(long, default_value = "/fleet-telemetry/tls.key")]
+    pub fleet_telemetry_key_file: String,
+    /// ca of the client certificates vehicles connect with
+    #[arg(long, default_value = "/fleet-telemetry/ca.crt")]
+    pub fleet_telemetry_ca_file: String,
+}
+
+#[derive(Args, Debug)]
+pub struct ControllerArgs {
+    /// log the decisions without sending commands to the vehicle
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct CommandKeyArgs {
+    /// pem file with the private key, the `commandKeyFile` of the accounts
+    #[arg(long, default_value = "command-key.pem")]
+    pub file: String,
+}
+
+#[derive(Args, Debug)]
+pub struct TelemetryConfigArgs {
+    #[command(flatten)]
+    pub config_args: ConfigArgs,
+    /// public hostname of the receiver
+    #[arg(long)]
+    pub hostname: String,
+    #[arg(long, default_value_t = 443)]
+    pub port: u16,
+    /// ca of the receiver's server certificate, for the vehicles to trust it
+    #[arg(long)]
+    pub ca_file: String,
+    #[arg(long, default_value_t = 60)]
+    pub interval_seconds: u32,
+    #[arg(long, default_value_t = 365)]
+    pub expires_in_days: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_without_subcommand_runs_exporter() {
+        // act
+        let cli = Cli::try_parse_from(["jarvis-tesla-exporter"]).unwrap();
+
+        assert!(cli.command.is_none());
+    }
+
+    #[test]
+    fn parse_measure_with_dry_run() {
+        // act
+        let cli = Cli::try_parse_from([
+            "jarvis-tesla-exporter",
+            "measure",
+            "--config",
+            "test-data/test-config.yaml",
+            "--dry-run",
+        ])
+        .unwrap();
+
+        match cli.command {
+            Some(Command::Measure(args)) => {
+                assert_eq!(args.config_args.config, "test-data/test-config.yaml");
+                assert_eq!(args.state_file, "vehicle-state.json");
+                assert!(args.dry_run);
+            }
+            _ => panic!("expected measure command"),
+        }
+    }
+
+    #[test]
+    fn parse_daemon_with_metrics_address() {
+        // act
+        let cli = Cli::try_parse_from([
+            "jarvis-tesla-exporter",
+            "daemon",
+            "--metrics-address",
+            "0.0.0.0:9090",
+        ])
+        .unwrap();
+
+        match cli.command {
+            Some(Command::Daemon(args)) => {
+                assert_eq!(args.interval_seconds, 300);
+                assert_eq!(args.metrics_address, Some("0.0.0.0:9090".parse().unwrap()));
+            }
+            _ => panic!("expected daemon command"),
+        }
+    }
+}
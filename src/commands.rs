@@ -0,0 +1,215 @@
+use crate::cli::{CommandKeyArgs, ConfigArgs, MeasureArgs, TelemetryConfigArgs, VehicleArgs};
+use crate::config_validation;
+use crate::fleet_telemetry;
+use crate::model::{AccountConfig, Config, TeslaAccessToken, TeslaVehicle};
+use crate::signed_command::CommandKey;
+use crate::tesla_api_client::TeslaApiClient;
+use chrono::{Duration, Utc};
+use jarvis_lib::config_client::{ConfigClient, ConfigClientConfig, SetDefaults};
+use jarvis_lib::measurement_client::MeasurementClient;
+use jarvis_lib::model::Measurement;
+use std::error::Error;
+use std::fs;
+use tracing::warn;
+
+/// Subcommands to inspect vehicles from a workstation, using a local config file.
+pub async fn vehicles(
+    args: ConfigArgs,
+    tesla_api_client: &TeslaApiClient,
+) -> Result<(), Box<dyn Error>> {
+    let config = read_config(&args)?;
+
+    println!("ACCOUNT\tID\tVEHICLE ID\tVIN\tNAME\tSTATE");
+    for account in &config.accounts {
+        let (account, token) = get_session(account, tesla_api_client).await?;
+
+        for vehicle in tesla_api_client.get_vehicles(&account, &token).await? {
+            println!(
+                "{}\t{}\t{}\t{}\t{}\t{:?}",
+                account.name,
+                vehicle.id,
+                vehicle.vehicle_id,
+                vehicle.vin,
+                vehicle.display_name.as_deref().unwrap_or_default(),
+                vehicle.state
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn vehicle_data(
+    args: VehicleArgs,
+    tesla_api_client: &TeslaApiClient,
+) -> Result<(), Box<dyn Error>> {
+    let config = read_config(&args.config_args)?;
+    let (account, token, vehicle) = find_vehicle(&config, &args.vin, tesla_api_client).await?;
+
+    let vehicle_data = tesla_api_client
+        .get_vehicle_data(&account, &token, &vehicle)
+        .await?;
+
+    println!("{}", serde_json::to_string_pretty(&vehicle_data)?);
+
+    Ok(())
+}
+
+pub async fn stream(
+    args: VehicleArgs,
+    tesla_api_client: &TeslaApiClient,
+) -> Result<(), Box<dyn Error>> {
+    let config = read_config(&args.config_args)?;
+    let (account, token, vehicle) = find_vehicle(&config, &args.vin, tesla_api_client).await?;
+
+    tesla_api_client
+        .stream_updates(&account, &token, &vehicle, |update| {
+            match serde_json::to_string(&update) {
+                Ok(update) => println!("{}", update),
+                Err(e) => eprintln!("Failed serializing update: {}", e),
+            }
+            true
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// `tesla_api_client` should be created with a read only state client for a dry run.
+pub async fn measure(
+    args: MeasureArgs,
+    tesla_api_client: &TeslaApiClient,
+) -> Result<(), Box<dyn Error>> {
+    let config = read_config(&args.config_args)?;
+    config_validation::validate(&config)?;
+
+    let last_measurements: Option<Vec<Measurement>> = match &args.last_measurements_file {
+        Some(last_measurements_file) => Some(serde_json::from_str(&fs::read_to_string(
+            last_measurements_file,
+        )?)?),
+        None => None,
+    };
+
+    let measurements = tesla_api_client.get_measurements(config, last_measurements)?;
+
+    println!("{}", serde_json::to_string_pretty(&measurements)?);
+
+    Ok(())
+}
+
+/// The public key has to be hosted at `/.well-known/appspecific/com.tesla.3p.public-key.pem` of the
+/// application domain, after which the owner enrolls it in the Tesla app through
+/// `https://tesla.com/_ak/<domain>`.
+pub fn command_key(args: CommandKeyArgs) -> Result<(), Box<dyn Error>> {
+    let command_key = CommandKey::load_or_generate(&args.file)?;
+
+    print!("{}", command_key.public_key_pem()?);
+
+    Ok(())
+}
+
+/// Point editors at the output to validate `configYaml`, like with a
+/// `# yaml-language-server: $schema=config.schema.json` comment in the config file.
+pub fn config_schema() -> Result<(), Box<dyn Error>> {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&config_validation::schema())?
+    );
+
+    Ok(())
+}
+
+pub async fn telemetry_config(
+    args: TelemetryConfigArgs,
+    tesla_api_client: &TeslaApiClient,
+) -> Result<(), Box<dyn Error>> {
+    let config = read_config(&args.config_args)?;
+    let ca = fs::read_to_string(&args.ca_file)?;
+    let expires_at = Utc::now() + Duration::days(args.expires_in_days);
+
+    for account in &config.accounts {
+        let (account, token) = get_session(account, tesla_api_client).await?;
+        // only the configured vehicles, the account may hold others that aren't ours to enroll
+        let vins: Vec<String> = tesla_api_client
+            .get_vehicles(&account, &token)
+            .await?
+            .into_iter()
+            .filter(|vehicle| {
+                account.vehicle_ids.iter().any(|vehicle_id| {
+                    *vehicle_id == vehicle.id.to_string()
+                        || vehicle_id.eq_ignore_ascii_case(&vehicle.vin)
+                })
+            })
+            .map(|vehicle| vehicle.vin)
+            .collect();
+        if vins.is_empty() {
+            warn!(
+                "None of the configured vehicles found in account {}",
+                account.name
+            );
+            continue;
+        }
+
+        let body = fleet_telemetry::fleet_telemetry_config(
+            &vins,
+            &args.hostname,
+            args.port,
+            &ca,
+            args.interval_seconds,
+            expires_at,
+        );
+        let response = tesla_api_client
+            .create_fleet_telemetry_config(&account, &token, &body)
+            .await?;
+
+        println!("{}", serde_json::to_string_pretty(&response)?);
+    }
+
+    Ok(())
+}
+
+fn read_config(args: &ConfigArgs) -> Result<Config, Box<dyn Error>> {
+    let config_client = ConfigClient::new(ConfigClientConfig::new(args.config.clone())?);
+
+    let mut config: Config = config_client.read_config_from_file()?;
+    config.set_defaults();
+
+    Ok(config)
+}
+
+async fn get_session(
+    account: &AccountConfig,
+    tesla_api_client: &TeslaApiClient,
+) -> Result<(AccountConfig, TeslaAccessToken), Box<dyn Error>> {
+    let account = account.with_resolved_refresh_token().await?;
+    let token = tesla_api_client
+        .get_access_token(&account, &account.refresh_token)
+        .await?;
+
+    Ok((account, token))
+}
+
+async fn find_vehicle(
+    config: &Config,
+    vin: &str,
+    tesla_api_client: &TeslaApiClient,
+) -> Result<(AccountConfig, TeslaAccessToken, TeslaVehicle), Box<dyn Error>> {
+    for account in &config.accounts {
+        let (account, token) = get_session(account, tesla_api_client).await?;
+
+        let vehicle = tesla_api_client
+            .get_vehicles(&account, &token)
+            .await?
+            .into_iter()
+            .find(|vehicle| vehicle.vin.eq_ignore_ascii_case(vin));
+
+        if let Some(vehicle) = vehicle {
+            return Ok((account, token, vehicle));
+        }
+    }
+
+    Err(Box::<dyn Error>::from(format!(
+        "No vehicle with vin {} in any of the configured accounts",
+        vin
+    )))
+}
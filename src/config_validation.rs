@@ -0,0 +1,385 @@
+use crate::model::{Config, GeofenceConfig};
+use chrono::NaiveTime;
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+
+/// Refresh tokens from examples, like the `abcd` in the helm values; real tokens are far longer.
+const MIN_REFRESH_TOKEN_LENGTH: usize = 16;
+
+/// All problems found in the config, so they can be fixed in one go.
+#[derive(Debug)]
+pub struct ConfigValidationError {
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid config:")?;
+        for problem in &self.problems {
+            write!(f, "\n- {}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Error for ConfigValidationError {}
+
+/// Checks a config with its defaults set, before the exporter starts talking to the api.
+pub fn validate(config: &Config) -> Result<(), ConfigValidationError> {
+    let mut problems: Vec<String> = vec![];
+
+    validate_accounts(config, &mut problems);
+    validate_geofences(&config.geofences, &mut problems);
+    validate_charging(config, &mut problems);
+    validate_webhooks(config, &mut problems);
+
+    if let Some(influx) = &config.outputs.influx {
+        if influx.url.trim().is_empty() {
+            problems.push("outputs.influx.url is empty".to_string());
+        }
+        if let Some(token) = &influx.token {
+            if token.sources() != 1 {
+                problems.push(
+                    "outputs.influx.token needs exactly one of value, file, env and secretRef"
+                        .to_string(),
+                );
+            }
+        }
+    }
+    if let Some(csv) = &config.outputs.csv {
+        if csv.directory.trim().is_empty() {
+            problems.push("outputs.csv.directory is empty".to_string());
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(ConfigValidationError { problems })
+    }
+}
+
+/// JSON Schema of the config file, for editors to validate the helm `configYaml` with.
+pub fn schema() -> RootSchema {
+    schema_for!(Config)
+}
+
+fn validate_accounts(config: &Config, problems: &mut Vec<String>) {
+    if config.accounts.is_empty() {
+        problems.push("no accounts configured, add one to accounts".to_string());
+    }
+
+    let mut names = HashSet::new();
+    for account in &config.accounts {
+        if account.name.trim().is_empty() {
+            problems.push("account without name".to_string());
+        } else if !names.insert(account.name.as_str()) {
+            problems.push(format!(
+                "account name {} is used more than once",
+                account.name
+            ));
+        }
+
+        let refresh_token_source = account.refresh_token_source();
+        match refresh_token_source.sources() {
+            0 => problems.push(format!(
+                "account {} has neither refreshToken, refreshTokenFile, refreshTokenEnv nor refreshTokenSecretRef",
+                account.name
+            )),
+            1 if refresh_token_source.value.is_some()
+                && account.refresh_token.trim().len() < MIN_REFRESH_TOKEN_LENGTH =>
+            {
+                problems.push(format!(
+                    "refreshToken of account {} looks like a placeholder, obtain one with `jarvis-tesla-exporter login`",
+                    account.name
+                ))
+            }
+            1 => {}
+            _ => problems.push(format!(
+                "account {} has more than one of refreshToken, refreshTokenFile, refreshTokenEnv and refreshTokenSecretRef",
+                account.name
+            )),
+        }
+
+        if account.vehicle_ids.is_empty() {
+            problems.push(format!(
+                "account {} has no vehicleIds, list them with `jarvis-tesla-exporter vehicles`",
+                account.name
+            ));
+        }
+        for vehicle_id in &account.vehicle_ids {
+            if vehicle_id.parse::<u64>().is_err() {
+                problems.push(format!(
+                    "vehicle id {} of account {} isn't numeric",
+                    vehicle_id, account.name
+                ));
+            }
+        }
+    }
+}
+
+fn validate_geofences(geofences: &[GeofenceConfig], problems: &mut Vec<String>) {
+    let mut locations = HashSet::new();
+    for geofence in geofences {
+        if geofence.location.trim().is_empty() {
+            problems.push("geofence without location".to_string());
+        } else if !locations.insert(geofence.location.as_str()) {
+            problems.push(format!(
+                "geofence location {} is used more than once",
+                geofence.location
+            ));
+        }
+
+        if !(-90.0..=90.0).contains(&geofence.latitude) {
+            problems.push(format!(
+                "latitude {} of geofence {} is outside -90 to 90",
+                geofence.latitude, geofence.location
+            ));
+        }
+        if !(-180.0..=180.0).contains(&geofence.longitude) {
+            problems.push(format!(
+                "longitude {} of geofence {} is outside -180 to 180",
+                geofence.longitude, geofence.location
+            ));
+        }
+        if geofence.geofence_radius_meters <= 0.0 {
+            problems.push(format!(
+                "geofenceRadiusMeters {} of geofence {} isn't positive",
+                geofence.geofence_radius_meters, geofence.location
+            ));
+        }
+    }
+}
+
+fn validate_charging(config: &Config, problems: &mut Vec<String>) {
+    let has_geofence = |location: &str| config.geofences.iter().any(|g| g.location == location);
+
+    if let Some(solar_charging) = &config.solar_charging {
+        if !has_geofence(&solar_charging.geofence) {
+            problems.push(format!(
+                "solarCharging.geofence {} isn't one of the geofences",
+                solar_charging.geofence
+            ));
+        }
+        if solar_charging.min_amps == 0 || solar_charging.min_amps > solar_charging.max_amps {
+            problems.push(format!(
+                "solarCharging.minAmps {} has to be between 1 and maxAmps {}",
+                solar_charging.min_amps, solar_charging.max_amps
+            ));
+        }
+        if solar_charging.voltage <= 0.0 {
+            problems.push("solarCharging.voltage isn't positive".to_string());
+        }
+        if solar_charging.phases != 1 && solar_charging.phases != 3 {
+            problems.push(format!(
+                "solarCharging.phases {} has to be 1 or 3",
+                solar_charging.phases
+            ));
+        }
+        if let Some(minimum_soc) = solar_charging.minimum_soc {
+            if !(0.0..=100.0).contains(&minimum_soc) {
+                problems.push(format!(
+                    "solarCharging.minimumSoc {} is outside 0 to 100",
+                    minimum_soc
+                ));
+            }
+        }
+        if let Some(deadline) = &solar_charging.minimum_soc_deadline {
+            validate_time_of_day("solarCharging.minimumSocDeadline", deadline, problems);
+        }
+    }
+
+    if let Some(tariff_charging) = &config.tariff_charging {
+        if !has_geofence(&tariff_charging.geofence) {
+            problems.push(format!(
+                "tariffCharging.geofence {} isn't one of the geofences",
+                tariff_charging.geofence
+            ));
+        }
+        if tariff_charging.prices.trim().is_empty() {
+            problems.push("tariffCharging.prices is empty".to_string());
+        }
+        validate_time_of_day(
+            "tariffCharging.departureTime",
+            &tariff_charging.departure_time,
+            problems,
+        );
+        if tariff_charging.charge_power_watts <= 0.0 {
+            problems.push("tariffCharging.chargePowerWatts isn't positive".to_string());
+        }
+    }
+}
+
+fn validate_webhooks(config: &Config, problems: &mut Vec<String>) {
+    let mut names = HashSet::new();
+    for webhook in &config.webhooks {
+        if !names.insert(webhook.name.as_str()) {
+            problems.push(format!(
+                "webhook name {} is used more than once",
+                webhook.name
+            ));
+        }
+        if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+            problems.push(format!(
+                "url {} of webhook {} isn't an http url",
+                webhook.url, webhook.name
+            ));
+        }
+        if webhook.cooldown_minutes < 0 {
+            problems.push(format!(
+                "cooldownMinutes of webhook {} is negative",
+                webhook.name
+            ));
+        }
+        if let Some(authorization) = &webhook.authorization {
+            if authorization.sources() != 1 {
+                problems.push(format!(
+                    "authorization of webhook {} needs exactly one of value, file, env and secretRef",
+                    webhook.name
+                ));
+            }
+        }
+        for condition in &webhook.conditions {
+            if condition.value.is_some() == condition.other_field.is_some() {
+                problems.push(format!(
+                    "condition on {} of webhook {} needs either value or otherField",
+                    condition.field, webhook.name
+                ));
+            }
+        }
+    }
+}
+
+fn validate_time_of_day(name: &str, value: &str, problems: &mut Vec<String>) {
+    if NaiveTime::parse_from_str(value, "%H:%M").is_err() {
+        problems.push(format!("{} {} isn't a time like 07:30", name, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jarvis_lib::config_client::SetDefaults;
+
+    fn config(yaml: &str) -> Config {
+        let mut config: Config = serde_yaml::from_str(yaml).unwrap();
+        config.set_defaults();
+        config
+    }
+
+    #[test]
+    fn validate_accepts_valid_config() {
+        let config = config(
+            r#"
+accounts:
+- name: default
+  refreshToken: eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9
+  vehicleIds:
+  - "23498074342"
+geofences:
+- location: My Home
+  latitude: 52.377956
+  longitude: 4.897070
+tariffCharging:
+  vin: 5YJ3E7EB2KF000000
+  geofence: My Home
+  prices: test-data/prices.csv
+  departureTime: "07:30"
+"#,
+        );
+
+        // act
+        let result = validate(&config);
+
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(config.geofences[0].geofence_radius_meters, 100.0);
+    }
+
+    #[test]
+    fn validate_reports_all_problems() {
+        let config = config(
+            r#"
+refreshToken: abcd
+geofences:
+- location: My Home
+  latitude: 152.377956
+  longitude: 4.897070
+  geofenceRadiusMeters: -100
+tariffCharging:
+  vin: 5YJ3E7EB2KF000000
+  geofence: Work
+  prices: test-data/prices.csv
+  departureTime: "7.30"
+"#,
+        );
+
+        // act
+        let result = validate(&config);
+
+        assert_eq!(
+            result.unwrap_err().problems,
+            vec![
+                "refreshToken of account default looks like a placeholder, obtain one with `jarvis-tesla-exporter login`",
+                "account default has no vehicleIds, list them with `jarvis-tesla-exporter vehicles`",
+                "latitude 152.377956 of geofence My Home is outside -90 to 90",
+                "geofenceRadiusMeters -100 of geofence My Home isn't positive",
+                "tariffCharging.geofence Work isn't one of the geofences",
+                "tariffCharging.departureTime 7.30 isn't a time like 07:30",
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_accepts_refresh_token_from_secret_ref() {
+        let config = config(
+            r#"
+accounts:
+- name: default
+  refreshTokenSecretRef:
+    name: jarvis-tesla-exporter-refresh-token
+    key: refresh-token
+  vehicleIds:
+  - "23498074342"
+webhooks:
+- name: ntfy
+  url: https://ntfy.sh/my-tesla
+  message: "{displayName} is at {soc}%"
+  authorization:
+    env: NTFY_AUTHORIZATION
+    file: /secrets/ntfy-authorization
+geofences: []
+"#,
+        );
+
+        // act
+        let result = validate(&config);
+
+        assert_eq!(
+            result.unwrap_err().problems,
+            vec![
+                "authorization of webhook ntfy needs exactly one of value, file, env and secretRef"
+            ]
+        );
+    }
+
+    #[test]
+    fn schema_describes_config_file() {
+        // act
+        let schema = serde_json::to_value(schema()).unwrap();
+
+        assert_eq!(
+            schema["definitions"]["GeofenceConfig"]["properties"]["geofenceRadiusMeters"]
+                ["default"],
+            100.0
+        );
+        assert_eq!(
+            schema["definitions"]["TeslaRegion"]["enum"],
+            serde_json::json!(["northAmerica", "europe", "china"])
+        );
+        assert!(schema["properties"]["accounts"].is_object());
+    }
+}
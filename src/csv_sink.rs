@@ -0,0 +1,220 @@
+use crate::model::CsvOutputConfig;
+use crate::output_sink::OutputSink;
+use async_trait::async_trait;
+use chrono::{Duration, NaiveDate, Utc};
+use jarvis_lib::model::Measurement;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+const CSV_HEADER: &str =
+    "measured_at_time,location,entity_name,sample_type,sample_name,metric_type,value";
+
+/// Appends samples to a csv file per day, so spreadsheets can open the history directly.
+pub struct CsvSink {
+    config: CsvOutputConfig,
+}
+
+impl CsvSink {
+    pub fn new(config: CsvOutputConfig) -> Self {
+        Self { config }
+    }
+
+    fn file_path(&self, date: NaiveDate) -> PathBuf {
+        Path::new(&self.config.directory).join(format!(
+            "{}-{}.csv",
+            self.config.file_prefix,
+            date.format("%Y-%m-%d")
+        ))
+    }
+
+    fn append(&self, date: NaiveDate, rows: &[String]) -> Result<(), Box<dyn Error>> {
+        let path = self.file_path(date);
+        let exists = path.exists();
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if !exists {
+            writeln!(file, "{}", CSV_HEADER)?;
+        }
+        for row in rows {
+            writeln!(file, "{}", row)?;
+        }
+
+        info!("Appended {} samples to {}", rows.len(), path.display());
+
+        Ok(())
+    }
+
+    /// Removes files of days older than the retention.
+    fn remove_expired_files(&self, today: NaiveDate) -> Result<(), Box<dyn Error>> {
+        let retention_days = match self.config.retention_days {
+            Some(retention_days) => retention_days,
+            None => return Ok(()),
+        };
+        let oldest_kept = today - Duration::days(retention_days as i64);
+        let prefix = format!("{}-", self.config.file_prefix);
+
+        for entry in fs::read_dir(&self.config.directory)? {
+            let path = entry?.path();
+            let date = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix(&prefix))
+                .and_then(|name| name.strip_suffix(".csv"))
+                .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+
+            if let Some(date) = date {
+                if date < oldest_kept {
+                    info!("Removing expired csv file {}", path.display());
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl OutputSink for CsvSink {
+    fn name(&self) -> &str {
+        "csv"
+    }
+
+    async fn write(&self, measurements: &[Measurement]) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(&self.config.directory)?;
+
+        // a run around midnight can span two days
+        let mut rows_by_date: BTreeMap<NaiveDate, Vec<String>> = BTreeMap::new();
+        for measurement in measurements {
+            rows_by_date
+                .entry(measurement.measured_at_time.date_naive())
+                .or_default()
+                .extend(csv_rows(measurement));
+        }
+
+        for (date, rows) in rows_by_date {
+            self.append(date, &rows)?;
+        }
+
+        if let Err(e) = self.remove_expired_files(Utc::now().date_naive()) {
+            warn!("Failed removing expired csv files: {}", e);
+        }
+
+        Ok(())
+    }
+}
+
+fn csv_rows(measurement: &Measurement) -> Vec<String> {
+    let measured_at_time = measurement.measured_at_time.to_rfc3339();
+
+    measurement
+        .samples
+        .iter()
+        .map(|sample| {
+            [
+                measured_at_time.clone(),
+                escape_field(&measurement.location),
+                escape_field(&sample.entity_name),
+                format!("{:?}", sample.sample_type),
+                escape_field(&sample.sample_name),
+                format!("{:?}", sample.metric_type),
+                sample.value.to_string(),
+            ]
+            .join(",")
+        })
+        .collect()
+}
+
+fn escape_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use jarvis_lib::model::{EntityType, MetricType, Sample, SampleType};
+
+    fn measurement(timestamp: i64) -> Measurement {
+        Measurement {
+            id: "id".into(),
+            source: "jarvis-tesla-exporter".into(),
+            location: "My Home".into(),
+            samples: vec![Sample {
+                entity_type: EntityType::Device,
+                entity_name: "jarvis-tesla-exporter".into(),
+                sample_type: SampleType::ElectricityConsumption,
+                sample_name: "Tessie, the red one".into(),
+                metric_type: MetricType::Gauge,
+                value: 11000.0,
+            }],
+            measured_at_time: Utc.timestamp_opt(timestamp, 0).unwrap(),
+        }
+    }
+
+    fn csv_sink(directory: &Path, retention_days: Option<u32>) -> CsvSink {
+        CsvSink::new(CsvOutputConfig {
+            directory: directory.to_str().unwrap().to_string(),
+            file_prefix: "jarvis-tesla".into(),
+            retention_days,
+        })
+    }
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("{}-{}", name, uuid::Uuid::new_v4()));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[tokio::test]
+    async fn write_appends_rows_to_file_of_the_day() {
+        let directory = test_directory("csv-sink-append");
+        let sink = csv_sink(&directory, None);
+
+        // act
+        sink.write(&[measurement(1685577600)]).await.unwrap();
+        sink.write(&[measurement(1685577900), measurement(1685664000)])
+            .await
+            .unwrap();
+
+        let first_day = fs::read_to_string(directory.join("jarvis-tesla-2023-06-01.csv")).unwrap();
+        let lines: Vec<&str> = first_day.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(
+            lines[1],
+            "2023-06-01T00:00:00+00:00,My Home,jarvis-tesla-exporter,ElectricityConsumption,\"Tessie, the red one\",Gauge,11000"
+        );
+        let second_day = fs::read_to_string(directory.join("jarvis-tesla-2023-06-02.csv")).unwrap();
+        assert_eq!(second_day.lines().count(), 2);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn remove_expired_files_keeps_files_within_retention() {
+        let directory = test_directory("csv-sink-retention");
+        fs::write(directory.join("jarvis-tesla-2023-05-01.csv"), CSV_HEADER).unwrap();
+        fs::write(directory.join("jarvis-tesla-2023-05-30.csv"), CSV_HEADER).unwrap();
+        fs::write(directory.join("other-2023-05-01.csv"), CSV_HEADER).unwrap();
+        let sink = csv_sink(&directory, Some(7));
+
+        // act
+        sink.remove_expired_files(NaiveDate::from_ymd_opt(2023, 6, 1).unwrap())
+            .unwrap();
+
+        assert!(!directory.join("jarvis-tesla-2023-05-01.csv").exists());
+        assert!(directory.join("jarvis-tesla-2023-05-30.csv").exists());
+        assert!(directory.join("other-2023-05-01.csv").exists());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+
+const MAX_SEGMENTS: usize = 20;
+// shorter distances mostly measure battery drain while parked
+const MIN_SEGMENT_DISTANCE: f64 = 500.0;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DrivingSegment {
+    /// distance driven in meters
+    pub distance: f64,
+    /// energy spent in watt hours
+    pub energy: f64,
+}
+
+impl DrivingSegment {
+    /// consumption in Wh/km
+    pub fn consumption(&self) -> f64 {
+        self.energy / (self.distance / 1000.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DrivingEfficiency {
+    /// state of charge in percent at the start of the current segment
+    #[serde(default)]
+    pub reference_soc: Option<f64>,
+    /// odometer in meters at the start of the current segment
+    #[serde(default)]
+    pub reference_odometer: Option<f64>,
+    #[serde(default)]
+    pub segments: Vec<DrivingSegment>,
+    /// total energy spent driving in watt hours
+    #[serde(default)]
+    pub energy_spent: f64,
+}
+
+impl DrivingEfficiency {
+    /// Closes a segment once the state of charge dropped while driving; since the state of charge
+    /// is reported in whole percents a segment usually spans several polls.
+    pub fn observe(
+        &mut self,
+        soc: f64,
+        odometer: f64,
+        charging: bool,
+        battery_capacity: f64,
+    ) -> Option<DrivingSegment> {
+        let (reference_soc, reference_odometer) =
+            match (self.reference_soc, self.reference_odometer) {
+                (Some(reference_soc), Some(reference_odometer)) => {
+                    (reference_soc, reference_odometer)
+                }
+                _ => {
+                    self.reset(soc, odometer);
+                    return None;
+                }
+            };
+
+        if charging || soc > reference_soc || odometer < reference_odometer {
+            self.reset(soc, odometer);
+            return None;
+        }
+
+        if soc == reference_soc {
+            // wait for the state of charge to drop
+            return None;
+        }
+
+        self.reset(soc, odometer);
+
+        let distance = odometer - reference_odometer;
+        if distance < MIN_SEGMENT_DISTANCE {
+            return None;
+        }
+
+        let segment = DrivingSegment {
+            distance,
+            energy: (reference_soc - soc) / 100.0 * battery_capacity,
+        };
+
+        self.energy_spent += segment.energy;
+        self.segments.push(segment.clone());
+        if self.segments.len() > MAX_SEGMENTS {
+            let excess = self.segments.len() - MAX_SEGMENTS;
+            self.segments.drain(..excess);
+        }
+
+        Some(segment)
+    }
+
+    /// consumption of the last segment in Wh/km
+    pub fn consumption(&self) -> Option<f64> {
+        self.segments.last().map(DrivingSegment::consumption)
+    }
+
+    /// consumption over the recent segments in Wh/km
+    pub fn average_consumption(&self) -> Option<f64> {
+        let distance: f64 = self.segments.iter().map(|s| s.distance).sum();
+        if distance <= 0.0 {
+            return None;
+        }
+
+        let energy: f64 = self.segments.iter().map(|s| s.energy).sum();
+
+        Some(energy / (distance / 1000.0))
+    }
+
+    fn reset(&mut self, soc: f64, odometer: f64) {
+        self.reference_soc = Some(soc);
+        self.reference_odometer = Some(odometer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BATTERY_CAPACITY: f64 = 75000.0;
+
+    #[test]
+    fn observe_computes_consumption_when_soc_drops_while_driving() {
+        let mut efficiency = DrivingEfficiency::default();
+        efficiency.observe(80.0, 10000.0, false, BATTERY_CAPACITY);
+        assert_eq!(
+            efficiency.observe(80.0, 12000.0, false, BATTERY_CAPACITY),
+            None
+        );
+
+        // act
+        let segment = efficiency
+            .observe(78.0, 20000.0, false, BATTERY_CAPACITY)
+            .unwrap();
+
+        assert_eq!(segment.distance, 10000.0);
+        assert_eq!(segment.energy, 1500.0);
+        assert_eq!(efficiency.consumption(), Some(150.0));
+        assert_eq!(efficiency.energy_spent, 1500.0);
+    }
+
+    #[test]
+    fn observe_ignores_charging_and_parked_drain() {
+        let mut efficiency = DrivingEfficiency::default();
+        efficiency.observe(80.0, 10000.0, false, BATTERY_CAPACITY);
+
+        // act
+        assert_eq!(
+            efficiency.observe(79.0, 10000.0, false, BATTERY_CAPACITY),
+            None
+        );
+        assert_eq!(
+            efficiency.observe(85.0, 10000.0, true, BATTERY_CAPACITY),
+            None
+        );
+        assert_eq!(
+            efficiency.observe(90.0, 15000.0, false, BATTERY_CAPACITY),
+            None
+        );
+
+        assert_eq!(efficiency.segments.len(), 0);
+        assert_eq!(efficiency.energy_spent, 0.0);
+        assert_eq!(efficiency.reference_soc, Some(90.0));
+    }
+
+    #[test]
+    fn average_consumption_weighs_segments_by_distance() {
+        let mut efficiency = DrivingEfficiency::default();
+        efficiency.observe(80.0, 0.0, false, BATTERY_CAPACITY);
+        efficiency.observe(79.0, 5000.0, false, BATTERY_CAPACITY);
+        efficiency.observe(77.0, 20000.0, false, BATTERY_CAPACITY);
+
+        // act
+        let average_consumption = efficiency.average_consumption();
+
+        assert_eq!(average_consumption, Some(112.5));
+    }
+}
@@ -0,0 +1,74 @@
+use crate::vehicle_events::VehicleEvent;
+use std::env;
+use std::error::Error;
+use std::sync::Mutex;
+use tracing::{debug, info};
+
+pub struct EventClientConfig {
+    host: String,
+    subject: String,
+}
+
+impl EventClientConfig {
+    pub fn new(host: String, subject: String) -> Result<Self, Box<dyn Error>> {
+        debug!(
+            "EventClientConfig::new(host: {}, subject: {})",
+            host, subject
+        );
+
+        Ok(Self { host, subject })
+    }
+
+    /// None when `NATS_EVENTS_SUBJECT` isn't set, which leaves publishing events off.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn Error>> {
+        let subject = match env::var("NATS_EVENTS_SUBJECT") {
+            Ok(subject) if !subject.is_empty() => subject,
+            _ => return Ok(None),
+        };
+        let host = env::var("NATS_HOST").unwrap_or_else(|_| "jarvis-nats".to_string());
+
+        Self::new(host, subject).map(Some)
+    }
+}
+
+/// Publishes vehicle events on their own nats subject, next to the measurements.
+pub struct EventClient {
+    config: EventClientConfig,
+    connection: Mutex<Option<nats::Connection>>,
+}
+
+impl EventClient {
+    pub fn new(config: EventClientConfig) -> Self {
+        Self {
+            config,
+            connection: Mutex::new(None),
+        }
+    }
+
+    pub fn publish(&self, events: &[VehicleEvent]) -> Result<(), Box<dyn Error>> {
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        let mut connection = self.connection.lock().unwrap();
+        if connection.is_none() {
+            // connect on first use, so an unreachable server doesn't stop the exporter from starting
+            *connection = Some(nats::connect(&self.config.host)?);
+        }
+        let connection = connection.as_ref().unwrap();
+
+        for event in events {
+            debug!("Publishing event {:?}", event);
+            connection.publish(&self.config.subject, serde_json::to_vec(event)?)?;
+        }
+        connection.flush()?;
+
+        info!(
+            "Published {} events to nats subject {}",
+            events.len(),
+            self.config.subject
+        );
+
+        Ok(())
+    }
+}
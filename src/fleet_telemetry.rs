@@ -0,0 +1,724 @@
+use crate::model::TeslaVehicleStreamingData;
+use crate::units::DistanceUnit;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use futures::StreamExt;
+use openssl::nid::Nid;
+use openssl::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::X509Ref;
+use prost::Message as ProstMessage;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_openssl::SslStream;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, error, info, warn};
+
+// topic of the vehicle data stream, next to alerts, errors and connectivity
+const TOPIC_VEHICLE_DATA: &[u8] = b"V";
+const KILOWATT: f64 = 1000.0;
+
+/// Fields the receiver maps onto measurements, as named in the `fleet_telemetry_config` request.
+pub const FIELDS: &[&str] = &[
+    "Soc",
+    "VehicleSpeed",
+    "Odometer",
+    "Location",
+    "ChargeAmps",
+    "ACChargingPower",
+    "DCChargingPower",
+    "Gear",
+];
+
+/// Subset of the `Field` enum of vehicle_data.proto of Tesla's fleet-telemetry server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+enum Field {
+    Unknown = 0,
+    VehicleSpeed = 4,
+    Odometer = 5,
+    Soc = 8,
+    Gear = 10,
+    Location = 21,
+    DcChargingPower = 35,
+    AcChargingPower = 37,
+    ChargeAmps = 49,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Payload {
+    #[prost(message, repeated, tag = "1")]
+    data: Vec<Datum>,
+    #[prost(message, optional, tag = "2")]
+    created_at: Option<Timestamp>,
+    #[prost(string, tag = "3")]
+    vin: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Timestamp {
+    #[prost(int64, tag = "1")]
+    seconds: i64,
+    #[prost(int32, tag = "2")]
+    nanos: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Datum {
+    #[prost(enumeration = "Field", tag = "1")]
+    key: i32,
+    #[prost(message, optional, tag = "2")]
+    value: Option<DatumValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct DatumValue {
+    #[prost(oneof = "ValueKind", tags = "1, 2, 3, 4, 5, 6, 7, 9")]
+    value: Option<ValueKind>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum ValueKind {
+    #[prost(string, tag = "1")]
+    String(String),
+    #[prost(int32, tag = "2")]
+    Int(i32),
+    #[prost(int64, tag = "3")]
+    Long(i64),
+    #[prost(float, tag = "4")]
+    Float(f32),
+    #[prost(double, tag = "5")]
+    Double(f64),
+    #[prost(bool, tag = "6")]
+    Boolean(bool),
+    #[prost(message, tag = "7")]
+    Location(LocationValue),
+    /// `ShiftState` enum: unknown, invalid, P, R, N, D, SNA
+    #[prost(int32, tag = "9")]
+    ShiftState(i32),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct LocationValue {
+    #[prost(double, tag = "1")]
+    latitude: f64,
+    #[prost(double, tag = "2")]
+    longitude: f64,
+}
+
+impl DatumValue {
+    /// Older firmware sends every value as a string.
+    fn as_f64(&self) -> Option<f64> {
+        match self.value.as_ref()? {
+            ValueKind::String(s) => s.parse().ok(),
+            ValueKind::Int(v) => Some(*v as f64),
+            ValueKind::Long(v) => Some(*v as f64),
+            ValueKind::Float(v) => Some(*v as f64),
+            ValueKind::Double(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    fn as_location(&self) -> Option<(f64, f64)> {
+        match self.value.as_ref()? {
+            ValueKind::Location(location) => Some((location.latitude, location.longitude)),
+            ValueKind::String(s) => {
+                let (latitude, longitude) = s.split_once(',')?;
+                Some((
+                    latitude.trim().parse().ok()?,
+                    longitude.trim().parse().ok()?,
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    fn as_gear(&self) -> Option<String> {
+        match self.value.as_ref()? {
+            ValueKind::ShiftState(state) => ["Unknown", "Invalid", "P", "R", "N", "D", "SNA"]
+                .get(*state as usize)
+                .map(|gear| gear.to_string()),
+            ValueKind::String(s) => Some(s.trim_start_matches("ShiftState").to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// Latest telemetry of a vehicle in si units, merged from the payloads that only hold changed fields.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VehicleTelemetry {
+    pub soc: Option<f64>,
+    /// meters per second
+    pub speed: Option<f64>,
+    /// meters
+    pub odometer: Option<f64>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub charge_amps: Option<f64>,
+    /// watt
+    pub ac_charging_power: Option<f64>,
+    /// watt
+    pub dc_charging_power: Option<f64>,
+    pub gear: Option<String>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl VehicleTelemetry {
+    fn apply(&mut self, payload: &Payload) {
+        // telemetry reports miles like the streaming api
+        let distance = DistanceUnit::Miles;
+
+        for datum in &payload.data {
+            let value = match &datum.value {
+                Some(value) => value,
+                None => continue,
+            };
+            match Field::from_i32(datum.key) {
+                Some(Field::Soc) => self.soc = value.as_f64(),
+                Some(Field::VehicleSpeed) => {
+                    self.speed = value.as_f64().map(|v| distance.to_meters_per_second(v))
+                }
+                Some(Field::Odometer) => {
+                    self.odometer = value.as_f64().map(|v| distance.to_meters(v))
+                }
+                Some(Field::Location) => {
+                    if let Some((latitude, longitude)) = value.as_location() {
+                        self.latitude = Some(latitude);
+                        self.longitude = Some(longitude);
+                    }
+                }
+                Some(Field::ChargeAmps) => self.charge_amps = value.as_f64(),
+                Some(Field::AcChargingPower) => {
+                    self.ac_charging_power = value.as_f64().map(|v| v * KILOWATT)
+                }
+                Some(Field::DcChargingPower) => {
+                    self.dc_charging_power = value.as_f64().map(|v| v * KILOWATT)
+                }
+                Some(Field::Gear) => self.gear = value.as_gear(),
+                _ => debug!("Ignoring telemetry field {}", datum.key),
+            }
+        }
+
+        self.updated_at = Some(
+            payload
+                .created_at
+                .as_ref()
+                .and_then(|t| Utc.timestamp_opt(t.seconds, t.nanos as u32).single())
+                .unwrap_or_else(Utc::now),
+        );
+    }
+
+    /// The telemetry in the shape of the streaming api, once all its values have been received.
+    pub fn streaming_data(&self) -> Option<TeslaVehicleStreamingData> {
+        let charging_power =
+            self.ac_charging_power.unwrap_or(0.0) + self.dc_charging_power.unwrap_or(0.0);
+
+        // the speed is only sent when it changes, so it may be stale once parked
+        let speed = match self.gear.as_deref() {
+            Some("P") => 0.0,
+            _ => self.speed.unwrap_or(0.0),
+        };
+
+        Some(TeslaVehicleStreamingData {
+            latitude: Some(self.latitude?),
+            longitude: Some(self.longitude?),
+            // kilowatt like the streaming api
+            power: charging_power / KILOWATT,
+            speed,
+            odometer: self.odometer?,
+            soc: self.soc?,
+        })
+    }
+}
+
+/// Telemetry by vin, shared between the receiver and the exporter.
+#[derive(Default)]
+pub struct TelemetryStore {
+    vehicles: Mutex<HashMap<String, VehicleTelemetry>>,
+}
+
+impl TelemetryStore {
+    fn apply(&self, payload: &Payload) {
+        let mut vehicles = self.vehicles.lock().unwrap();
+        vehicles
+            .entry(payload.vin.clone())
+            .or_default()
+            .apply(payload);
+    }
+
+    /// Telemetry of a vehicle received within `max_age`.
+    pub fn get(&self, vin: &str, max_age: Duration) -> Option<VehicleTelemetry> {
+        let vehicles = self.vehicles.lock().unwrap();
+        let telemetry = vehicles.get(vin)?;
+        if Utc::now() - telemetry.updated_at? > max_age {
+            return None;
+        }
+
+        Some(telemetry.clone())
+    }
+}
+
+pub struct FleetTelemetryServerConfig {
+    pub address: SocketAddr,
+    pub cert_file: String,
+    pub key_file: String,
+    /// ca of the client certificates the vehicles connect with
+    pub ca_file: String,
+}
+
+/// Accepts the websocket connections of vehicles over mutual tls until the process exits.
+pub async fn serve(
+    config: FleetTelemetryServerConfig,
+    store: Arc<TelemetryStore>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+    acceptor.set_certificate_chain_file(&config.cert_file)?;
+    acceptor.set_private_key_file(&config.key_file, SslFiletype::PEM)?;
+    acceptor.set_ca_file(&config.ca_file)?;
+    acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    let acceptor = acceptor.build();
+
+    let listener = TcpListener::bind(config.address).await?;
+    info!("Receiving fleet telemetry on {}", config.address);
+
+    loop {
+        let (tcp_stream, peer_address) = listener.accept().await?;
+        let ssl = Ssl::new(acceptor.context())?;
+        let store = store.clone();
+
+        tokio::spawn(async move {
+            let mut stream = match SslStream::new(ssl, tcp_stream) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Failed setting up tls for {}: {}", peer_address, e);
+                    return;
+                }
+            };
+            // the client certificate identifies the vehicle, so only vehicles get past this
+            if let Err(e) = Pin::new(&mut stream).accept().await {
+                warn!("Tls handshake with {} failed: {}", peer_address, e);
+                return;
+            }
+            let vin = match stream
+                .ssl()
+                .peer_certificate()
+                .and_then(|c| certificate_vin(&c))
+            {
+                Some(vin) => vin,
+                None => {
+                    warn!("Client certificate of {} holds no vin", peer_address);
+                    return;
+                }
+            };
+
+            if let Err(e) = handle_connection(stream, &vin, &store).await {
+                warn!("Telemetry connection of {} failed: {}", peer_address, e);
+            }
+        });
+    }
+}
+
+/// Vin of a vehicle's client certificate, which tesla issues with the vin as common name.
+fn certificate_vin(certificate: &X509Ref) -> Option<String> {
+    let common_name = certificate
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()?
+        .data()
+        .as_utf8()
+        .ok()?;
+
+    Some(common_name.to_string())
+}
+
+/// Applies the payloads of the vehicle with `vin` only, so one vehicle can't report for another.
+async fn handle_connection<S>(
+    stream: S,
+    vin: &str,
+    store: &TelemetryStore,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut websocket = tokio_tungstenite::accept_async(stream).await?;
+
+    while let Some(message) = websocket.next().await {
+        match message? {
+            Message::Binary(frame) => match decode_frame(&frame) {
+                Ok(Some(payload)) if !payload.vin.eq_ignore_ascii_case(vin) => {
+                    warn!(
+                        "Ignoring telemetry for {} sent with the certificate of {}",
+                        payload.vin, vin
+                    );
+                }
+                Ok(Some(payload)) => {
+                    debug!(
+                        "Received {} telemetry fields for {}",
+                        payload.data.len(),
+                        payload.vin
+                    );
+                    store.apply(&payload);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Failed decoding telemetry frame: {}", e),
+            },
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// The vehicle data payload of a flatbuffers envelope, or none for the other topics.
+fn decode_frame(frame: &[u8]) -> Result<Option<Payload>, Box<dyn Error + Send + Sync>> {
+    // FlatbuffersEnvelope: txid, topic, messageType, message, messageId
+    let envelope = Table::root(frame)?;
+    if envelope.vector(1)? != Some(TOPIC_VEHICLE_DATA) {
+        return Ok(None);
+    }
+
+    // FlatbuffersStream: createdAt, senderId, payload, deviceType, deviceId, deliveredAtEpochMs
+    let stream = envelope.table(3)?.ok_or("Envelope holds no message")?;
+    let payload = stream.vector(2)?.ok_or("Stream message holds no payload")?;
+
+    Ok(Some(Payload::decode(payload)?))
+}
+
+/// Just enough of the flatbuffers format to read the vectors and tables of the envelope.
+struct Table<'a> {
+    buffer: &'a [u8],
+    position: usize,
+    vtable: usize,
+}
+
+impl<'a> Table<'a> {
+    fn root(buffer: &'a [u8]) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Self::at(buffer, read_u32(buffer, 0)? as usize)
+    }
+
+    fn at(buffer: &'a [u8], position: usize) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let vtable = position as i64 - read_u32(buffer, position)? as i32 as i64;
+        if vtable < 0 {
+            return Err("Flatbuffer vtable out of bounds".into());
+        }
+
+        Ok(Self {
+            buffer,
+            position,
+            vtable: vtable as usize,
+        })
+    }
+
+    /// Absolute position of a field, none when it isn't set.
+    fn field(&self, index: usize) -> Result<Option<usize>, Box<dyn Error + Send + Sync>> {
+        let vtable_size = read_u16(self.buffer, self.vtable)? as usize;
+        let entry = 4 + 2 * index;
+        if entry >= vtable_size {
+            return Ok(None);
+        }
+
+        match read_u16(self.buffer, self.vtable + entry)? as usize {
+            0 => Ok(None),
+            offset => Ok(Some(self.position + offset)),
+        }
+    }
+
+    fn vector(&self, index: usize) -> Result<Option<&'a [u8]>, Box<dyn Error + Send + Sync>> {
+        let field = match self.field(index)? {
+            Some(field) => field,
+            None => return Ok(None),
+        };
+        let start = field + read_u32(self.buffer, field)? as usize;
+        let length = read_u32(self.buffer, start)? as usize;
+
+        self.buffer
+            .get(start + 4..start + 4 + length)
+            .map(Some)
+            .ok_or_else(|| "Flatbuffer vector out of bounds".into())
+    }
+
+    fn table(&self, index: usize) -> Result<Option<Table<'a>>, Box<dyn Error + Send + Sync>> {
+        match self.field(index)? {
+            Some(field) => Ok(Some(Table::at(
+                self.buffer,
+                field + read_u32(self.buffer, field)? as usize,
+            )?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn read_u16(buffer: &[u8], position: usize) -> Result<u16, Box<dyn Error + Send + Sync>> {
+    let bytes = buffer
+        .get(position..position + 2)
+        .ok_or("Flatbuffer read out of bounds")?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(buffer: &[u8], position: usize) -> Result<u32, Box<dyn Error + Send + Sync>> {
+    let bytes = buffer
+        .get(position..position + 4)
+        .ok_or("Flatbuffer read out of bounds")?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Body of the `fleet_telemetry_config` request, streaming the fields the receiver maps to the
+/// server at `hostname`.
+pub fn fleet_telemetry_config(
+    vins: &[String],
+    hostname: &str,
+    port: u16,
+    ca: &str,
+    interval_seconds: u32,
+    expires_at: DateTime<Utc>,
+) -> Value {
+    let fields: serde_json::Map<String, Value> = FIELDS
+        .iter()
+        .map(|field| {
+            (
+                field.to_string(),
+                json!({ "interval_seconds": interval_seconds }),
+            )
+        })
+        .collect();
+
+    json!({
+        "vins": vins,
+        "config": {
+            "hostname": hostname,
+            "port": port,
+            "ca": ca,
+            "exp": expires_at.timestamp(),
+            "fields": fields,
+            "alert_types": ["service"],
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use futures::SinkExt;
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::fs;
+
+    const VIN: &str = "5YJ3E7EB2KF000000";
+
+    /// Lays out an envelope the way the vehicle does, tables first and the vectors they point to after.
+    fn envelope(topic: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![0u8; 4];
+
+        let table = |buffer: &mut Vec<u8>, fields: usize| -> (usize, Vec<usize>) {
+            let vtable = buffer.len();
+            buffer.extend_from_slice(&((4 + 2 * fields) as u16).to_le_bytes());
+            buffer.extend_from_slice(&((4 + 4 * fields) as u16).to_le_bytes());
+            for i in 0..fields {
+                buffer.extend_from_slice(&((4 + 4 * i) as u16).to_le_bytes());
+            }
+            while buffer.len() % 4 != 0 {
+                buffer.push(0);
+            }
+            let position = buffer.len();
+            buffer.extend_from_slice(&((position - vtable) as i32).to_le_bytes());
+            buffer.extend(vec![0u8; 4 * fields]);
+            (
+                position,
+                (0..fields).map(|i| position + 4 + 4 * i).collect(),
+            )
+        };
+        let point = |buffer: &mut Vec<u8>, slot: usize, target: usize| {
+            buffer[slot..slot + 4].copy_from_slice(&((target - slot) as u32).to_le_bytes());
+        };
+        let vector = |buffer: &mut Vec<u8>, bytes: &[u8]| -> usize {
+            let position = buffer.len();
+            buffer.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            buffer.extend_from_slice(bytes);
+            while buffer.len() % 4 != 0 {
+                buffer.push(0);
+            }
+            position
+        };
+
+        let (envelope, envelope_slots) = table(&mut buffer, 5);
+        buffer[0..4].copy_from_slice(&(envelope as u32).to_le_bytes());
+        let topic = vector(&mut buffer, topic);
+        point(&mut buffer, envelope_slots[1], topic);
+        let (stream, stream_slots) = table(&mut buffer, 6);
+        point(&mut buffer, envelope_slots[3], stream);
+        let payload = vector(&mut buffer, payload);
+        point(&mut buffer, stream_slots[2], payload);
+
+        buffer
+    }
+
+    fn datum(field: Field, value: ValueKind) -> Datum {
+        Datum {
+            key: field as i32,
+            value: Some(DatumValue { value: Some(value) }),
+        }
+    }
+
+    fn payload() -> Payload {
+        Payload {
+            data: vec![
+                datum(Field::Soc, ValueKind::Double(78.5)),
+                datum(Field::Odometer, ValueKind::String("1000".into())),
+                datum(
+                    Field::Location,
+                    ValueKind::Location(LocationValue {
+                        latitude: 52.377956,
+                        longitude: 4.89707,
+                    }),
+                ),
+                datum(Field::AcChargingPower, ValueKind::Float(11.0)),
+                datum(Field::Gear, ValueKind::ShiftState(2)),
+            ],
+            created_at: Some(Timestamp {
+                seconds: Utc::now().timestamp(),
+                nanos: 0,
+            }),
+            vin: VIN.into(),
+        }
+    }
+
+    #[test]
+    fn decode_frame_reads_vehicle_data_payload_from_envelope() {
+        let frame = envelope(b"V", &payload().encode_to_vec());
+
+        // act
+        let decoded = decode_frame(&frame).unwrap();
+
+        assert_eq!(decoded, Some(payload()));
+        assert_eq!(
+            decode_frame(&envelope(b"alerts", &payload().encode_to_vec())).unwrap(),
+            None
+        );
+        assert!(decode_frame(&frame[..12]).is_err());
+    }
+
+    #[test]
+    fn streaming_data_maps_telemetry_to_si_units() {
+        let store = TelemetryStore::default();
+        store.apply(&payload());
+
+        // act
+        let streaming_data = store
+            .get(VIN, Duration::minutes(5))
+            .unwrap()
+            .streaming_data()
+            .unwrap();
+
+        assert_eq!(streaming_data.soc, 78.5);
+        assert_eq!(streaming_data.odometer, 1609344.0);
+        assert_eq!(streaming_data.power, 11.0);
+        assert_eq!(streaming_data.speed, 0.0);
+        assert_eq!(streaming_data.latitude, Some(52.377956));
+        assert!(store.get("other", Duration::minutes(5)).is_none());
+    }
+
+    #[test]
+    fn streaming_data_requires_location_odometer_and_soc() {
+        let mut telemetry = VehicleTelemetry::default();
+        telemetry.apply(&Payload {
+            data: vec![datum(Field::Soc, ValueKind::Int(80))],
+            ..payload()
+        });
+
+        // act
+        let streaming_data = telemetry.streaming_data();
+
+        assert!(streaming_data.is_none());
+    }
+
+    /// Replays the recorded frames to `handle_connection` of a vehicle with `vin`.
+    async fn replay_frames(vin: &'static str) -> Arc<TelemetryStore> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let store = Arc::new(TelemetryStore::default());
+        let server_store = store.clone();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_connection(stream, vin, &server_store).await.unwrap();
+        });
+        let frames: Vec<Vec<u8>> = serde_json::from_str::<Vec<String>>(
+            &fs::read_to_string("test-data/fleet-telemetry-frames.json").unwrap(),
+        )
+        .unwrap()
+        .iter()
+        .map(|frame| STANDARD.decode(frame).unwrap())
+        .collect();
+
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}", address))
+            .await
+            .unwrap();
+        for frame in frames {
+            client.send(Message::Binary(frame)).await.unwrap();
+        }
+        client.close(None).await.unwrap();
+        server.await.unwrap();
+
+        store
+    }
+
+    #[tokio::test]
+    async fn handle_connection_applies_frames_replayed_by_client() {
+        // act
+        let store = replay_frames(VIN).await;
+
+        // the recorded frames were created long ago
+        let telemetry = store.get(VIN, Duration::days(36500)).unwrap();
+        assert_eq!(telemetry.soc, Some(78.5));
+        assert_eq!(telemetry.gear, Some("D".into()));
+    }
+
+    #[test]
+    fn certificate_vin_reads_common_name() {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, VIN).unwrap();
+        let mut certificate = X509::builder().unwrap();
+        certificate.set_subject_name(&name.build()).unwrap();
+
+        // act
+        let vin = certificate_vin(&certificate.build());
+
+        assert_eq!(vin, Some(VIN.to_string()));
+    }
+
+    #[tokio::test]
+    async fn handle_connection_ignores_frames_of_other_vehicle_than_certificate() {
+        // act
+        let store = replay_frames("5YJ3E7EB2KF999999").await;
+
+        assert!(store.get(VIN, Duration::days(36500)).is_none());
+    }
+
+    #[test]
+    fn fleet_telemetry_config_lists_fields_with_interval() {
+        let expires_at = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+
+        // act
+        let config = fleet_telemetry_config(
+            &[VIN.to_string()],
+            "telemetry.example.com",
+            4443,
+            "-----BEGIN CERTIFICATE-----",
+            60,
+            expires_at,
+        );
+
+        assert_eq!(config["vins"], json!([VIN]));
+        assert_eq!(config["config"]["port"], 4443);
+        assert_eq!(config["config"]["exp"], 1704067200);
+        assert_eq!(
+            config["config"]["fields"]["ACChargingPower"],
+            json!({ "interval_seconds": 60 })
+        );
+    }
+}
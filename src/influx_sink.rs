@@ -0,0 +1,337 @@
+use crate::model::InfluxOutputConfig;
+use crate::output_sink::OutputSink;
+use async_trait::async_trait;
+use jarvis_lib::model::Measurement;
+use reqwest::header::AUTHORIZATION;
+use reqwest::Url;
+use std::error::Error;
+use tokio::net::UdpSocket;
+use tracing::{debug, info};
+
+// stay below the payload size influxdb accepts in a single udp packet
+const MAX_UDP_PAYLOAD_BYTES: usize = 8192;
+
+enum InfluxTransport {
+    Http(Url),
+    Udp(String),
+}
+
+/// Writes samples in the influx line protocol, over the http write api or udp.
+pub struct InfluxSink {
+    config: InfluxOutputConfig,
+    transport: InfluxTransport,
+    token: Option<String>,
+    http_client: reqwest::Client,
+}
+
+impl InfluxSink {
+    pub async fn new(
+        config: InfluxOutputConfig,
+        http_client: reqwest::Client,
+    ) -> Result<Self, Box<dyn Error>> {
+        let url = Url::parse(&config.url)?;
+        let transport = match url.scheme() {
+            "http" | "https" => InfluxTransport::Http(url),
+            "udp" => InfluxTransport::Udp(format!(
+                "{}:{}",
+                url.host_str().ok_or("Influx udp url has no host")?,
+                url.port().unwrap_or(8089)
+            )),
+            scheme => return Err(format!("Unsupported influx url scheme {}", scheme).into()),
+        };
+        let token = match &config.token {
+            Some(token) => token.resolve().await?,
+            None => None,
+        };
+
+        Ok(Self {
+            config,
+            transport,
+            token,
+            http_client,
+        })
+    }
+
+    async fn write_http(&self, url: &Url, lines: Vec<String>) -> Result<(), Box<dyn Error>> {
+        let mut request = self
+            .http_client
+            .post(url.clone())
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(lines.join("\n"));
+        if let Some(token) = &self.token {
+            request = request.header(AUTHORIZATION, format!("Token {}", token));
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("Influx write failed with status {}: {}", status, body).into());
+        }
+
+        Ok(())
+    }
+
+    async fn write_udp(&self, address: &str, lines: Vec<String>) -> Result<(), Box<dyn Error>> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(address).await?;
+
+        for packet in udp_packets(lines) {
+            socket.send(packet.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl OutputSink for InfluxSink {
+    fn name(&self) -> &str {
+        "influx"
+    }
+
+    async fn write(&self, measurements: &[Measurement]) -> Result<(), Box<dyn Error>> {
+        let lines: Vec<String> = measurements
+            .iter()
+            .flat_map(|measurement| line_protocol(&self.config.measurement, measurement))
+            .collect();
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        debug!("Writing {} lines to influx", lines.len());
+        let count = lines.len();
+
+        match &self.transport {
+            InfluxTransport::Http(url) => self.write_http(url, lines).await?,
+            InfluxTransport::Udp(address) => self.write_udp(address, lines).await?,
+        }
+
+        info!("Wrote {} samples to influx", count);
+
+        Ok(())
+    }
+}
+
+/// A line per sample, tagged with the location and sample identity and timestamped in nanoseconds.
+fn line_protocol(measurement_name: &str, measurement: &Measurement) -> Vec<String> {
+    let timestamp = measurement.measured_at_time.timestamp_nanos();
+
+    measurement
+        .samples
+        .iter()
+        .filter(|sample| sample.value.is_finite())
+        .map(|sample| {
+            format!(
+                "{},location={},entity_name={},sample_type={},sample_name={},metric_type={} value={} {}",
+                escape_measurement(measurement_name),
+                escape_tag(&measurement.location),
+                escape_tag(&sample.entity_name),
+                escape_tag(&format!("{:?}", sample.sample_type)),
+                escape_tag(&sample.sample_name),
+                escape_tag(&format!("{:?}", sample.metric_type)),
+                sample.value,
+                timestamp
+            )
+        })
+        .collect()
+}
+
+fn escape_measurement(value: &str) -> String {
+    value.replace(',', "\\,").replace(' ', "\\ ")
+}
+
+fn escape_tag(value: &str) -> String {
+    // influx drops tags with an empty value, which changes the series
+    if value.is_empty() {
+        return "none".to_string();
+    }
+
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// Groups lines into packets that fit a single datagram.
+fn udp_packets(lines: Vec<String>) -> Vec<String> {
+    let mut packets: Vec<String> = vec![];
+    let mut packet = String::new();
+
+    for line in lines {
+        if !packet.is_empty() && packet.len() + line.len() + 1 > MAX_UDP_PAYLOAD_BYTES {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(&line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::SecretValue;
+    use chrono::{TimeZone, Utc};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use jarvis_lib::model::{EntityType, MetricType, Sample, SampleType};
+    use std::convert::Infallible;
+    use std::env;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    fn measurement() -> Measurement {
+        Measurement {
+            id: "id".into(),
+            source: "jarvis-tesla-exporter".into(),
+            location: "My Home".into(),
+            samples: vec![Sample {
+                entity_type: EntityType::Device,
+                entity_name: "jarvis-tesla-exporter".into(),
+                sample_type: SampleType::ElectricityConsumption,
+                sample_name: "Tessie".into(),
+                metric_type: MetricType::Gauge,
+                value: 11000.0,
+            }],
+            measured_at_time: Utc.timestamp_opt(1685577600, 0).unwrap(),
+        }
+    }
+
+    fn influx_config(url: String) -> InfluxOutputConfig {
+        InfluxOutputConfig {
+            url,
+            token: Some(SecretValue {
+                value: Some("secret".into()),
+                ..Default::default()
+            }),
+            measurement: "jarvis_tesla".into(),
+        }
+    }
+
+    #[test]
+    fn line_protocol_escapes_tags() {
+        // act
+        let lines = line_protocol("jarvis_tesla", &measurement());
+
+        assert_eq!(
+            lines,
+            vec!["jarvis_tesla,location=My\\ Home,entity_name=jarvis-tesla-exporter,sample_type=ElectricityConsumption,sample_name=Tessie,metric_type=Gauge value=11000 1685577600000000000".to_string()]
+        );
+    }
+
+    #[test]
+    fn udp_packets_splits_lines_over_packets() {
+        let line = "a".repeat(MAX_UDP_PAYLOAD_BYTES / 2 - 1);
+
+        // act
+        let packets = udp_packets(vec![line.clone(), line.clone(), line]);
+
+        assert_eq!(packets.len(), 2);
+        assert!(packets.iter().all(|p| p.len() <= MAX_UDP_PAYLOAD_BYTES));
+    }
+
+    #[tokio::test]
+    async fn write_posts_lines_to_http_write_api() {
+        // (authorization header, body) of each received write
+        let received = Arc::new(Mutex::new(Vec::<(Option<String>, String)>::new()));
+        let server_received = received.clone();
+        let make_service = make_service_fn(move |_| {
+            let received = server_received.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let received = received.clone();
+                    async move {
+                        let authorization = request
+                            .headers()
+                            .get("authorization")
+                            .map(|h| h.to_str().unwrap().to_string());
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        received
+                            .lock()
+                            .unwrap()
+                            .push((authorization, String::from_utf8(body.to_vec()).unwrap()));
+                        let mut response = Response::new(Body::empty());
+                        *response.status_mut() = hyper::StatusCode::NO_CONTENT;
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!(
+            "http://{}/api/v2/write?org=home&bucket=tesla",
+            server.local_addr()
+        );
+        tokio::spawn(server);
+        let sink = InfluxSink::new(influx_config(url), reqwest::Client::new())
+            .await
+            .unwrap();
+
+        // act
+        sink.write(&[measurement()]).await.unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0, Some("Token secret".to_string()));
+        assert!(received[0]
+            .1
+            .starts_with("jarvis_tesla,location=My\\ Home,"));
+    }
+
+    #[tokio::test]
+    async fn write_sends_lines_over_udp() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://127.0.0.1:{}", socket.local_addr().unwrap().port());
+        let sink = InfluxSink::new(influx_config(url), reqwest::Client::new())
+            .await
+            .unwrap();
+
+        // act
+        sink.write(&[measurement()]).await.unwrap();
+
+        let mut buffer = [0u8; MAX_UDP_PAYLOAD_BYTES];
+        let size = socket.recv(&mut buffer).await.unwrap();
+        let packet = String::from_utf8(buffer[..size].to_vec()).unwrap();
+        assert!(packet.ends_with("value=11000 1685577600000000000"));
+    }
+
+    #[tokio::test]
+    async fn new_resolves_token_from_env() {
+        env::set_var("JARVIS_TESLA_INFLUX_TOKEN_TEST", "from-env");
+        let config = InfluxOutputConfig {
+            token: Some(SecretValue {
+                env: Some("JARVIS_TESLA_INFLUX_TOKEN_TEST".into()),
+                ..Default::default()
+            }),
+            ..influx_config("http://localhost:8086/api/v2/write".into())
+        };
+
+        // act
+        let sink = InfluxSink::new(config, reqwest::Client::new())
+            .await
+            .unwrap();
+
+        assert_eq!(sink.token.as_deref(), Some("from-env"));
+        assert!(!format!("{:?}", sink.config).contains("from-env"));
+    }
+
+    #[tokio::test]
+    async fn new_rejects_unsupported_scheme() {
+        // act
+        let result = InfluxSink::new(
+            influx_config("tcp://localhost:8086".into()),
+            reqwest::Client::new(),
+        )
+        .await;
+
+        assert!(result.is_err());
+    }
+}
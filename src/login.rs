@@ -0,0 +1,301 @@
+use crate::cli::LoginArgs;
+use crate::model::{AccountConfig, TeslaBackend, TeslaRegion};
+use crate::tesla_api_client::TeslaApiClient;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use k8s_openapi::ByteString;
+use kube::api::{Api, PostParams};
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::{self, Write};
+use tracing::info;
+use uuid::Uuid;
+
+const OWNER_API_SCOPE: &str = "openid email offline_access";
+const FLEET_API_SCOPE: &str =
+    "openid offline_access vehicle_device_data vehicle_cmds vehicle_charging_cmds";
+
+/// Runs the oauth authorization code flow with pkce; tesla redirects to a page that doesn't exist
+/// after logging in, so the redirected url gets pasted back instead of being received.
+pub async fn login(
+    args: LoginArgs,
+    tesla_api_client: &TeslaApiClient,
+) -> Result<(), Box<dyn Error>> {
+    if args.backend == TeslaBackend::FleetApi && args.client_id.is_none() {
+        return Err(Box::<dyn Error>::from(
+            "The fleet api needs the client id of a registered application",
+        ));
+    }
+
+    let account = AccountConfig {
+        name: "login".into(),
+        refresh_token: "".into(),
+        refresh_token_file: None,
+        refresh_token_env: None,
+        refresh_token_secret_ref: None,
+        backend: args.backend,
+        region: args.region,
+        client_id: args.client_id.clone(),
+        api_base_url: None,
+        command_key_file: None,
+        vehicle_ids: vec![],
+    };
+    let redirect_uri = args
+        .redirect_uri
+        .clone()
+        .unwrap_or_else(|| default_redirect_uri(args.region).to_string());
+
+    let code_verifier = generate_code_verifier();
+    let state = Uuid::new_v4().to_simple().to_string();
+
+    let url = authorize_url(&account, &code_verifier, &state, &redirect_uri)?;
+
+    println!(
+        "Open the following url in a browser, log in and paste the url of the page you're redirected to:\n\n{}\n",
+        url
+    );
+    print!("Redirected url or code: ");
+    io::stdout().flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+
+    let code = parse_authorization_code(&input, &state)?;
+
+    let token = tesla_api_client
+        .exchange_authorization_code(
+            &account,
+            &code,
+            &code_verifier,
+            &redirect_uri,
+            args.client_secret.as_deref(),
+        )
+        .await?;
+
+    let refresh_token = token
+        .refresh_token
+        .ok_or("Token response does not contain a refresh token")?;
+
+    match &args.secret_name {
+        Some(secret_name) => {
+            store_refresh_token_in_secret(
+                secret_name,
+                &args.secret_key,
+                args.namespace.as_deref(),
+                &refresh_token,
+            )
+            .await?;
+
+            println!(
+                "Stored refresh token in key {} of secret {}",
+                args.secret_key, secret_name
+            );
+        }
+        None => println!("{}", refresh_token),
+    }
+
+    Ok(())
+}
+
+fn default_redirect_uri(region: TeslaRegion) -> &'static str {
+    match region {
+        TeslaRegion::China => "https://auth.tesla.cn/void/callback",
+        _ => "https://auth.tesla.com/void/callback",
+    }
+}
+
+fn generate_code_verifier() -> String {
+    // 64 characters, within the 43 to 128 pkce allows
+    format!(
+        "{}{}",
+        Uuid::new_v4().to_simple(),
+        Uuid::new_v4().to_simple()
+    )
+}
+
+fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+fn authorize_url(
+    account: &AccountConfig,
+    code_verifier: &str,
+    state: &str,
+    redirect_uri: &str,
+) -> Result<Url, Box<dyn Error>> {
+    let challenge = code_challenge(code_verifier);
+    let scope = match account.backend {
+        TeslaBackend::OwnerApi => OWNER_API_SCOPE,
+        TeslaBackend::FleetApi => FLEET_API_SCOPE,
+    };
+
+    let url = Url::parse_with_params(
+        account.authorize_url(),
+        &[
+            ("client_id", account.client_id()),
+            ("code_challenge", challenge.as_str()),
+            ("code_challenge_method", "S256"),
+            ("redirect_uri", redirect_uri),
+            ("response_type", "code"),
+            ("scope", scope),
+            ("state", state),
+        ],
+    )?;
+
+    Ok(url)
+}
+
+/// Accepts either the full redirected url or just the code.
+fn parse_authorization_code(input: &str, expected_state: &str) -> Result<String, Box<dyn Error>> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err(Box::<dyn Error>::from("No redirected url or code entered"));
+    }
+
+    if !input.starts_with("http") {
+        return Ok(input.to_string());
+    }
+
+    let url = Url::parse(input)?;
+    let query: BTreeMap<String, String> = url.query_pairs().into_owned().collect();
+
+    if let Some(error) = query.get("error") {
+        return Err(Box::<dyn Error>::from(format!("Login failed: {}", error)));
+    }
+
+    // without a matching state the redirect may come from another login
+    if query.get("state").map(String::as_str) != Some(expected_state) {
+        return Err(Box::<dyn Error>::from(
+            "State in redirected url is missing or doesn't match this login",
+        ));
+    }
+
+    query
+        .get("code")
+        .cloned()
+        .ok_or_else(|| Box::<dyn Error>::from("Redirected url contains no code"))
+}
+
+async fn store_refresh_token_in_secret(
+    secret_name: &str,
+    secret_key: &str,
+    namespace: Option<&str>,
+    refresh_token: &str,
+) -> Result<(), Box<dyn Error>> {
+    let kube_client = kube::Client::try_default().await?;
+    let namespace = namespace
+        .map(str::to_string)
+        .unwrap_or_else(|| kube_client.default_namespace().to_string());
+
+    let secrets: Api<Secret> = Api::namespaced(kube_client, &namespace);
+    let value = ByteString(refresh_token.as_bytes().to_vec());
+
+    match secrets.get_opt(secret_name).await? {
+        Some(mut secret) => {
+            secret
+                .data
+                .get_or_insert_with(BTreeMap::new)
+                .insert(secret_key.to_string(), value);
+
+            secrets
+                .replace(secret_name, &PostParams::default(), &secret)
+                .await?;
+        }
+        None => {
+            let secret = Secret {
+                metadata: ObjectMeta {
+                    name: Some(secret_name.to_string()),
+                    ..Default::default()
+                },
+                data: Some(BTreeMap::from([(secret_key.to_string(), value)])),
+                ..Default::default()
+            };
+
+            secrets.create(&PostParams::default(), &secret).await?;
+        }
+    }
+
+    info!(
+        "Stored refresh token in secret {}/{}",
+        namespace, secret_name
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_challenge_matches_rfc_7636_example() {
+        // act
+        let code_challenge = code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk");
+
+        assert_eq!(
+            code_challenge,
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM".to_string()
+        );
+    }
+
+    #[test]
+    fn parse_authorization_code_reads_code_from_redirected_url() {
+        // act
+        let code = parse_authorization_code(
+            "https://auth.tesla.com/void/callback?code=abcd&state=1234&issuer=https%3A%2F%2Fauth.tesla.com%2Foauth2%2Fv3\n",
+            "1234",
+        )
+        .unwrap();
+
+        assert_eq!(code, "abcd".to_string());
+    }
+
+    #[test]
+    fn parse_authorization_code_rejects_missing_state() {
+        // act
+        let result =
+            parse_authorization_code("https://auth.tesla.com/void/callback?code=abcd", "1234");
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn authorize_url_requests_fleet_api_scopes_for_fleet_api() {
+        let account = AccountConfig {
+            name: "login".into(),
+            refresh_token: "".into(),
+            refresh_token_file: None,
+            refresh_token_env: None,
+            refresh_token_secret_ref: None,
+            backend: TeslaBackend::FleetApi,
+            region: TeslaRegion::Europe,
+            client_id: Some("my-client-id".into()),
+            api_base_url: None,
+            command_key_file: None,
+            vehicle_ids: vec![],
+        };
+
+        // act
+        let url =
+            authorize_url(&account, "verifier", "1234", "https://example.com/callback").unwrap();
+
+        let query: BTreeMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(query["client_id"], "my-client-id");
+        assert_eq!(query["scope"], FLEET_API_SCOPE);
+    }
+
+    #[test]
+    fn parse_authorization_code_rejects_other_state() {
+        // act
+        let result = parse_authorization_code(
+            "https://auth.tesla.com/void/callback?code=abcd&state=5678",
+            "1234",
+        );
+
+        assert!(result.is_err());
+    }
+}
@@ -1,21 +1,157 @@
+mod battery;
+mod cassette;
+mod cli;
+mod commands;
+mod config_validation;
+mod csv_sink;
+mod efficiency;
+mod event_client;
+mod fleet_telemetry;
+mod influx_sink;
+mod login;
+mod metrics;
 mod model;
+mod mqtt_client;
+mod output_sink;
+mod rate_limiter;
+mod secrets;
+mod shadow;
+mod signed_command;
+#[cfg(test)]
+mod snapshot;
+mod solar_charging;
+mod tariff_scheduler;
 mod tesla_api_client;
+mod tesla_api_error;
+mod units;
+mod vehicle_events;
+mod vehicle_state_client;
+mod webhook_client;
 
-use jarvis_lib::config_client::{ConfigClient, ConfigClientConfig};
+use clap::Parser;
+use cli::{Cli, Command, DaemonArgs};
+use event_client::{EventClient, EventClientConfig};
+use fleet_telemetry::{FleetTelemetryServerConfig, TelemetryStore};
+use jarvis_lib::config_client::{ConfigClient, ConfigClientConfig, SetDefaults};
 use jarvis_lib::exporter_service::{ExporterService, ExporterServiceConfig};
 use jarvis_lib::nats_client::{NatsClient, NatsClientConfig};
 use jarvis_lib::state_client::{StateClient, StateClientConfig};
-use tesla_api_client::TeslaApiClient;
+use model::Config;
+use mqtt_client::{MqttClient, MqttClientConfig};
+use std::sync::Arc;
+use std::time::Duration;
+use tesla_api_client::{TeslaApiClient, TeslaApiClientConfig};
+use tokio::time::sleep;
+use tracing::error;
+use vehicle_state_client::{VehicleStateClient, VehicleStateClientConfig};
 
 #[tokio::main]
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt()
+    let cli = Cli::parse();
+
+    let subscriber = tracing_subscriber::fmt()
         .json()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env());
+    if !matches!(
+        cli.command,
+        None | Some(Command::Daemon(_)) | Some(Command::Controller(_))
+    ) {
+        // keep stdout for the output of subcommands
+        subscriber.with_writer(std::io::stderr).init();
+    } else {
+        subscriber.init();
+    }
+
+    let command = match cli.command {
+        Some(command) => command,
+        None => return run_exporter().await,
+    };
+
+    // only measure and shadow read the state, the other subcommands just talk to the api
+    let tesla_api_client = match &command {
+        Command::Measure(args) => local_tesla_api_client(args.state_file.clone(), args.dry_run)?,
+        Command::Shadow(_) => cluster_tesla_api_client(true, None).await?,
+        Command::Daemon(args) => {
+            let telemetry_store = args
+                .fleet_telemetry_address
+                .map(|_| Arc::new(TelemetryStore::default()));
+            cluster_tesla_api_client(false, telemetry_store).await?
+        }
+        Command::Controller(_) => cluster_tesla_api_client(false, None).await?,
+        _ => local_tesla_api_client("vehicle-state.json".to_string(), true)?,
+    };
+
+    match command {
+        Command::Login(args) => login::login(args, &tesla_api_client).await,
+        Command::Vehicles(args) => commands::vehicles(args, &tesla_api_client).await,
+        Command::Data(args) => commands::vehicle_data(args, &tesla_api_client).await,
+        Command::Stream(args) => commands::stream(args, &tesla_api_client).await,
+        Command::Measure(args) => commands::measure(args, &tesla_api_client).await,
+        Command::Shadow(args) => shadow::shadow(args, &tesla_api_client).await,
+        Command::Daemon(args) => run_daemon(args, tesla_api_client).await,
+        Command::Controller(args) => solar_charging::run_controller(args, &tesla_api_client).await,
+        Command::CommandKey(args) => commands::command_key(args),
+        Command::TelemetryConfig(args) => commands::telemetry_config(args, &tesla_api_client).await,
+        Command::ConfigSchema => commands::config_schema(),
+    }
+}
+
+async fn run_exporter() -> Result<(), Box<dyn std::error::Error>> {
+    let tesla_api_client = cluster_tesla_api_client(false, None).await?;
+
+    let mut exporter_service = exporter_service(tesla_api_client).await?;
+
+    exporter_service.run().await?;
+
+    Ok(())
+}
+
+async fn run_daemon(
+    args: DaemonArgs,
+    tesla_api_client: TeslaApiClient,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(metrics_address) = args.metrics_address {
+        let metrics = tesla_api_client.metrics();
+
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(metrics_address, metrics).await {
+                error!("Serving metrics failed: {}", e);
+            }
+        });
+    }
 
-    let tesla_api_client = TeslaApiClient::new();
+    if let (Some(address), Some(telemetry_store)) = (
+        args.fleet_telemetry_address,
+        tesla_api_client.telemetry_store(),
+    ) {
+        let server_config = FleetTelemetryServerConfig {
+            address,
+            cert_file: args.fleet_telemetry_cert_file.clone(),
+            key_file: args.fleet_telemetry_key_file.clone(),
+            ca_file: args.fleet_telemetry_ca_file.clone(),
+        };
 
+        tokio::spawn(async move {
+            if let Err(e) = fleet_telemetry::serve(server_config, telemetry_store).await {
+                error!("Receiving fleet telemetry failed: {}", e);
+            }
+        });
+    }
+
+    let mut exporter_service = exporter_service(tesla_api_client).await?;
+
+    loop {
+        if let Err(e) = exporter_service.run().await {
+            error!("Exporter run failed: {}", e);
+        }
+
+        sleep(Duration::from_secs(args.interval_seconds)).await;
+    }
+}
+
+async fn exporter_service(
+    tesla_api_client: TeslaApiClient,
+) -> Result<ExporterService<Config>, Box<dyn std::error::Error>> {
     let state_client_config = StateClientConfig::from_env().await?;
     let state_client = StateClient::new(state_client_config);
 
@@ -25,17 +161,66 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config_client_config = ConfigClientConfig::from_env()?;
     let config_client = ConfigClient::new(config_client_config);
 
+    // fail at startup instead of in every run
+    let mut config: Config = config_client.read_config_from_file()?;
+    config.set_defaults();
+    config_validation::validate(&config)?;
+
     let exporter_service_config = ExporterServiceConfig::new(
         config_client,
         nats_client,
         state_client,
         Box::new(tesla_api_client),
     )?;
-    let mut exporter_service = ExporterService::new(exporter_service_config);
 
-    exporter_service.run().await?;
+    Ok(ExporterService::new(exporter_service_config))
+}
 
-    Ok(())
+/// Client keeping state in the exporter's configmap.
+async fn cluster_tesla_api_client(
+    read_only: bool,
+    telemetry_store: Option<Arc<TelemetryStore>>,
+) -> Result<TeslaApiClient, Box<dyn std::error::Error>> {
+    let vehicle_state_client_config = VehicleStateClientConfig::from_env(read_only).await?;
+    let vehicle_state_client = VehicleStateClient::new(vehicle_state_client_config);
+
+    // shadow runs mustn't publish next to the production exporter
+    let (mqtt_client, event_client) = if read_only {
+        (None, None)
+    } else {
+        (
+            MqttClientConfig::from_env()?.map(MqttClient::new),
+            EventClientConfig::from_env()?.map(EventClient::new),
+        )
+    };
+
+    let tesla_api_client_config = TeslaApiClientConfig::from_env(
+        vehicle_state_client,
+        mqtt_client,
+        event_client,
+        telemetry_store,
+    )?;
+
+    Ok(TeslaApiClient::new(tesla_api_client_config))
+}
+
+/// Client for subcommands run from a workstation, keeping state in a local file.
+fn local_tesla_api_client(
+    state_file_path: String,
+    read_only: bool,
+) -> Result<TeslaApiClient, Box<dyn std::error::Error>> {
+    let vehicle_state_client_config = VehicleStateClientConfig::new(
+        None,
+        state_file_path,
+        "jarvis-tesla-exporter".to_string(),
+        read_only,
+    )?;
+    let vehicle_state_client = VehicleStateClient::new(vehicle_state_client_config);
+
+    let tesla_api_client_config =
+        TeslaApiClientConfig::from_env(vehicle_state_client, None, None, None)?;
+
+    Ok(TeslaApiClient::new(tesla_api_client_config))
 }
 
 #[cfg(test)]
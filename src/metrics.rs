@@ -0,0 +1,425 @@
+use crate::model::VehicleStatus;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use prometheus::{
+    Encoder, GaugeVec, Histogram, HistogramOpts, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use reqwest::Url;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{error, info};
+
+const VEHICLE_LABELS: [&str; 3] = ["vin", "display_name", "geofence"];
+
+/// Prometheus metrics for the vehicles and the exporter itself, served by `serve` in daemon mode.
+pub struct Metrics {
+    registry: Registry,
+    charger_power: GaugeVec,
+    charge_energy_added: GaugeVec,
+    odometer: GaugeVec,
+    availability: GaugeVec,
+    soc: GaugeVec,
+    estimated_full_range: GaugeVec,
+    range_degradation: GaugeVec,
+    driving_consumption: GaugeVec,
+    average_driving_consumption: GaugeVec,
+    driving_energy: GaugeVec,
+    planned_charging_power: GaugeVec,
+    planned_charging_hours: GaugeVec,
+    expected_charging_cost: GaugeVec,
+    api_requests: IntCounterVec,
+    streaming_duration: Histogram,
+    token_refresh_failures: IntCounterVec,
+    /// labels last used per vin, to drop series once a vehicle moved to another geofence
+    vehicle_labels: Mutex<HashMap<String, [String; 3]>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let vehicle_gauge = |name: &str, help: &str| {
+            let gauge = GaugeVec::new(Opts::new(name, help), &VEHICLE_LABELS)
+                .expect("Invalid gauge definition");
+            registry
+                .register(Box::new(gauge.clone()))
+                .expect("Gauge registered twice");
+            gauge
+        };
+
+        let charger_power = vehicle_gauge(
+            "tesla_charger_power_watts",
+            "Power the vehicle is charging with",
+        );
+        let charge_energy_added = vehicle_gauge(
+            "tesla_charge_energy_joules",
+            "Energy charged since the exporter started counting",
+        );
+        let odometer = vehicle_gauge("tesla_odometer_meters", "Odometer of the vehicle");
+        let availability = vehicle_gauge(
+            "tesla_availability",
+            "1 awake, 0 asleep, -1 offline, -2 in service, -3 polling failed",
+        );
+        let soc = vehicle_gauge("tesla_battery_level_percent", "State of charge");
+        let estimated_full_range = vehicle_gauge(
+            "tesla_estimated_full_range_meters",
+            "Rated range extrapolated to a full battery",
+        );
+        let range_degradation = vehicle_gauge(
+            "tesla_range_degradation_percent",
+            "Full range lost compared to the first estimate",
+        );
+        let driving_consumption = vehicle_gauge(
+            "tesla_driving_consumption_wh_per_km",
+            "Consumption over the last driven segment",
+        );
+        let average_driving_consumption = vehicle_gauge(
+            "tesla_average_driving_consumption_wh_per_km",
+            "Consumption over the recently driven segments",
+        );
+        let driving_energy = vehicle_gauge(
+            "tesla_driving_energy_joules",
+            "Energy spent driving since the exporter started counting",
+        );
+        let planned_charging_power = vehicle_gauge(
+            "tesla_planned_charging_power_watts",
+            "Power the tariff plan charges with right now, 0 outside planned periods",
+        );
+        let planned_charging_hours = vehicle_gauge(
+            "tesla_planned_charging_hours",
+            "Hours the tariff plan charges before departure",
+        );
+        let expected_charging_cost = vehicle_gauge(
+            "tesla_expected_charging_cost",
+            "Cost of the tariff plan in the currency of the prices",
+        );
+
+        let api_requests = IntCounterVec::new(
+            Opts::new(
+                "tesla_api_requests_total",
+                "Requests to the tesla api by endpoint and status",
+            ),
+            &["endpoint", "status"],
+        )
+        .expect("Invalid counter definition");
+        registry
+            .register(Box::new(api_requests.clone()))
+            .expect("Counter registered twice");
+
+        let streaming_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "tesla_streaming_duration_seconds",
+                "Time until the streaming api returned the first update",
+            )
+            .buckets(vec![0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0]),
+        )
+        .expect("Invalid histogram definition");
+        registry
+            .register(Box::new(streaming_duration.clone()))
+            .expect("Histogram registered twice");
+
+        let token_refresh_failures = IntCounterVec::new(
+            Opts::new(
+                "tesla_token_refresh_failures_total",
+                "Failures getting an access token by account",
+            ),
+            &["account"],
+        )
+        .expect("Invalid counter definition");
+        registry
+            .register(Box::new(token_refresh_failures.clone()))
+            .expect("Counter registered twice");
+
+        Self {
+            registry,
+            charger_power,
+            charge_energy_added,
+            odometer,
+            availability,
+            soc,
+            estimated_full_range,
+            range_degradation,
+            driving_consumption,
+            average_driving_consumption,
+            driving_energy,
+            planned_charging_power,
+            planned_charging_hours,
+            expected_charging_cost,
+            api_requests,
+            streaming_duration,
+            token_refresh_failures,
+            vehicle_labels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record_vehicle(&self, vehicle: &VehicleStatus) {
+        let labels = [
+            vehicle.vin.clone(),
+            vehicle.display_name.clone(),
+            vehicle.location.clone(),
+        ];
+
+        let previous_labels = self
+            .vehicle_labels
+            .lock()
+            .unwrap()
+            .insert(vehicle.vin.clone(), labels.clone());
+        if let Some(previous_labels) = previous_labels {
+            if previous_labels != labels {
+                self.remove_vehicle_series(&previous_labels);
+            }
+        }
+
+        let label_values = [labels[0].as_str(), labels[1].as_str(), labels[2].as_str()];
+        let set = |gauge: &GaugeVec, value: Option<f64>| match value {
+            Some(value) => gauge.with_label_values(&label_values).set(value),
+            None => {
+                let _ = gauge.remove_label_values(&label_values);
+            }
+        };
+
+        set(&self.charger_power, Some(vehicle.charger_power));
+        set(&self.charge_energy_added, Some(vehicle.charge_energy_added));
+        set(&self.odometer, Some(vehicle.odometer));
+        set(&self.availability, Some(vehicle.availability));
+        set(&self.soc, vehicle.soc);
+        set(&self.estimated_full_range, vehicle.estimated_full_range);
+        set(&self.range_degradation, vehicle.range_degradation);
+        set(&self.driving_consumption, vehicle.driving_consumption);
+        set(
+            &self.average_driving_consumption,
+            vehicle.average_driving_consumption,
+        );
+        set(&self.driving_energy, Some(vehicle.driving_energy));
+    }
+
+    /// Records the plan of tariff charging, before `record_vehicle` with the same status.
+    pub fn record_charging_plan(
+        &self,
+        vehicle: &VehicleStatus,
+        planned_power: f64,
+        planned_hours: f64,
+        expected_cost: f64,
+    ) {
+        let label_values = [
+            vehicle.vin.as_str(),
+            vehicle.display_name.as_str(),
+            vehicle.location.as_str(),
+        ];
+
+        self.planned_charging_power
+            .with_label_values(&label_values)
+            .set(planned_power);
+        self.planned_charging_hours
+            .with_label_values(&label_values)
+            .set(planned_hours);
+        self.expected_charging_cost
+            .with_label_values(&label_values)
+            .set(expected_cost);
+    }
+
+    /// `status` is none if no response was received.
+    pub fn record_api_request(&self, url: &str, status: Option<StatusCode>) {
+        let status = status.map_or("error".to_string(), |s| s.as_u16().to_string());
+
+        self.api_requests
+            .with_label_values(&[&endpoint_label(url), &status])
+            .inc();
+    }
+
+    pub fn record_streaming_duration(&self, duration: Duration) {
+        self.streaming_duration.observe(duration.as_secs_f64());
+    }
+
+    pub fn record_token_refresh_failure(&self, account: &str) {
+        self.token_refresh_failures
+            .with_label_values(&[account])
+            .inc();
+    }
+
+    /// Metrics in the prometheus text format.
+    pub fn gather(&self) -> Result<String, Box<dyn Error>> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+
+        Ok(String::from_utf8(buffer)?)
+    }
+
+    fn remove_vehicle_series(&self, labels: &[String; 3]) {
+        let label_values = [labels[0].as_str(), labels[1].as_str(), labels[2].as_str()];
+
+        for gauge in [
+            &self.charger_power,
+            &self.charge_energy_added,
+            &self.odometer,
+            &self.availability,
+            &self.soc,
+            &self.estimated_full_range,
+            &self.range_degradation,
+            &self.driving_consumption,
+            &self.average_driving_consumption,
+            &self.driving_energy,
+            &self.planned_charging_power,
+            &self.planned_charging_hours,
+            &self.expected_charging_cost,
+        ] {
+            let _ = gauge.remove_label_values(&label_values);
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Path of the url with ids replaced, so every vehicle shares the same label values.
+fn endpoint_label(url: &str) -> String {
+    let path = match Url::parse(url) {
+        Ok(url) => url.path().to_string(),
+        Err(_) => return url.to_string(),
+    };
+
+    // the segment after `vehicles` is a vehicle id or vin, other segments like the `1` in
+    // `/api/1/` are part of the endpoint
+    let segments: Vec<&str> = path.split('/').collect();
+    segments
+        .iter()
+        .enumerate()
+        .map(|(index, segment)| {
+            if index > 0 && segments[index - 1] == "vehicles" && !segment.is_empty() {
+                ":id"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+/// Serves `/metrics` until the process stops.
+pub async fn serve(address: SocketAddr, metrics: Arc<Metrics>) -> Result<(), Box<dyn Error>> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let metrics = metrics.clone();
+
+                async move { Ok::<_, Infallible>(handle_request(&metrics, request)) }
+            }))
+        }
+    });
+
+    info!("Serving metrics on {}", address);
+
+    Server::try_bind(&address)?.serve(make_service).await?;
+
+    Ok(())
+}
+
+fn handle_request(metrics: &Metrics, request: Request<Body>) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return response;
+    }
+
+    match metrics.gather() {
+        Ok(body) => {
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                header::HeaderValue::from_static("text/plain; version=0.0.4"),
+            );
+            *response.body_mut() = Body::from(body);
+        }
+        Err(e) => {
+            error!("Failed gathering metrics: {}", e);
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vehicle_status(location: &str) -> VehicleStatus {
+        VehicleStatus {
+            vin: "5YJ3E7EB2KF000000".into(),
+            display_name: "Tessie".into(),
+            location: location.into(),
+            charger_power: 11000.0,
+            charge_energy_added: 3600000.0,
+            odometer: 10000.0,
+            availability: 1.0,
+            soc: Some(80.0),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn record_vehicle_exposes_gauges_with_vehicle_labels() {
+        let metrics = Metrics::new();
+
+        // act
+        metrics.record_vehicle(&vehicle_status("My Home"));
+
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            "tesla_charger_power_watts{display_name=\"Tessie\",geofence=\"My Home\",vin=\"5YJ3E7EB2KF000000\"} 11000"
+        ));
+        assert!(output.contains("tesla_battery_level_percent{"));
+        assert!(!output.contains("tesla_estimated_full_range_meters{"));
+    }
+
+    #[test]
+    fn record_vehicle_drops_series_of_previous_geofence() {
+        let metrics = Metrics::new();
+        metrics.record_vehicle(&vehicle_status("My Home"));
+
+        // act
+        metrics.record_vehicle(&vehicle_status("Other"));
+
+        let output = metrics.gather().unwrap();
+        assert!(!output.contains("geofence=\"My Home\""));
+        assert!(output.contains("geofence=\"Other\""));
+    }
+
+    #[test]
+    fn record_charging_plan_drops_series_with_vehicle() {
+        let metrics = Metrics::new();
+        metrics.record_charging_plan(&vehicle_status("My Home"), 11000.0, 3.5, 2.1);
+        metrics.record_vehicle(&vehicle_status("My Home"));
+
+        // act
+        metrics.record_vehicle(&vehicle_status("Other"));
+
+        let output = metrics.gather().unwrap();
+        assert!(!output.contains("tesla_planned_charging_hours{"));
+    }
+
+    #[test]
+    fn record_api_request_labels_endpoint_without_ids() {
+        let metrics = Metrics::new();
+
+        // act
+        metrics.record_api_request(
+            "https://owner-api.teslamotors.com/api/1/vehicles/12345/vehicle_data",
+            Some(StatusCode::REQUEST_TIMEOUT),
+        );
+
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            "tesla_api_requests_total{endpoint=\"/api/1/vehicles/:id/vehicle_data\",status=\"408\"} 1"
+        ));
+    }
+}
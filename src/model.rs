@@ -1,31 +1,465 @@
+use crate::battery::BatteryHistory;
+use crate::efficiency::DrivingEfficiency;
+use crate::secrets::{SecretRef, SecretValue, REDACTED};
+use crate::units::{self, Units};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
 use geoutils::{Distance, Location};
 use jarvis_lib::config_client::SetDefaults;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
-    pub refresh_token: String,
-    pub geofences: Vec<GeofenceConfig>,
+    /// single account config from before `accounts`, moved into `accounts` by `set_defaults`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub vehicle_ids: Vec<String>,
+    #[serde(default)]
+    pub accounts: Vec<AccountConfig>,
+    /// shared by the vehicles of all accounts
+    pub geofences: Vec<GeofenceConfig>,
+    /// usable battery capacity, to convert a drop in state of charge into energy
+    #[serde(default)]
+    pub battery_capacity_kwh: f64,
+    #[serde(default)]
+    pub max_concurrent_vehicles: usize,
+    /// sinks the measurements are written to next to nats
+    #[serde(default)]
+    pub outputs: OutputsConfig,
+    /// rules posting a message to a webhook when the vehicle matches their conditions
+    #[serde(default)]
+    pub webhooks: Vec<WebhookRuleConfig>,
+    /// used by the `controller` subcommand
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub solar_charging: Option<SolarChargingConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tariff_charging: Option<TariffChargingConfig>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field(
+                "refresh_token",
+                &self.refresh_token.as_ref().map(|_| REDACTED),
+            )
+            .field("vehicle_ids", &self.vehicle_ids)
+            .field("accounts", &self.accounts)
+            .field("geofences", &self.geofences)
+            .field("battery_capacity_kwh", &self.battery_capacity_kwh)
+            .field("max_concurrent_vehicles", &self.max_concurrent_vehicles)
+            .field("outputs", &self.outputs)
+            .field("webhooks", &self.webhooks)
+            .field("solar_charging", &self.solar_charging)
+            .field("tariff_charging", &self.tariff_charging)
+            .finish()
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputsConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub influx: Option<InfluxOutputConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub csv: Option<CsvOutputConfig>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct InfluxOutputConfig {
+    /// write api url like `http://influxdb:8086/api/v2/write?org=home&bucket=tesla`, or `udp://influxdb:8089`
+    pub url: String,
+    /// api token of the http write api
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<SecretValue>,
+    #[serde(default = "default_influx_measurement")]
+    pub measurement: String,
+}
+
+fn default_influx_measurement() -> String {
+    "jarvis_tesla".to_string()
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CsvOutputConfig {
+    /// directory with a file per day, like `jarvis-tesla-2023-06-01.csv`
+    pub directory: String,
+    #[serde(default = "default_csv_file_prefix")]
+    pub file_prefix: String,
+    /// files older than this many days are removed; kept forever when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_days: Option<u32>,
+}
+
+fn default_csv_file_prefix() -> String {
+    "jarvis-tesla".to_string()
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookRuleConfig {
+    /// identifies the rule in logs and in the persisted cooldowns
+    pub name: String,
+    pub url: String,
+    /// vehicle event type like `chargingStopped` that has to occur in the same poll
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event: Option<String>,
+    /// all have to match
+    #[serde(default)]
+    pub conditions: Vec<WebhookConditionConfig>,
+    /// minutes to wait before the rule fires again for the same vehicle
+    #[serde(default = "default_webhook_cooldown_minutes")]
+    pub cooldown_minutes: i64,
+    /// with `{field}` placeholders for vehicle status fields, like `{displayName} is at {soc}%`
+    pub message: String,
+    /// `Authorization` header, like `Bearer <token>`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authorization: Option<SecretValue>,
+    /// json posted to the url, with placeholders in its strings; `{"message": ...}` with vehicle details when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<serde_json::Value>,
+}
+
+fn default_webhook_cooldown_minutes() -> i64 {
+    60
+}
+
+/// Compares a vehicle status field, or the previous status with `previous.<field>`, to a value or another field.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookConditionConfig {
+    pub field: String,
+    pub operator: ComparisonOperator,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub other_field: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ComparisonOperator {
+    Equals,
+    NotEquals,
+    LessThan,
+    LessThanOrEquals,
+    GreaterThan,
+    GreaterThanOrEquals,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct GeofenceConfig {
     pub location: String,
     pub latitude: f64,
     pub longitude: f64,
+    #[serde(default = "default_geofence_radius_meters")]
     pub geofence_radius_meters: f64,
 }
 
+fn default_geofence_radius_meters() -> f64 {
+    100.0
+}
+
+impl GeofenceConfig {
+    pub fn contains(&self, latitude: f64, longitude: f64) -> bool {
+        let location = Location::new(latitude, longitude);
+        let geofence_location = Location::new(self.latitude, self.longitude);
+
+        location
+            .is_in_circle(
+                &geofence_location,
+                Distance::from_meters(self.geofence_radius_meters),
+            )
+            .unwrap_or(false)
+    }
+}
+
+/// Charges a vehicle with the solar power that would otherwise be exported to the grid.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SolarChargingConfig {
+    pub vin: String,
+    /// geofence the vehicle charges at with solar power
+    #[serde(default = "default_charging_geofence")]
+    pub geofence: String,
+    /// gauge sample of another exporter with the grid power in watt, positive when importing
+    pub grid_sample_name: String,
+    #[serde(default = "default_solar_charging_min_amps")]
+    pub min_amps: u32,
+    #[serde(default = "default_solar_charging_max_amps")]
+    pub max_amps: u32,
+    /// used to convert amps to watts until the vehicle reports them while charging
+    #[serde(default = "default_solar_charging_voltage")]
+    pub voltage: f64,
+    #[serde(default = "default_solar_charging_phases")]
+    pub phases: u32,
+    /// state of charge to reach by `minimumSocDeadline`, using grid power if the sun doesn't suffice
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub minimum_soc: Option<f64>,
+    /// local time of day like `07:30`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub minimum_soc_deadline: Option<String>,
+    /// minimum time between adjustments, so a passing cloud doesn't toggle charging
+    #[serde(default = "default_solar_charging_interval_seconds")]
+    pub interval_seconds: u64,
+}
+
+/// Charges a vehicle in the cheapest hours before its departure.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TariffChargingConfig {
+    pub vin: String,
+    /// geofence the vehicle charges at on the hourly priced contract
+    #[serde(default = "default_charging_geofence")]
+    pub geofence: String,
+    /// url or file with day-ahead prices, as json or csv
+    pub prices: String,
+    /// local time of day like `07:30`
+    pub departure_time: String,
+    /// used to plan until the vehicle reports its charger power
+    #[serde(default = "default_tariff_charging_charge_power_watts")]
+    pub charge_power_watts: f64,
+}
+
+fn default_tariff_charging_charge_power_watts() -> f64 {
+    11000.0
+}
+
+fn default_charging_geofence() -> String {
+    "Home".to_string()
+}
+
+fn default_solar_charging_min_amps() -> u32 {
+    5
+}
+
+fn default_solar_charging_max_amps() -> u32 {
+    16
+}
+
+fn default_solar_charging_voltage() -> f64 {
+    230.0
+}
+
+fn default_solar_charging_phases() -> u32 {
+    3
+}
+
+fn default_solar_charging_interval_seconds() -> u64 {
+    60
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountConfig {
+    /// identifies the account in logs and in the persisted state
+    pub name: String,
+    /// inline refresh token, only for local configs since helm renders the config into a configmap
+    #[serde(default)]
+    pub refresh_token: String,
+    /// file holding the refresh token instead, like a mounted secret written by `login`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token_file: Option<String>,
+    /// environment variable holding the refresh token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token_env: Option<String>,
+    /// kubernetes secret holding the refresh token, read through the api
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token_secret_ref: Option<SecretRef>,
+    #[serde(default)]
+    pub backend: TeslaBackend,
+    #[serde(default)]
+    pub region: TeslaRegion,
+    /// client id of the registered fleet api application; the owner api uses `ownerapi`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// replaces the api url of the backend and region, like for a proxy or a mock server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_base_url: Option<String>,
+    /// private key enrolled on the vehicles to sign commands with, generated when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command_key_file: Option<String>,
+    #[serde(default)]
+    pub vehicle_ids: Vec<String>,
+}
+
+impl fmt::Debug for AccountConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccountConfig")
+            .field("name", &self.name)
+            .field("refresh_token", &REDACTED)
+            .field("refresh_token_file", &self.refresh_token_file)
+            .field("refresh_token_env", &self.refresh_token_env)
+            .field("refresh_token_secret_ref", &self.refresh_token_secret_ref)
+            .field("backend", &self.backend)
+            .field("region", &self.region)
+            .field("client_id", &self.client_id)
+            .field("api_base_url", &self.api_base_url)
+            .field("command_key_file", &self.command_key_file)
+            .field("vehicle_ids", &self.vehicle_ids)
+            .finish()
+    }
+}
+
+#[derive(
+    Serialize, Deserialize, JsonSchema, Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum TeslaBackend {
+    #[default]
+    OwnerApi,
+    FleetApi,
+}
+
+#[derive(
+    Serialize, Deserialize, JsonSchema, Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum TeslaRegion {
+    #[default]
+    NorthAmerica,
+    Europe,
+    China,
+}
+
+const OWNER_API_CLIENT_ID: &str = "ownerapi";
+const FINGERPRINT_LENGTH: usize = 8;
+
+impl AccountConfig {
+    pub fn auth_url(&self) -> &'static str {
+        match (self.backend, self.region) {
+            (_, TeslaRegion::China) => "https://auth.tesla.cn/oauth2/v3/token",
+            (TeslaBackend::OwnerApi, _) => "https://auth.tesla.com/oauth2/v3/token",
+            (TeslaBackend::FleetApi, _) => {
+                "https://fleet-auth.prd.vn.cloud.tesla.com/oauth2/v3/token"
+            }
+        }
+    }
+
+    pub fn authorize_url(&self) -> &'static str {
+        match self.region {
+            TeslaRegion::China => "https://auth.tesla.cn/oauth2/v3/authorize",
+            _ => "https://auth.tesla.com/oauth2/v3/authorize",
+        }
+    }
+
+    pub fn api_url(&self) -> &str {
+        if let Some(api_base_url) = &self.api_base_url {
+            return api_base_url.trim_end_matches('/');
+        }
+
+        match (self.backend, self.region) {
+            (TeslaBackend::OwnerApi, TeslaRegion::China) => "https://owner-api.vn.cloud.tesla.cn",
+            (TeslaBackend::OwnerApi, _) => "https://owner-api.teslamotors.com",
+            (TeslaBackend::FleetApi, TeslaRegion::NorthAmerica) => {
+                "https://fleet-api.prd.na.vn.cloud.tesla.com"
+            }
+            (TeslaBackend::FleetApi, TeslaRegion::Europe) => {
+                "https://fleet-api.prd.eu.vn.cloud.tesla.com"
+            }
+            (TeslaBackend::FleetApi, TeslaRegion::China) => {
+                "https://fleet-api.prd.cn.vn.cloud.tesla.cn"
+            }
+        }
+    }
+
+    pub fn streaming_url(&self) -> &'static str {
+        match self.region {
+            TeslaRegion::China => "wss://streaming.vn.cloud.tesla.cn/streaming/",
+            _ => "wss://streaming.vn.teslamotors.com/streaming/",
+        }
+    }
+
+    /// Where the refresh token comes from, the inline one only if no other source is set.
+    pub fn refresh_token_source(&self) -> SecretValue {
+        SecretValue {
+            value: Some(self.refresh_token.clone()).filter(|t| !t.is_empty()),
+            file: self.refresh_token_file.clone(),
+            env: self.refresh_token_env.clone(),
+            secret_ref: self.refresh_token_secret_ref.clone(),
+        }
+    }
+
+    /// Copy of the account with `refresh_token` read from its file, environment variable or
+    /// kubernetes secret if set.
+    pub async fn with_resolved_refresh_token(&self) -> Result<Self, Box<dyn Error>> {
+        let mut account = self.clone();
+
+        account.refresh_token = self
+            .refresh_token_source()
+            .resolve()
+            .await?
+            .unwrap_or_default();
+
+        if account.refresh_token.is_empty() {
+            return Err(Box::<dyn Error>::from(format!(
+                "Account {} has no refresh token",
+                self.name
+            )));
+        }
+
+        Ok(account)
+    }
+
+    pub fn client_id(&self) -> &str {
+        self.client_id.as_deref().unwrap_or(OWNER_API_CLIENT_ID)
+    }
+
+    /// Tail of the configured refresh token, to notice it was replaced without persisting it.
+    pub fn refresh_token_fingerprint(&self) -> String {
+        let skip = self
+            .refresh_token
+            .chars()
+            .count()
+            .saturating_sub(FINGERPRINT_LENGTH);
+        self.refresh_token.chars().skip(skip).collect()
+    }
+}
+
+const DEFAULT_ACCOUNT_NAME: &str = "default";
+const DEFAULT_BATTERY_CAPACITY_KWH: f64 = 75.0;
+const DEFAULT_MAX_CONCURRENT_VEHICLES: usize = 4;
+
 impl SetDefaults for Config {
-    fn set_defaults(&mut self) {}
+    fn set_defaults(&mut self) {
+        if let Some(refresh_token) = self.refresh_token.take() {
+            self.accounts.insert(
+                0,
+                AccountConfig {
+                    name: DEFAULT_ACCOUNT_NAME.to_string(),
+                    refresh_token,
+                    refresh_token_file: None,
+                    refresh_token_env: None,
+                    refresh_token_secret_ref: None,
+                    backend: TeslaBackend::default(),
+                    region: TeslaRegion::default(),
+                    client_id: None,
+                    api_base_url: None,
+                    command_key_file: None,
+                    vehicle_ids: std::mem::take(&mut self.vehicle_ids),
+                },
+            );
+        }
+        if self.battery_capacity_kwh <= 0.0 {
+            self.battery_capacity_kwh = DEFAULT_BATTERY_CAPACITY_KWH;
+        }
+        if self.max_concurrent_vehicles == 0 {
+            self.max_concurrent_vehicles = DEFAULT_MAX_CONCURRENT_VEHICLES;
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
-
 pub struct TeslaAccessTokenRequest {
     pub grant_type: String,
     pub scope: String,
@@ -33,12 +467,72 @@ pub struct TeslaAccessTokenRequest {
     pub refresh_token: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl fmt::Debug for TeslaAccessTokenRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TeslaAccessTokenRequest")
+            .field("grant_type", &self.grant_type)
+            .field("scope", &self.scope)
+            .field("client_id", &self.client_id)
+            .field("refresh_token", &REDACTED)
+            .finish()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct TeslaAuthorizationCodeRequest {
+    pub grant_type: String,
+    pub client_id: String,
+    pub code: String,
+    pub code_verifier: String,
+    pub redirect_uri: String,
+    /// only the fleet api needs the secret and audience of the registered application
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+}
+
+impl fmt::Debug for TeslaAuthorizationCodeRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TeslaAuthorizationCodeRequest")
+            .field("grant_type", &self.grant_type)
+            .field("client_id", &self.client_id)
+            .field("code", &REDACTED)
+            .field("code_verifier", &REDACTED)
+            .field("redirect_uri", &self.redirect_uri)
+            .field(
+                "client_secret",
+                &self.client_secret.as_ref().map(|_| REDACTED),
+            )
+            .field("audience", &self.audience)
+            .finish()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct TeslaAccessToken {
     pub access_token: String,
     pub token_type: String,
     pub expires_in: usize,
+    /// tesla can hand out a new refresh token, the previous one stops working eventually
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+}
+
+impl fmt::Debug for TeslaAccessToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TeslaAccessToken")
+            .field("access_token", &REDACTED)
+            .field("token_type", &self.token_type)
+            .field("expires_in", &self.expires_in)
+            .field(
+                "refresh_token",
+                &self.refresh_token.as_ref().map(|_| REDACTED),
+            )
+            .finish()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -47,7 +541,16 @@ pub struct TeslaApiResponse<T> {
     pub response: T,
 }
 
-#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub struct TeslaCommandResult {
+    pub result: bool,
+    /// why the vehicle didn't execute the command, like `is_charging`
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 #[serde(rename_all = "snake_case", from = "String")]
 pub enum TeslaVehicleState {
     Offline,
@@ -71,7 +574,7 @@ impl From<String> for TeslaVehicleState {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "snake_case")]
 pub struct TeslaVehicle {
     pub id: usize,
@@ -84,24 +587,23 @@ pub struct TeslaVehicle {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TeslaVehicleStreamingData {
-    pub latitude: f64,
-    pub longitude: f64,
+    /// missing while the vehicle goes to sleep
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
     pub power: f64,
+    /// speed in meters per second
     pub speed: f64,
+    /// odometer in meters
     pub odometer: f64,
+    pub soc: f64,
 }
 
 impl TeslaVehicleStreamingData {
     pub fn inside_geofence(&self, geofence: &GeofenceConfig) -> bool {
-        let tesla_location = Location::new(self.latitude, self.longitude);
-        let geofence_location = Location::new(geofence.latitude, geofence.longitude);
-
-        tesla_location
-            .is_in_circle(
-                &geofence_location,
-                Distance::from_meters(geofence.geofence_radius_meters),
-            )
-            .unwrap_or(false)
+        match (self.latitude, self.longitude) {
+            (Some(latitude), Some(longitude)) => geofence.contains(latitude, longitude),
+            _ => false,
+        }
     }
 
     pub fn in_geofence(&self, geofences: &[GeofenceConfig]) -> Option<GeofenceConfig> {
@@ -124,14 +626,261 @@ pub struct TeslaVehicleData {
     pub in_service: bool,
 
     pub charge_state: Option<TeslaVehicleChargeState>,
+    pub climate_state: Option<TeslaVehicleClimateState>,
+    pub drive_state: Option<TeslaVehicleDriveState>,
+    pub gui_settings: Option<TeslaVehicleGuiSettings>,
+    pub vehicle_state: Option<TeslaVehicleVehicleState>,
+}
+
+impl TeslaVehicleData {
+    /// Converts distances to meters, speeds to meters per second and pressures to pascal, so no
+    /// api units leak into samples; temperatures are already reported in celsius.
+    pub fn convert_to_si_units(&mut self) {
+        let units = Units::from_gui_settings(self.gui_settings.as_ref());
+
+        if let Some(charge_state) = &mut self.charge_state {
+            charge_state.battery_range = units.distance.to_meters(charge_state.battery_range);
+            charge_state.charge_rate = units
+                .charge_rate
+                .to_meters_per_second(charge_state.charge_rate);
+        }
+
+        if let Some(drive_state) = &mut self.drive_state {
+            drive_state.speed = drive_state
+                .speed
+                .map(|s| units.distance.to_meters_per_second(s));
+        }
+
+        if let Some(vehicle_state) = &mut self.vehicle_state {
+            vehicle_state.odometer = vehicle_state.odometer.map(|o| units.distance.to_meters(o));
+            for tpms_pressure in [
+                &mut vehicle_state.tpms_pressure_fl,
+                &mut vehicle_state.tpms_pressure_fr,
+                &mut vehicle_state.tpms_pressure_rl,
+                &mut vehicle_state.tpms_pressure_rr,
+            ] {
+                *tpms_pressure = tpms_pressure.map(units::bar_to_pascal);
+            }
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "snake_case")]
 pub struct TeslaVehicleChargeState {
+    pub battery_heater_on: bool,
+    pub battery_level: f64,
+    pub battery_range: f64,
     pub charge_energy_added: f64,
+    pub charge_limit_soc: f64,
+    pub charge_rate: f64,
     pub charger_power: f64,
     pub charge_port_latch: String,
+    pub charging_state: String,
+    /// amps the vehicle is set to charge with
+    #[serde(default)]
+    pub charge_current_request: Option<f64>,
+    #[serde(default)]
+    pub charger_actual_current: Option<f64>,
+    #[serde(default)]
+    pub charger_phases: Option<f64>,
+    #[serde(default)]
+    pub charger_voltage: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct TeslaVehicleClimateState {
+    pub inside_temp: Option<f64>,
+    pub outside_temp: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct TeslaVehicleDriveState {
+    /// missing when the fleet api isn't asked for `location_data` or the location is shared off
+    #[serde(default)]
+    pub latitude: Option<f64>,
+    #[serde(default)]
+    pub longitude: Option<f64>,
+    pub power: Option<f64>,
+    pub shift_state: Option<String>,
+    pub speed: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct TeslaVehicleGuiSettings {
+    pub gui_charge_rate_units: Option<String>,
+    pub gui_distance_units: Option<String>,
+    pub gui_temperature_units: Option<String>,
+    pub gui_tirepressure_units: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct TeslaVehicleVehicleState {
+    pub car_version: Option<String>,
+    #[serde(default)]
+    pub locked: Option<bool>,
+    /// missing for vehicles that only report part of their state
+    #[serde(default)]
+    pub odometer: Option<f64>,
+    pub sentry_mode: Option<bool>,
+    #[serde(default)]
+    pub software_update: Option<TeslaVehicleSoftwareUpdate>,
+    pub tpms_pressure_fl: Option<f64>,
+    pub tpms_pressure_fr: Option<f64>,
+    pub tpms_pressure_rl: Option<f64>,
+    pub tpms_pressure_rr: Option<f64>,
+}
+
+impl TeslaVehicleVehicleState {
+    pub fn min_tire_pressure(&self) -> Option<f64> {
+        [
+            self.tpms_pressure_fl,
+            self.tpms_pressure_fr,
+            self.tpms_pressure_rl,
+            self.tpms_pressure_rr,
+        ]
+        .iter()
+        .flatten()
+        .copied()
+        .reduce(f64::min)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct TeslaVehicleSoftwareUpdate {
+    /// empty without a pending update, otherwise `available`, `scheduled`, `downloading` or `installing`
+    pub status: String,
+    pub version: String,
+}
+
+impl TeslaVehicleSoftwareUpdate {
+    pub fn pending_version(&self) -> Option<String> {
+        if self.status.is_empty() || self.version.trim().is_empty() {
+            None
+        } else {
+            Some(self.version.trim().to_string())
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct VehicleState {
+    /// to find the last measurement of a vehicle that can't be polled
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub battery_history: BatteryHistory,
+    #[serde(default)]
+    pub driving_efficiency: DrivingEfficiency,
+    /// status as of the last successful poll, to only publish changes
+    #[serde(default)]
+    pub status: Option<VehicleStatus>,
+}
+
+/// Latest known values of a vehicle, for the outputs that work per vehicle rather than with samples.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct VehicleStatus {
+    pub vin: String,
+    pub display_name: String,
+    /// geofence the vehicle is in, or `Other`
+    pub location: String,
+    #[serde(default)]
+    pub latitude: Option<f64>,
+    #[serde(default)]
+    pub longitude: Option<f64>,
+    /// watt
+    pub charger_power: f64,
+    /// joule
+    pub charge_energy_added: f64,
+    /// meters
+    pub odometer: f64,
+    /// 1 awake, 0 asleep, -1 offline, -2 in service
+    pub availability: f64,
+    /// percent
+    #[serde(default)]
+    pub soc: Option<f64>,
+    #[serde(default)]
+    pub locked: Option<bool>,
+    /// percent the vehicle charges up to
+    #[serde(default)]
+    pub charge_limit_soc: Option<f64>,
+    /// lowest pressure of the four tires in pascal
+    #[serde(default)]
+    pub min_tire_pressure: Option<f64>,
+    /// whether the charge cable is latched into the charge port
+    #[serde(default)]
+    pub plugged_in: Option<bool>,
+    /// version of a software update waiting to be installed
+    #[serde(default)]
+    pub software_update_version: Option<String>,
+    /// meters
+    #[serde(default)]
+    pub estimated_full_range: Option<f64>,
+    /// percent
+    #[serde(default)]
+    pub range_degradation: Option<f64>,
+    /// Wh/km
+    #[serde(default)]
+    pub driving_consumption: Option<f64>,
+    /// Wh/km
+    #[serde(default)]
+    pub average_driving_consumption: Option<f64>,
+    /// joule
+    #[serde(default)]
+    pub driving_energy: f64,
+}
+
+/// Tokens of an account, stored in a secret of their own instead of the exporter's configmap.
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountState {
+    /// `AccountConfig::refresh_token_fingerprint` the tokens below were obtained with
+    #[serde(default)]
+    pub configured_refresh_token_fingerprint: Option<String>,
+    /// latest refresh token handed out by tesla
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    #[serde(default)]
+    pub access_token: Option<String>,
+    #[serde(default)]
+    pub access_token_expires_at: Option<DateTime<Utc>>,
+}
+
+impl fmt::Debug for AccountState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let redacted = |token: &Option<String>| token.as_ref().map(|_| REDACTED);
+
+        f.debug_struct("AccountState")
+            .field(
+                "configured_refresh_token_fingerprint",
+                &redacted(&self.configured_refresh_token_fingerprint),
+            )
+            .field("refresh_token", &redacted(&self.refresh_token))
+            .field("access_token", &redacted(&self.access_token))
+            .field("access_token_expires_at", &self.access_token_expires_at)
+            .finish()
+    }
+}
+
+/// By account name.
+pub type AccountStates = HashMap<String, AccountState>;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExporterState {
+    /// by vehicle id
+    #[serde(default)]
+    pub vehicles: HashMap<String, VehicleState>,
+    /// last time a webhook rule fired, by rule name and vin
+    #[serde(default)]
+    pub webhooks_sent_at: HashMap<String, DateTime<Utc>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -149,6 +898,7 @@ mod tests {
     use std::fs;
 
     use super::*;
+    use crate::snapshot::assert_json_snapshot;
     use jarvis_lib::config_client::{ConfigClient, ConfigClientConfig};
 
     #[test]
@@ -157,16 +907,137 @@ mod tests {
             ConfigClientConfig::new("test-data/test-config.yaml".to_string()).unwrap(),
         );
 
-        let config: Config = config_client.read_config_from_file().unwrap();
+        let mut config: Config = config_client.read_config_from_file().unwrap();
+        config.set_defaults();
 
-        assert_eq!(config.refresh_token, "abcd".to_string());
+        assert_eq!(config.accounts.len(), 1);
+        assert_eq!(config.accounts[0].name, "default".to_string());
+        assert_eq!(config.accounts[0].refresh_token, "abcd".to_string());
+        assert_eq!(config.accounts[0].backend, TeslaBackend::OwnerApi);
         assert_eq!(config.geofences.len(), 1);
         assert_eq!(config.geofences[0].location, "My Home".to_string());
         assert_eq!(config.geofences[0].latitude, 52.377956);
         assert_eq!(config.geofences[0].longitude, 4.897070);
         assert_eq!(config.geofences[0].geofence_radius_meters, 100.0);
-        assert_eq!(config.vehicle_ids.len(), 1);
-        assert_eq!(config.vehicle_ids[0], "23498074342");
+        assert_eq!(config.accounts[0].vehicle_ids.len(), 1);
+        assert_eq!(config.accounts[0].vehicle_ids[0], "23498074342");
+        assert_eq!(config.battery_capacity_kwh, 82.0);
+    }
+
+    #[test]
+    fn read_config_from_file_returns_accounts() {
+        let config_client = ConfigClient::new(
+            ConfigClientConfig::new("test-data/test-config-accounts.yaml".to_string()).unwrap(),
+        );
+
+        let mut config: Config = config_client.read_config_from_file().unwrap();
+        config.set_defaults();
+
+        assert_eq!(config.accounts.len(), 2);
+        assert_eq!(config.accounts[0].name, "personal".to_string());
+        assert_eq!(
+            config.accounts[0].api_url(),
+            "https://owner-api.teslamotors.com"
+        );
+        assert_eq!(config.accounts[0].client_id(), "ownerapi");
+        assert_eq!(config.accounts[1].name, "business".to_string());
+        assert_eq!(config.accounts[1].backend, TeslaBackend::FleetApi);
+        assert_eq!(
+            config.accounts[1].api_url(),
+            "https://fleet-api.prd.eu.vn.cloud.tesla.com"
+        );
+        assert_eq!(config.accounts[1].client_id(), "my-client-id");
+        assert_eq!(config.accounts[1].vehicle_ids.len(), 2);
+        assert_eq!(config.geofences.len(), 1);
+    }
+
+    #[test]
+    fn refresh_token_fingerprint_returns_tail_of_token() {
+        let account = AccountConfig {
+            name: "personal".to_string(),
+            refresh_token: "abcdefghijkl".to_string(),
+            refresh_token_file: None,
+            refresh_token_env: None,
+            refresh_token_secret_ref: None,
+            backend: TeslaBackend::OwnerApi,
+            region: TeslaRegion::Europe,
+            client_id: None,
+            api_base_url: None,
+            command_key_file: None,
+            vehicle_ids: vec![],
+        };
+
+        // act
+        let fingerprint = account.refresh_token_fingerprint();
+
+        assert_eq!(fingerprint, "efghijkl".to_string());
+    }
+
+    #[tokio::test]
+    async fn with_resolved_refresh_token_reads_refresh_token_file() {
+        let account = AccountConfig {
+            name: "personal".to_string(),
+            refresh_token: "".to_string(),
+            refresh_token_file: Some("test-data/refresh-token".to_string()),
+            refresh_token_env: None,
+            refresh_token_secret_ref: None,
+            backend: TeslaBackend::OwnerApi,
+            region: TeslaRegion::Europe,
+            client_id: None,
+            api_base_url: None,
+            command_key_file: None,
+            vehicle_ids: vec![],
+        };
+
+        // act
+        let account = account.with_resolved_refresh_token().await.unwrap();
+
+        assert_eq!(account.refresh_token, "efgh".to_string());
+    }
+
+    #[test]
+    fn debug_redacts_account_state_tokens() {
+        let account_state = AccountState {
+            configured_refresh_token_fingerprint: Some("efghijkl".into()),
+            refresh_token: Some("eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9".into()),
+            access_token: Some("qts-0123456789abcdef".into()),
+            access_token_expires_at: None,
+        };
+
+        // act
+        let debug = format!("{:?}", account_state);
+
+        assert!(!debug.contains("efghijkl"));
+        assert!(!debug.contains("eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9"));
+        assert!(!debug.contains("qts-0123456789abcdef"));
+    }
+
+    #[test]
+    fn debug_redacts_refresh_tokens() {
+        let mut config: Config = serde_yaml::from_str(
+            r#"
+refreshToken: eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9
+vehicleIds:
+- "23498074342"
+geofences: []
+"#,
+        )
+        .unwrap();
+        let access_token_request = TeslaAccessTokenRequest {
+            grant_type: "refresh_token".into(),
+            scope: "openid email offline_access".into(),
+            client_id: "ownerapi".into(),
+            refresh_token: "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9".into(),
+        };
+
+        // act
+        let debug_before_defaults = format!("{:?}", config);
+        config.set_defaults();
+        let debug = format!("{:?} {:?}", config, access_token_request);
+
+        assert!(!debug_before_defaults.contains("eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9"));
+        assert!(!debug.contains("eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9"));
+        assert!(debug.contains(REDACTED));
     }
 
     #[test]
@@ -196,5 +1067,152 @@ mod tests {
             vehicle_data_response.response.state,
             TeslaVehicleState::Online
         );
+        assert_json_snapshot("vehicle_data_response", &vehicle_data_response);
+    }
+
+    #[test]
+    fn deserialize_vehicles_response_with_asleep_offline_and_in_service_vehicles() {
+        let json_string =
+            fs::read_to_string("test-data/vehicles_response_asleep_offline.json").unwrap();
+
+        // act
+        let vehicles_response: TeslaApiResponse<Vec<TeslaVehicle>> =
+            serde_json::from_str(&json_string).unwrap();
+
+        assert_json_snapshot("vehicles_response_asleep_offline", &vehicles_response);
+    }
+
+    #[test]
+    fn deserialize_vehicle_data_response_without_charge_state() {
+        let json_string =
+            fs::read_to_string("test-data/vehicle_data_response_charge_state_null.json").unwrap();
+
+        // act
+        let vehicle_data_response: TeslaApiResponse<TeslaVehicleData> =
+            serde_json::from_str(&json_string).unwrap();
+
+        assert!(vehicle_data_response.response.charge_state.is_none());
+        assert_json_snapshot(
+            "vehicle_data_response_charge_state_null",
+            &vehicle_data_response,
+        );
+    }
+
+    #[test]
+    fn deserialize_vehicle_data_response_while_supercharging() {
+        let json_string =
+            fs::read_to_string("test-data/vehicle_data_response_supercharging.json").unwrap();
+
+        // act
+        let vehicle_data_response: TeslaApiResponse<TeslaVehicleData> =
+            serde_json::from_str(&json_string).unwrap();
+
+        assert_json_snapshot(
+            "vehicle_data_response_supercharging",
+            &vehicle_data_response,
+        );
+    }
+
+    #[test]
+    fn deserialize_fleet_api_vehicles_response() {
+        let json_string = fs::read_to_string("test-data/fleet_vehicles_response.json").unwrap();
+
+        // act
+        let vehicles_response: TeslaApiResponse<Vec<TeslaVehicle>> =
+            serde_json::from_str(&json_string).unwrap();
+
+        assert_json_snapshot("fleet_vehicles_response", &vehicles_response);
+    }
+
+    #[test]
+    fn deserialize_fleet_api_vehicle_data_response() {
+        let json_string = fs::read_to_string("test-data/fleet_vehicle_data_response.json").unwrap();
+
+        // act
+        let vehicle_data_response: TeslaApiResponse<TeslaVehicleData> =
+            serde_json::from_str(&json_string).unwrap();
+
+        assert_json_snapshot("fleet_vehicle_data_response", &vehicle_data_response);
+    }
+
+    #[test]
+    fn deserialize_fleet_api_token_response() {
+        let json_string = fs::read_to_string("test-data/fleet_token_response.json").unwrap();
+
+        // act
+        let access_token: TeslaAccessToken = serde_json::from_str(&json_string).unwrap();
+
+        assert_json_snapshot("fleet_token_response", &access_token);
+    }
+
+    #[test]
+    fn deserialize_vehicle_data_response_charge_and_climate_state() {
+        let json_string = fs::read_to_string("test-data/vehicle_data_response.json").unwrap();
+
+        // act
+        let vehicle_data_response: TeslaApiResponse<TeslaVehicleData> =
+            serde_json::from_str(&json_string).unwrap();
+
+        let charge_state = vehicle_data_response.response.charge_state.unwrap();
+        assert_eq!(charge_state.battery_level, 49.0);
+        assert_eq!(charge_state.battery_range, 148.39);
+        assert_eq!(charge_state.charge_limit_soc, 50.0);
+        assert_eq!(charge_state.charging_state, "Complete".to_string());
+
+        let climate_state = vehicle_data_response.response.climate_state.unwrap();
+        assert_eq!(climate_state.outside_temp, Some(16.5));
+    }
+
+    #[test]
+    fn deserialize_vehicle_data_without_location_data() {
+        let json_string = r#"{"id":1,"vehicle_id":2,"state":"online","in_service":false,"drive_state":{"power":0,"shift_state":null,"speed":null},"vehicle_state":{"car_version":"2024.2.7"}}"#;
+
+        // act
+        let vehicle_data: TeslaVehicleData = serde_json::from_str(json_string).unwrap();
+
+        let drive_state = vehicle_data.drive_state.unwrap();
+        assert_eq!(drive_state.latitude, None);
+        assert_eq!(drive_state.longitude, None);
+        let vehicle_state = vehicle_data.vehicle_state.unwrap();
+        assert_eq!(vehicle_state.locked, None);
+        assert_eq!(vehicle_state.odometer, None);
+    }
+
+    #[test]
+    fn convert_to_si_units_for_vehicle_configured_in_kilometers() {
+        let json_string = fs::read_to_string("test-data/vehicle_data_response.json").unwrap();
+        let mut vehicle_data_response: TeslaApiResponse<TeslaVehicleData> =
+            serde_json::from_str(&json_string).unwrap();
+
+        assert_eq!(
+            vehicle_data_response
+                .response
+                .gui_settings
+                .as_ref()
+                .unwrap()
+                .gui_distance_units,
+            Some("km/hr".to_string())
+        );
+
+        // act
+        vehicle_data_response.response.convert_to_si_units();
+
+        let vehicle_data = vehicle_data_response.response;
+
+        // the api reports miles, even when the car displays kilometers
+        let charge_state = vehicle_data.charge_state.unwrap();
+        assert!((charge_state.battery_range - 238810.556).abs() < 0.01);
+        assert_eq!(charge_state.charge_rate, 0.0);
+
+        let climate_state = vehicle_data.climate_state.unwrap();
+        assert_eq!(climate_state.inside_temp, Some(25.0));
+        assert_eq!(climate_state.outside_temp, Some(16.5));
+
+        let vehicle_state = vehicle_data.vehicle_state.unwrap();
+        assert!((vehicle_state.odometer.unwrap() - 28324569.346).abs() < 0.01);
+        assert_eq!(vehicle_state.tpms_pressure_fl, Some(307500.0));
+        assert_eq!(vehicle_state.tpms_pressure_rr, Some(300000.0));
+
+        assert_eq!(vehicle_data.drive_state.unwrap().speed, None);
     }
 }
@@ -0,0 +1,434 @@
+use crate::model::VehicleStatus;
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::env;
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+const LOCATION_OTHER: &str = "Other";
+// device tracker state for a vehicle outside all zones
+const NOT_HOME: &str = "not_home";
+
+pub struct MqttClientConfig {
+    host: String,
+    port: u16,
+    username: Option<String>,
+    password: Option<String>,
+    topic_prefix: String,
+    discovery_prefix: String,
+}
+
+impl MqttClientConfig {
+    pub fn new(
+        host: String,
+        port: u16,
+        username: Option<String>,
+        password: Option<String>,
+        topic_prefix: String,
+        discovery_prefix: String,
+    ) -> Result<Self, Box<dyn Error>> {
+        debug!(
+            "MqttClientConfig::new(host: {}, port: {}, topic_prefix: {}, discovery_prefix: {})",
+            host, port, topic_prefix, discovery_prefix
+        );
+
+        Ok(Self {
+            host,
+            port,
+            username,
+            password,
+            topic_prefix,
+            discovery_prefix,
+        })
+    }
+
+    /// None when `MQTT_HOST` isn't set, which leaves the mqtt output off.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn Error>> {
+        let host = match env::var("MQTT_HOST") {
+            Ok(host) if !host.is_empty() => host,
+            _ => return Ok(None),
+        };
+        let port: u16 = env::var("MQTT_PORT")
+            .unwrap_or_else(|_| "1883".to_string())
+            .parse()?;
+        let username = env::var("MQTT_USERNAME").ok();
+        let password = env::var("MQTT_PASSWORD").ok();
+        let topic_prefix =
+            env::var("MQTT_TOPIC_PREFIX").unwrap_or_else(|_| "jarvis-tesla".to_string());
+        let discovery_prefix =
+            env::var("MQTT_DISCOVERY_PREFIX").unwrap_or_else(|_| "homeassistant".to_string());
+
+        Self::new(
+            host,
+            port,
+            username,
+            password,
+            topic_prefix,
+            discovery_prefix,
+        )
+        .map(Some)
+    }
+}
+
+/// Publishes the status of each vehicle to mqtt, with home assistant discovery configs so the
+/// vehicles show up as devices without configuring them by hand.
+pub struct MqttClient {
+    config: MqttClientConfig,
+    client: AsyncClient,
+    /// publishes not acknowledged by the broker yet
+    pending: Arc<AtomicUsize>,
+    /// vins the discovery configs were published for by this process
+    announced: Mutex<HashSet<String>>,
+}
+
+impl MqttClient {
+    /// Has to be called from within the tokio runtime, since it spawns the connection.
+    pub fn new(config: MqttClientConfig) -> Self {
+        let mut options = MqttOptions::new(
+            format!("jarvis-tesla-exporter-{}", uuid::Uuid::new_v4().to_simple()),
+            config.host.clone(),
+            config.port,
+        );
+        options.set_keep_alive(Duration::from_secs(30));
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            options.set_credentials(username, password);
+        }
+
+        let (client, event_loop) = AsyncClient::new(options, 100);
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        tokio::spawn(Self::run_event_loop(event_loop, pending.clone()));
+
+        Self {
+            config,
+            client,
+            pending,
+            announced: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Publishes the status if it differs from `previous`; announces the vehicle first if needed.
+    pub async fn publish_vehicle(
+        &self,
+        status: &VehicleStatus,
+        previous: Option<&VehicleStatus>,
+    ) -> Result<(), Box<dyn Error>> {
+        let announce = self.announced.lock().unwrap().insert(status.vin.clone());
+        if announce {
+            for (topic, payload) in discovery_configs(
+                &self.config.discovery_prefix,
+                &self.config.topic_prefix,
+                status,
+            ) {
+                self.publish(&topic, payload.to_string(), true).await?;
+            }
+            info!("Published discovery configs for vehicle {}", status.vin);
+        } else if previous == Some(status) {
+            debug!("Status of vehicle {} didn't change", status.vin);
+            return Ok(());
+        }
+
+        let base_topic = vehicle_topic(&self.config.topic_prefix, &status.vin);
+
+        self.publish(
+            &format!("{}/state", base_topic),
+            serde_json::to_string(status)?,
+            true,
+        )
+        .await?;
+
+        self.publish(
+            &format!("{}/location", base_topic),
+            tracker_state(&status.location).to_string(),
+            true,
+        )
+        .await?;
+
+        if let (Some(latitude), Some(longitude)) = (status.latitude, status.longitude) {
+            self.publish(
+                &format!("{}/location/attributes", base_topic),
+                json!({ "latitude": latitude, "longitude": longitude, "gps_accuracy": 10 })
+                    .to_string(),
+                true,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Waits until the broker acknowledged all publishes, since a cronjob run exits right after.
+    pub async fn flush(&self, timeout: Duration) {
+        let started_at = Instant::now();
+
+        while self.pending.load(Ordering::SeqCst) > 0 {
+            if started_at.elapsed() > timeout {
+                warn!(
+                    "Gave up waiting for {} mqtt publishes to be acknowledged",
+                    self.pending.load(Ordering::SeqCst)
+                );
+                return;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    async fn publish(
+        &self,
+        topic: &str,
+        payload: String,
+        retain: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        debug!("Publishing to mqtt topic {}", topic);
+
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        if let Err(e) = self
+            .client
+            .publish(topic, QoS::AtLeastOnce, retain, payload)
+            .await
+        {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            return Err(Box::new(e));
+        }
+
+        Ok(())
+    }
+
+    async fn run_event_loop(mut event_loop: EventLoop, pending: Arc<AtomicUsize>) {
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::PubAck(_))) => {
+                    let _ = pending.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |p| {
+                        Some(p.saturating_sub(1))
+                    });
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Mqtt connection failed, reconnecting: {}", e);
+                    sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+}
+
+fn vehicle_topic(topic_prefix: &str, vin: &str) -> String {
+    format!("{}/{}", topic_prefix, vin.to_lowercase())
+}
+
+/// Home assistant matches the state of a device tracker to zones with the same name.
+fn tracker_state(location: &str) -> &str {
+    if location == LOCATION_OTHER {
+        NOT_HOME
+    } else {
+        location
+    }
+}
+
+/// An entity published from the vehicle state topic.
+struct Sensor {
+    component: &'static str,
+    key: &'static str,
+    name: &'static str,
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+    state_class: Option<&'static str>,
+    value_template: &'static str,
+}
+
+/// Discovery topics and payloads for all entities of a vehicle.
+fn discovery_configs(
+    discovery_prefix: &str,
+    topic_prefix: &str,
+    status: &VehicleStatus,
+) -> Vec<(String, Value)> {
+    let base_topic = vehicle_topic(topic_prefix, &status.vin);
+    let object_id = status.vin.to_lowercase();
+    let device = json!({
+        "identifiers": [status.vin],
+        "name": status.display_name,
+        "manufacturer": "Tesla",
+    });
+
+    let sensors = [
+        Sensor {
+            component: "sensor",
+            key: "battery_level",
+            name: "Battery level",
+            device_class: Some("battery"),
+            unit: Some("%"),
+            state_class: Some("measurement"),
+            value_template: "{{ value_json.soc }}",
+        },
+        Sensor {
+            component: "sensor",
+            key: "charger_power",
+            name: "Charging power",
+            device_class: Some("power"),
+            unit: Some("W"),
+            state_class: Some("measurement"),
+            value_template: "{{ value_json.chargerPower }}",
+        },
+        Sensor {
+            component: "sensor",
+            key: "charge_energy",
+            name: "Charged energy",
+            device_class: Some("energy"),
+            unit: Some("kWh"),
+            state_class: Some("total_increasing"),
+            value_template: "{{ (value_json.chargeEnergyAdded / 3600000) | round(3) }}",
+        },
+        Sensor {
+            component: "sensor",
+            key: "odometer",
+            name: "Odometer",
+            device_class: Some("distance"),
+            unit: Some("km"),
+            state_class: Some("total_increasing"),
+            value_template: "{{ (value_json.odometer / 1000) | round(1) }}",
+        },
+        Sensor {
+            component: "sensor",
+            key: "estimated_full_range",
+            name: "Estimated full range",
+            device_class: Some("distance"),
+            unit: Some("km"),
+            state_class: Some("measurement"),
+            value_template: "{{ (value_json.estimatedFullRange / 1000) | round(1) if value_json.estimatedFullRange is not none else none }}",
+        },
+        Sensor {
+            component: "sensor",
+            key: "driving_consumption",
+            name: "Driving consumption",
+            device_class: None,
+            unit: Some("Wh/km"),
+            state_class: Some("measurement"),
+            value_template: "{{ value_json.averageDrivingConsumption }}",
+        },
+        Sensor {
+            component: "sensor",
+            key: "availability",
+            name: "Availability",
+            device_class: None,
+            unit: None,
+            state_class: None,
+            value_template: "{{ {1: 'awake', 0: 'asleep', -1: 'offline', -2: 'in service'}.get(value_json.availability | int, 'unknown') }}",
+        },
+        Sensor {
+            component: "binary_sensor",
+            key: "lock",
+            name: "Lock",
+            device_class: Some("lock"),
+            unit: None,
+            state_class: None,
+            // the lock device class is on when unlocked
+            value_template: "{{ 'OFF' if value_json.locked else 'ON' }}",
+        },
+        Sensor {
+            component: "binary_sensor",
+            key: "charging",
+            name: "Charging",
+            device_class: Some("battery_charging"),
+            unit: None,
+            state_class: None,
+            value_template: "{{ 'ON' if value_json.chargerPower > 0 else 'OFF' }}",
+        },
+    ];
+
+    let mut configs: Vec<(String, Value)> = sensors
+        .iter()
+        .map(|sensor| {
+            let mut payload = json!({
+                "name": sensor.name,
+                "unique_id": format!("{}_{}", object_id, sensor.key),
+                "object_id": format!("{}_{}", object_id, sensor.key),
+                "state_topic": format!("{}/state", base_topic),
+                "value_template": sensor.value_template,
+                "device": device,
+            });
+            if let Some(device_class) = sensor.device_class {
+                payload["device_class"] = json!(device_class);
+            }
+            if let Some(unit) = sensor.unit {
+                payload["unit_of_measurement"] = json!(unit);
+            }
+            if let Some(state_class) = sensor.state_class {
+                payload["state_class"] = json!(state_class);
+            }
+
+            (
+                format!(
+                    "{}/{}/{}_{}/config",
+                    discovery_prefix, sensor.component, object_id, sensor.key
+                ),
+                payload,
+            )
+        })
+        .collect();
+
+    configs.push((
+        format!(
+            "{}/device_tracker/{}_location/config",
+            discovery_prefix, object_id
+        ),
+        json!({
+            "name": "Location",
+            "unique_id": format!("{}_location", object_id),
+            "object_id": format!("{}_location", object_id),
+            "state_topic": format!("{}/location", base_topic),
+            "json_attributes_topic": format!("{}/location/attributes", base_topic),
+            "source_type": "gps",
+            "device": device,
+        }),
+    ));
+
+    configs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discovery_configs_describe_vehicle_entities() {
+        let status = VehicleStatus {
+            vin: "5YJ3E7EB2KF000000".into(),
+            display_name: "Tessie".into(),
+            location: "My Home".into(),
+            ..Default::default()
+        };
+
+        // act
+        let configs = discovery_configs("homeassistant", "jarvis-tesla", &status);
+
+        assert_eq!(configs.len(), 10);
+        let (topic, payload) = &configs[0];
+        assert_eq!(
+            topic,
+            "homeassistant/sensor/5yj3e7eb2kf000000_battery_level/config"
+        );
+        assert_eq!(
+            payload["state_topic"],
+            json!("jarvis-tesla/5yj3e7eb2kf000000/state")
+        );
+        assert_eq!(payload["device"]["name"], json!("Tessie"));
+        assert_eq!(payload["unit_of_measurement"], json!("%"));
+        let (topic, payload) = &configs[9];
+        assert_eq!(
+            topic,
+            "homeassistant/device_tracker/5yj3e7eb2kf000000_location/config"
+        );
+        assert_eq!(payload["source_type"], json!("gps"));
+    }
+
+    #[test]
+    fn tracker_state_maps_other_to_not_home() {
+        assert_eq!(tracker_state("My Home"), "My Home");
+        assert_eq!(tracker_state("Other"), "not_home");
+    }
+}
@@ -0,0 +1,73 @@
+use crate::csv_sink::CsvSink;
+use crate::influx_sink::InfluxSink;
+use crate::model::OutputsConfig;
+use async_trait::async_trait;
+use jarvis_lib::model::Measurement;
+use std::error::Error;
+
+/// Destination for the measurements of a run, next to the nats subject the exporter service publishes to.
+#[async_trait(?Send)]
+pub trait OutputSink {
+    fn name(&self) -> &str;
+
+    async fn write(&self, measurements: &[Measurement]) -> Result<(), Box<dyn Error>>;
+}
+
+/// Sinks turned on in the config.
+pub async fn sinks_from_config(
+    config: &OutputsConfig,
+    http_client: &reqwest::Client,
+) -> Result<Vec<Box<dyn OutputSink>>, Box<dyn Error>> {
+    let mut sinks: Vec<Box<dyn OutputSink>> = vec![];
+
+    if let Some(influx_config) = &config.influx {
+        sinks.push(Box::new(
+            InfluxSink::new(influx_config.clone(), http_client.clone()).await?,
+        ));
+    }
+    if let Some(csv_config) = &config.csv {
+        sinks.push(Box::new(CsvSink::new(csv_config.clone())));
+    }
+
+    Ok(sinks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{CsvOutputConfig, InfluxOutputConfig};
+
+    #[tokio::test]
+    async fn sinks_from_config_returns_configured_sinks() {
+        let config = OutputsConfig {
+            influx: Some(InfluxOutputConfig {
+                url: "udp://localhost:8089".into(),
+                token: None,
+                measurement: "jarvis_tesla".into(),
+            }),
+            csv: Some(CsvOutputConfig {
+                directory: "/tmp".into(),
+                file_prefix: "jarvis-tesla".into(),
+                retention_days: None,
+            }),
+        };
+
+        // act
+        let sinks = sinks_from_config(&config, &reqwest::Client::new())
+            .await
+            .unwrap();
+
+        let names: Vec<&str> = sinks.iter().map(|s| s.name()).collect();
+        assert_eq!(names, vec!["influx", "csv"]);
+    }
+
+    #[tokio::test]
+    async fn sinks_from_config_returns_no_sinks_by_default() {
+        // act
+        let sinks = sinks_from_config(&OutputsConfig::default(), &reqwest::Client::new())
+            .await
+            .unwrap();
+
+        assert!(sinks.is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+const MIN_REQUEST_DELAY: Duration = Duration::from_secs(1);
+const MAX_REQUEST_DELAY: Duration = Duration::from_secs(30);
+/// Longest `Retry-After` to wait for, as waiting longer doesn't fit within a single run.
+pub const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimiterStats {
+    pub requests: usize,
+    pub retries: usize,
+    pub rate_limited: usize,
+}
+
+#[derive(Default)]
+struct RateLimiterState {
+    blocked_until: Option<Instant>,
+    request_delay: Duration,
+    stats: RateLimiterStats,
+}
+
+impl RateLimiterState {
+    fn blocked_for(&self) -> Duration {
+        self.blocked_until
+            .map(|blocked_until| blocked_until.saturating_duration_since(Instant::now()))
+            .unwrap_or_default()
+    }
+}
+
+/// Spaces out requests of an account for the rest of the run once the api started rate limiting,
+/// since the limit applies to the account rather than to a single vehicle.
+#[derive(Default)]
+pub struct RateLimiter {
+    state: Mutex<RateLimiterState>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time to wait before sending the next request, never waiting longer than `MAX_RETRY_AFTER`
+    /// for a block.
+    pub fn delay(&self) -> Duration {
+        let state = self.state.lock().unwrap();
+
+        state.blocked_for().min(MAX_RETRY_AFTER) + state.request_delay
+    }
+
+    /// Remaining time of a block longer than `MAX_RETRY_AFTER`; requests should fail right away
+    /// instead of stalling the run until then.
+    pub fn exhausted_for(&self) -> Option<Duration> {
+        let blocked_for = self.state.lock().unwrap().blocked_for();
+
+        if blocked_for > MAX_RETRY_AFTER {
+            Some(blocked_for)
+        } else {
+            None
+        }
+    }
+
+    pub fn record_request(&self) {
+        self.state.lock().unwrap().stats.requests += 1;
+    }
+
+    pub fn record_retry(&self) {
+        self.state.lock().unwrap().stats.retries += 1;
+    }
+
+    /// Blocks requests for `retry_after` and doubles the delay between all later requests.
+    pub fn record_rate_limited(&self, retry_after: Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+
+        state.stats.rate_limited += 1;
+        state.blocked_until = Some(Instant::now() + retry_after.unwrap_or(DEFAULT_RETRY_AFTER));
+        state.request_delay = (state.request_delay * 2).clamp(MIN_REQUEST_DELAY, MAX_REQUEST_DELAY);
+    }
+
+    pub fn stats(&self) -> RateLimiterStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_is_zero_until_rate_limited() {
+        let rate_limiter = RateLimiter::new();
+
+        // act
+        let delay = rate_limiter.delay();
+
+        assert_eq!(delay, Duration::ZERO);
+    }
+
+    #[test]
+    fn delay_obeys_retry_after() {
+        let rate_limiter = RateLimiter::new();
+
+        // act
+        rate_limiter.record_rate_limited(Some(Duration::from_secs(20)));
+
+        let delay = rate_limiter.delay();
+        assert!(delay > Duration::from_secs(20));
+        assert!(delay <= Duration::from_secs(21));
+        assert_eq!(rate_limiter.stats().rate_limited, 1);
+    }
+
+    #[test]
+    fn oversized_retry_after_exhausts_instead_of_delaying() {
+        let rate_limiter = RateLimiter::new();
+
+        // act
+        rate_limiter.record_rate_limited(Some(Duration::from_secs(3600)));
+
+        let exhausted_for = rate_limiter.exhausted_for().unwrap();
+        assert!(exhausted_for > Duration::from_secs(3599));
+        assert!(rate_limiter.delay() <= MAX_RETRY_AFTER + MIN_REQUEST_DELAY);
+    }
+
+    #[test]
+    fn retry_after_within_max_does_not_exhaust() {
+        let rate_limiter = RateLimiter::new();
+
+        // act
+        rate_limiter.record_rate_limited(Some(Duration::from_secs(20)));
+
+        assert_eq!(rate_limiter.exhausted_for(), None);
+    }
+
+    #[test]
+    fn record_rate_limited_doubles_request_delay_up_to_max() {
+        let rate_limiter = RateLimiter::new();
+
+        // act
+        for _ in 0..10 {
+            rate_limiter.record_rate_limited(Some(Duration::ZERO));
+        }
+
+        assert_eq!(rate_limiter.delay(), MAX_REQUEST_DELAY);
+        assert_eq!(rate_limiter.stats().rate_limited, 10);
+    }
+}
@@ -0,0 +1,153 @@
+use k8s_openapi::api::core::v1::Secret;
+use kube::api::Api;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+
+/// Shown instead of secrets in `Debug` output, so they don't end up in logs.
+pub const REDACTED: &str = "<redacted>";
+
+/// Key of a kubernetes secret, read through the api instead of mounting the secret.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SecretRef {
+    pub name: String,
+    pub key: String,
+    /// defaults to the namespace of the exporter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+}
+
+/// Secret kept out of the config, which helm renders into a configmap; only use `value` locally.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SecretValue {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// file of a mounted secret
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// environment variable, like one set from a secret with `secretKeyRef`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_ref: Option<SecretRef>,
+}
+
+impl fmt::Debug for SecretValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretValue")
+            .field("value", &self.value.as_ref().map(|_| REDACTED))
+            .field("file", &self.file)
+            .field("env", &self.env)
+            .field("secret_ref", &self.secret_ref)
+            .finish()
+    }
+}
+
+impl SecretValue {
+    /// Number of sources set, more than one is ambiguous.
+    pub fn sources(&self) -> usize {
+        [
+            self.value.is_some(),
+            self.file.is_some(),
+            self.env.is_some(),
+            self.secret_ref.is_some(),
+        ]
+        .iter()
+        .filter(|set| **set)
+        .count()
+    }
+
+    /// Reads the secret from the file, environment variable or kubernetes secret, in that order,
+    /// falling back to the inline value; `None` when no source is set.
+    pub async fn resolve(&self) -> Result<Option<String>, Box<dyn Error>> {
+        let value = if let Some(file) = &self.file {
+            fs::read_to_string(file)
+                .map_err(|e| format!("Failed reading secret file {}: {}", file, e))?
+        } else if let Some(env) = &self.env {
+            env::var(env).map_err(|e| format!("Failed reading secret from ${}: {}", env, e))?
+        } else if let Some(secret_ref) = &self.secret_ref {
+            read_secret_ref(secret_ref).await?
+        } else if let Some(value) = &self.value {
+            value.clone()
+        } else {
+            return Ok(None);
+        };
+
+        Ok(Some(value.trim().to_string()))
+    }
+}
+
+async fn read_secret_ref(secret_ref: &SecretRef) -> Result<String, Box<dyn Error>> {
+    let kube_client = kube::Client::try_default().await?;
+    let namespace = secret_ref
+        .namespace
+        .clone()
+        .unwrap_or_else(|| kube_client.default_namespace().to_string());
+
+    let secrets: Api<Secret> = Api::namespaced(kube_client, &namespace);
+    let secret = secrets.get(&secret_ref.name).await?;
+
+    let value = secret
+        .data
+        .and_then(|mut data| data.remove(&secret_ref.key))
+        .ok_or_else(|| {
+            format!(
+                "Secret {}/{} has no key {}",
+                namespace, secret_ref.name, secret_ref.key
+            )
+        })?;
+
+    Ok(String::from_utf8(value.0)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn resolve_prefers_file_over_inline_value() {
+        let secret_value = SecretValue {
+            value: Some("abcd".into()),
+            file: Some("test-data/refresh-token".into()),
+            ..Default::default()
+        };
+
+        // act
+        let value = secret_value.resolve().await.unwrap();
+
+        assert_eq!(value, Some("efgh".to_string()));
+    }
+
+    #[tokio::test]
+    async fn resolve_reads_environment_variable() {
+        env::set_var("JARVIS_TESLA_EXPORTER_TEST_SECRET", "ijkl\n");
+        let secret_value = SecretValue {
+            env: Some("JARVIS_TESLA_EXPORTER_TEST_SECRET".into()),
+            ..Default::default()
+        };
+
+        // act
+        let value = secret_value.resolve().await.unwrap();
+
+        assert_eq!(value, Some("ijkl".to_string()));
+    }
+
+    #[test]
+    fn debug_redacts_inline_value() {
+        let secret_value = SecretValue {
+            value: Some("abcd".into()),
+            ..Default::default()
+        };
+
+        // act
+        let debug = format!("{:?}", secret_value);
+
+        assert!(!debug.contains("abcd"));
+        assert!(debug.contains(REDACTED));
+    }
+}
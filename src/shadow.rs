@@ -0,0 +1,222 @@
+use crate::cli::ShadowArgs;
+use crate::config_validation;
+use crate::model::Config;
+use crate::tesla_api_client::TeslaApiClient;
+use jarvis_lib::config_client::{ConfigClient, ConfigClientConfig, SetDefaults};
+use jarvis_lib::measurement_client::MeasurementClient;
+use jarvis_lib::model::Measurement;
+use jarvis_lib::state_client::{StateClient, StateClientConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use tracing::info;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ShadowOutput {
+    pub measurements: Vec<Measurement>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diff: Option<Vec<SampleDiff>>,
+}
+
+/// A sample that changed compared to the last published measurements, or only exists on one side.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SampleDiff {
+    pub sample_name: String,
+    pub sample_type: String,
+    pub metric_type: String,
+    pub last_location: Option<String>,
+    pub location: Option<String>,
+    pub last_value: Option<f64>,
+    pub value: Option<f64>,
+}
+
+/// Runs next to the production exporter to check a change before rolling it out; reads the real
+/// last measurements and vehicle state, but never publishes or stores anything.
+///
+/// `tesla_api_client` has to be created with a read only state client.
+pub async fn shadow(
+    args: ShadowArgs,
+    tesla_api_client: &TeslaApiClient,
+) -> Result<(), Box<dyn Error>> {
+    let config_client = ConfigClient::new(ConfigClientConfig::from_env()?);
+    let mut config: Config = config_client.read_config_from_file()?;
+    config.set_defaults();
+    config_validation::validate(&config)?;
+
+    let state_client = StateClient::new(StateClientConfig::from_env().await?);
+    let last_measurements = state_client.read_state()?;
+
+    let measurements = tesla_api_client.get_measurements(config, last_measurements.clone())?;
+
+    let diff = if args.diff {
+        let diff = diff_measurements(
+            last_measurements.as_deref().unwrap_or_default(),
+            &measurements,
+        );
+        info!(
+            "{} samples differ from the last published measurements",
+            diff.len()
+        );
+        Some(diff)
+    } else {
+        None
+    };
+
+    let output = serde_json::to_string_pretty(&ShadowOutput { measurements, diff })?;
+    if args.output == "-" {
+        println!("{}", output);
+    } else {
+        fs::write(&args.output, output)?;
+        info!("Wrote shadow measurements to {}", args.output);
+    }
+
+    Ok(())
+}
+
+/// Compares samples by name, sample type and metric type.
+pub fn diff_measurements(
+    last_measurements: &[Measurement],
+    measurements: &[Measurement],
+) -> Vec<SampleDiff> {
+    let last_samples = samples_by_key(last_measurements);
+    let samples = samples_by_key(measurements);
+
+    let mut keys: Vec<&(String, String, String)> =
+        last_samples.keys().chain(samples.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    keys.into_iter()
+        .filter_map(|key| {
+            let last = last_samples.get(key);
+            let current = samples.get(key);
+
+            if last == current {
+                return None;
+            }
+
+            let (sample_name, sample_type, metric_type) = key.clone();
+
+            Some(SampleDiff {
+                sample_name,
+                sample_type,
+                metric_type,
+                last_location: last.map(|(location, _)| location.clone()),
+                location: current.map(|(location, _)| location.clone()),
+                last_value: last.map(|(_, value)| *value),
+                value: current.map(|(_, value)| *value),
+            })
+        })
+        .collect()
+}
+
+fn samples_by_key(
+    measurements: &[Measurement],
+) -> BTreeMap<(String, String, String), (String, f64)> {
+    measurements
+        .iter()
+        .flat_map(|measurement| {
+            measurement.samples.iter().map(move |sample| {
+                (
+                    (
+                        sample.sample_name.clone(),
+                        format!("{:?}", sample.sample_type),
+                        format!("{:?}", sample.metric_type),
+                    ),
+                    (measurement.location.clone(), sample.value),
+                )
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use jarvis_lib::model::{EntityType, MetricType, Sample, SampleType};
+
+    fn measurement(location: &str, samples: Vec<(&str, SampleType, f64)>) -> Measurement {
+        Measurement {
+            id: "id".into(),
+            source: "jarvis-tesla-exporter".into(),
+            location: location.into(),
+            samples: samples
+                .into_iter()
+                .map(|(sample_name, sample_type, value)| Sample {
+                    entity_type: EntityType::Device,
+                    entity_name: "jarvis-tesla-exporter".into(),
+                    sample_type,
+                    sample_name: sample_name.into(),
+                    metric_type: MetricType::Gauge,
+                    value,
+                })
+                .collect(),
+            measured_at_time: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn diff_measurements_returns_changed_added_and_removed_samples() {
+        let last_measurements = vec![measurement(
+            "My Home",
+            vec![
+                ("Tessie", SampleType::ElectricityConsumption, 11000.0),
+                ("Tessie", SampleType::Availability, 1.0),
+                (
+                    "Tessie estimated full range",
+                    SampleType::DistanceTraveled,
+                    500000.0,
+                ),
+            ],
+        )];
+        let measurements = vec![measurement(
+            "My Home",
+            vec![
+                ("Tessie", SampleType::ElectricityConsumption, 7000.0),
+                ("Tessie", SampleType::Availability, 1.0),
+                (
+                    "Tessie driving consumption",
+                    SampleType::ElectricityConsumption,
+                    150.0,
+                ),
+            ],
+        )];
+
+        // act
+        let diff = diff_measurements(&last_measurements, &measurements);
+
+        assert_eq!(diff.len(), 3);
+        assert!(diff.iter().any(|d| d.sample_name == "Tessie"
+            && d.last_value == Some(11000.0)
+            && d.value == Some(7000.0)));
+        assert!(diff
+            .iter()
+            .any(|d| d.sample_name == "Tessie estimated full range" && d.value.is_none()));
+        assert!(diff
+            .iter()
+            .any(|d| d.sample_name == "Tessie driving consumption" && d.last_value.is_none()));
+    }
+
+    #[test]
+    fn diff_measurements_reports_location_changes() {
+        let last_measurements = vec![measurement(
+            "My Home",
+            vec![("Tessie", SampleType::Availability, 1.0)],
+        )];
+        let measurements = vec![measurement(
+            "Other",
+            vec![("Tessie", SampleType::Availability, 1.0)],
+        )];
+
+        // act
+        let diff = diff_measurements(&last_measurements, &measurements);
+
+        assert_eq!(diff.len(), 1);
+        assert_eq!(diff[0].last_location, Some("My Home".to_string()));
+        assert_eq!(diff[0].location, Some("Other".to_string()));
+    }
+}
@@ -0,0 +1,860 @@
+use openssl::bn::BigNumContext;
+use openssl::derive::Deriver;
+use openssl::ec::{EcGroup, EcKey, EcPoint, PointConversionForm};
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::rand::rand_bytes;
+use openssl::sha::{sha1, Sha256};
+use openssl::sign::Signer;
+use openssl::symm::{encrypt_aead, Cipher};
+use prost::Message;
+use serde_json::Value;
+use std::error::Error;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::Instant;
+use tracing::{debug, info};
+
+// commands are rejected by the vehicle after this many seconds on its clock
+const COMMAND_EXPIRY_SECONDS: u32 = 15;
+const SESSION_KEY_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+const GCM_TAG_LENGTH: usize = 16;
+const RKE_ACTION_LOCK: i32 = 1;
+// `OperationStatus_E` of car_server.proto, which differs from the one of universal_message.proto
+const CAR_SERVER_OPERATION_STATUS_ERROR: i32 = 1;
+
+// metadata tags and signature types of the vehicle command protocol
+const TAG_SIGNATURE_TYPE: u8 = 0;
+const TAG_DOMAIN: u8 = 1;
+const TAG_PERSONALIZATION: u8 = 2;
+const TAG_EPOCH: u8 = 3;
+const TAG_EXPIRES_AT: u8 = 4;
+const TAG_COUNTER: u8 = 5;
+const TAG_CHALLENGE: u8 = 6;
+const TAG_END: u8 = 255;
+const SIGNATURE_TYPE_AES_GCM_PERSONALIZED: u8 = 5;
+const SIGNATURE_TYPE_HMAC: u8 = 6;
+
+/// Part of the vehicle that executes a command, each with its own session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Domain {
+    Broadcast = 0,
+    /// locks and closures
+    VehicleSecurity = 2,
+    /// charging, climate and most other commands
+    Infotainment = 3,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct RoutableMessage {
+    #[prost(message, optional, tag = "6")]
+    to_destination: Option<Destination>,
+    #[prost(message, optional, tag = "7")]
+    from_destination: Option<Destination>,
+    #[prost(oneof = "Payload", tags = "10, 14, 15")]
+    payload: Option<Payload>,
+    #[prost(message, optional, tag = "12")]
+    signed_message_status: Option<MessageStatus>,
+    #[prost(message, optional, tag = "13")]
+    signature_data: Option<SignatureData>,
+    #[prost(bytes = "vec", tag = "50")]
+    request_uuid: Vec<u8>,
+    #[prost(bytes = "vec", tag = "51")]
+    uuid: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum Payload {
+    #[prost(bytes, tag = "10")]
+    ProtobufMessageAsBytes(Vec<u8>),
+    #[prost(message, tag = "14")]
+    SessionInfoRequest(SessionInfoRequest),
+    #[prost(bytes, tag = "15")]
+    SessionInfo(Vec<u8>),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Destination {
+    #[prost(oneof = "SubDestination", tags = "1, 2")]
+    sub_destination: Option<SubDestination>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum SubDestination {
+    #[prost(enumeration = "Domain", tag = "1")]
+    Domain(i32),
+    #[prost(bytes, tag = "2")]
+    RoutingAddress(Vec<u8>),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct MessageStatus {
+    #[prost(int32, tag = "1")]
+    operation_status: i32,
+    #[prost(int32, tag = "2")]
+    signed_message_fault: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct SessionInfoRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    public_key: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    challenge: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct SessionInfo {
+    #[prost(uint32, tag = "1")]
+    counter: u32,
+    #[prost(bytes = "vec", tag = "2")]
+    public_key: Vec<u8>,
+    #[prost(bytes = "vec", tag = "3")]
+    epoch: Vec<u8>,
+    #[prost(fixed32, tag = "4")]
+    clock_time: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct SignatureData {
+    #[prost(message, optional, tag = "1")]
+    signer_identity: Option<KeyIdentity>,
+    #[prost(oneof = "SignatureType", tags = "5, 6")]
+    signature_type: Option<SignatureType>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct KeyIdentity {
+    #[prost(bytes = "vec", tag = "1")]
+    public_key: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum SignatureType {
+    #[prost(message, tag = "5")]
+    AesGcmPersonalized(AesGcmPersonalizedSignatureData),
+    #[prost(message, tag = "6")]
+    SessionInfoTag(HmacSignatureData),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct AesGcmPersonalizedSignatureData {
+    #[prost(bytes = "vec", tag = "1")]
+    epoch: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    nonce: Vec<u8>,
+    #[prost(uint32, tag = "3")]
+    counter: u32,
+    #[prost(fixed32, tag = "4")]
+    expires_at: u32,
+    #[prost(bytes = "vec", tag = "5")]
+    tag: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct HmacSignatureData {
+    #[prost(bytes = "vec", tag = "1")]
+    tag: Vec<u8>,
+}
+
+// the few infotainment actions of car_server.proto the exporter sends
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Action {
+    #[prost(message, optional, tag = "2")]
+    vehicle_action: Option<VehicleAction>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct VehicleAction {
+    #[prost(oneof = "VehicleActionMessage", tags = "5, 6, 10, 30, 43")]
+    vehicle_action_msg: Option<VehicleActionMessage>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum VehicleActionMessage {
+    #[prost(message, tag = "5")]
+    ChargingSetLimit(ChargingSetLimitAction),
+    #[prost(message, tag = "6")]
+    ChargingStartStop(ChargingStartStopAction),
+    #[prost(message, tag = "10")]
+    HvacAuto(HvacAutoAction),
+    #[prost(message, tag = "30")]
+    VehicleControlSetSentryMode(VehicleControlSetSentryModeAction),
+    #[prost(message, tag = "43")]
+    SetChargingAmps(SetChargingAmpsAction),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ChargingSetLimitAction {
+    #[prost(int32, tag = "1")]
+    percent: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ChargingStartStopAction {
+    #[prost(oneof = "ChargingAction", tags = "2, 5")]
+    charging_action: Option<ChargingAction>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum ChargingAction {
+    #[prost(message, tag = "2")]
+    Start(Void),
+    #[prost(message, tag = "5")]
+    Stop(Void),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Void {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct HvacAutoAction {
+    #[prost(bool, tag = "1")]
+    power_on: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct VehicleControlSetSentryModeAction {
+    #[prost(bool, tag = "1")]
+    on: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct SetChargingAmpsAction {
+    #[prost(int32, tag = "1")]
+    charging_amps: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct CarServerResponse {
+    #[prost(message, optional, tag = "1")]
+    action_status: Option<ActionStatus>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ActionStatus {
+    #[prost(int32, tag = "1")]
+    result: i32,
+    #[prost(message, optional, tag = "2")]
+    result_reason: Option<ResultReason>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ResultReason {
+    #[prost(string, tag = "1")]
+    plain_text: String,
+}
+
+/// Vehicle security message of vcsec.proto, only used to lock the doors.
+#[derive(Clone, PartialEq, prost::Message)]
+struct UnsignedMessage {
+    #[prost(int32, tag = "2")]
+    rke_action: i32,
+}
+
+/// Key pair the vehicle knows the exporter by, enrolled through the Tesla app.
+pub struct CommandKey {
+    key: EcKey<Private>,
+}
+
+impl CommandKey {
+    /// Reads the pem encoded private key; a new key would have to be enrolled first, so a missing
+    /// file is an error instead of a reason to generate one.
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        if !Path::new(path).exists() {
+            return Err(format!(
+                "Command key {} doesn't exist, generate it with `jarvis-tesla-exporter command-key` and enroll it in the Tesla app",
+                path
+            )
+            .into());
+        }
+
+        let key = EcKey::private_key_from_pem(&fs::read(path)?)?;
+        Ok(Self { key })
+    }
+
+    /// Reads the pem encoded private key, generating and storing a new one when the file is missing.
+    pub fn load_or_generate(path: &str) -> Result<Self, Box<dyn Error>> {
+        if Path::new(path).exists() {
+            return Self::load(path);
+        }
+
+        info!("Generating command key {}", path);
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let key = EcKey::generate(&group)?;
+        fs::write(path, key.private_key_to_pem()?)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+
+        Ok(Self { key })
+    }
+
+    /// Uncompressed point, the form the vehicle identifies keys by.
+    pub fn public_key(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut context = BigNumContext::new()?;
+
+        Ok(self.key.public_key().to_bytes(
+            self.key.group(),
+            PointConversionForm::UNCOMPRESSED,
+            &mut context,
+        )?)
+    }
+
+    /// To host at `/.well-known/appspecific/com.tesla.3p.public-key.pem` of the application domain.
+    pub fn public_key_pem(&self) -> Result<String, Box<dyn Error>> {
+        Ok(String::from_utf8(self.key.public_key_to_pem()?)?)
+    }
+
+    /// First 16 bytes of the sha1 of the ecdh secret, shared by the exporter and a vehicle domain.
+    fn session_key(&self, vehicle_public_key: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut context = BigNumContext::new()?;
+        let point = EcPoint::from_bytes(self.key.group(), vehicle_public_key, &mut context)?;
+        let peer = PKey::from_ec_key(EcKey::from_public_key(self.key.group(), &point)?)?;
+        let private = PKey::from_ec_key(self.key.clone())?;
+
+        let mut deriver = Deriver::new(&private)?;
+        deriver.set_peer(&peer)?;
+        let shared_secret = deriver.derive_to_vec()?;
+
+        Ok(sha1(&shared_secret)[..SESSION_KEY_LENGTH].to_vec())
+    }
+}
+
+/// Authenticated session with a vehicle domain; the counter has to increase with every command.
+#[derive(Clone, Debug)]
+pub struct Session {
+    vin: String,
+    domain: Domain,
+    key: Vec<u8>,
+    epoch: Vec<u8>,
+    counter: u32,
+    clock_time: u32,
+    started_at: Instant,
+}
+
+impl Session {
+    /// The command signed and wrapped in a routable message for the `signed_command` endpoint.
+    pub fn sign(
+        &mut self,
+        command_key: &CommandKey,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.counter += 1;
+        // the vehicle clock started at the clock time of the session info
+        let expires_at =
+            self.clock_time + self.started_at.elapsed().as_secs() as u32 + COMMAND_EXPIRY_SECONDS;
+
+        let mut metadata = Metadata::default();
+        metadata.add(TAG_SIGNATURE_TYPE, &[SIGNATURE_TYPE_AES_GCM_PERSONALIZED]);
+        metadata.add(TAG_DOMAIN, &[self.domain as u8]);
+        metadata.add(TAG_PERSONALIZATION, self.vin.as_bytes());
+        metadata.add(TAG_EPOCH, &self.epoch);
+        metadata.add(TAG_EXPIRES_AT, &expires_at.to_be_bytes());
+        metadata.add(TAG_COUNTER, &self.counter.to_be_bytes());
+
+        let nonce = random_bytes(NONCE_LENGTH)?;
+        let (ciphertext, tag) = encrypt(&self.key, &nonce, &metadata.checksum(), payload)?;
+
+        let message = RoutableMessage {
+            to_destination: Some(Destination::domain(self.domain)),
+            from_destination: Some(Destination::routing_address()?),
+            payload: Some(Payload::ProtobufMessageAsBytes(ciphertext)),
+            signature_data: Some(SignatureData {
+                signer_identity: Some(KeyIdentity {
+                    public_key: command_key.public_key()?,
+                }),
+                signature_type: Some(SignatureType::AesGcmPersonalized(
+                    AesGcmPersonalizedSignatureData {
+                        epoch: self.epoch.clone(),
+                        nonce,
+                        counter: self.counter,
+                        expires_at,
+                        tag,
+                    },
+                )),
+            }),
+            uuid: random_bytes(16)?,
+            ..Default::default()
+        };
+
+        Ok(message.encode_to_vec())
+    }
+}
+
+/// Request for the session info of a domain, along with the challenge the answer is tagged with.
+pub fn session_info_request(
+    command_key: &CommandKey,
+    domain: Domain,
+) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+    let challenge = random_bytes(16)?;
+    let message = RoutableMessage {
+        to_destination: Some(Destination::domain(domain)),
+        from_destination: Some(Destination::routing_address()?),
+        payload: Some(Payload::SessionInfoRequest(SessionInfoRequest {
+            public_key: command_key.public_key()?,
+            challenge: challenge.clone(),
+        })),
+        uuid: challenge.clone(),
+        ..Default::default()
+    };
+
+    Ok((message.encode_to_vec(), challenge))
+}
+
+/// Verifies the session info the vehicle answered with and derives the session from it.
+pub fn session_from_response(
+    command_key: &CommandKey,
+    vin: &str,
+    domain: Domain,
+    challenge: &[u8],
+    response: &[u8],
+) -> Result<Session, Box<dyn Error>> {
+    let message = RoutableMessage::decode(response)?;
+    check_status(&message)?;
+
+    let session_info_bytes = match message.payload {
+        Some(Payload::SessionInfo(session_info)) => session_info,
+        _ => return Err("Response doesn't contain session info".into()),
+    };
+    let tag = match message.signature_data.and_then(|s| s.signature_type) {
+        Some(SignatureType::SessionInfoTag(hmac)) => hmac.tag,
+        _ => return Err("Session info isn't tagged".into()),
+    };
+    let session_info = SessionInfo::decode(session_info_bytes.as_slice())?;
+    let key = command_key.session_key(&session_info.public_key)?;
+
+    let mut metadata = Metadata::default();
+    metadata.add(TAG_SIGNATURE_TYPE, &[SIGNATURE_TYPE_HMAC]);
+    metadata.add(TAG_PERSONALIZATION, vin.as_bytes());
+    metadata.add(TAG_CHALLENGE, challenge);
+    let expected_tag = hmac_sha256(
+        &hmac_sha256(&key, b"session info")?,
+        &metadata.with_message(&session_info_bytes),
+    )?;
+    if expected_tag.len() != tag.len() || !memcmp::eq(&expected_tag, &tag) {
+        return Err("Session info tag doesn't match, the key may not be enrolled".into());
+    }
+
+    debug!(
+        "Started session with {:?} of vehicle {} at counter {}",
+        domain, vin, session_info.counter
+    );
+
+    Ok(Session {
+        vin: vin.to_string(),
+        domain,
+        key,
+        epoch: session_info.epoch,
+        counter: session_info.counter,
+        clock_time: session_info.clock_time,
+        started_at: Instant::now(),
+    })
+}
+
+/// What the vehicle did with a signed command.
+#[derive(Debug, PartialEq)]
+pub enum CommandOutcome {
+    Executed,
+    /// executed but refused, with the reason like `is_charging`
+    Failed(String),
+    /// the vehicle didn't accept the signature, usually because the session is stale
+    Fault(i32),
+}
+
+pub fn command_outcome(response: &[u8]) -> Result<CommandOutcome, Box<dyn Error>> {
+    let message = RoutableMessage::decode(response)?;
+    if let Some(status) = &message.signed_message_status {
+        if status.signed_message_fault != 0 {
+            return Ok(CommandOutcome::Fault(status.signed_message_fault));
+        }
+    }
+
+    // responses are only encrypted on request, the plain infotainment response holds the result
+    match (&message.payload, &message.from_destination) {
+        (
+            Some(Payload::ProtobufMessageAsBytes(payload)),
+            Some(Destination {
+                sub_destination: Some(SubDestination::Domain(domain)),
+            }),
+        ) if *domain == Domain::Infotainment as i32 && message.signature_data.is_none() => {
+            let status = CarServerResponse::decode(payload.as_slice())?.action_status;
+            match status {
+                Some(status) if status.result == CAR_SERVER_OPERATION_STATUS_ERROR => {
+                    Ok(CommandOutcome::Failed(
+                        status
+                            .result_reason
+                            .map(|reason| reason.plain_text)
+                            .unwrap_or_default(),
+                    ))
+                }
+                _ => Ok(CommandOutcome::Executed),
+            }
+        }
+        _ => Ok(CommandOutcome::Executed),
+    }
+}
+
+/// Domain and payload of the signed equivalent of a rest command, if the exporter knows it.
+pub fn command_payload(command: &str, body: &Value) -> Option<(Domain, Vec<u8>)> {
+    let integer = |key: &str| body[key].as_i64().map(|value| value as i32);
+    let vehicle_action = match command {
+        "charge_start" => VehicleActionMessage::ChargingStartStop(ChargingStartStopAction {
+            charging_action: Some(ChargingAction::Start(Void {})),
+        }),
+        "charge_stop" => VehicleActionMessage::ChargingStartStop(ChargingStartStopAction {
+            charging_action: Some(ChargingAction::Stop(Void {})),
+        }),
+        "set_charge_limit" => VehicleActionMessage::ChargingSetLimit(ChargingSetLimitAction {
+            percent: integer("percent")?,
+        }),
+        "set_charging_amps" => VehicleActionMessage::SetChargingAmps(SetChargingAmpsAction {
+            charging_amps: integer("charging_amps")?,
+        }),
+        "auto_conditioning_start" | "auto_conditioning_stop" => {
+            VehicleActionMessage::HvacAuto(HvacAutoAction {
+                power_on: command == "auto_conditioning_start",
+            })
+        }
+        "set_sentry_mode" => {
+            VehicleActionMessage::VehicleControlSetSentryMode(VehicleControlSetSentryModeAction {
+                on: body["on"].as_bool()?,
+            })
+        }
+        "door_lock" => {
+            let message = UnsignedMessage {
+                rke_action: RKE_ACTION_LOCK,
+            };
+            return Some((Domain::VehicleSecurity, message.encode_to_vec()));
+        }
+        _ => return None,
+    };
+
+    let action = Action {
+        vehicle_action: Some(VehicleAction {
+            vehicle_action_msg: Some(vehicle_action),
+        }),
+    };
+
+    Some((Domain::Infotainment, action.encode_to_vec()))
+}
+
+fn check_status(message: &RoutableMessage) -> Result<(), Box<dyn Error>> {
+    match &message.signed_message_status {
+        Some(status) if status.signed_message_fault != 0 => Err(format!(
+            "Vehicle returned message fault {}",
+            status.signed_message_fault
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+impl Destination {
+    fn domain(domain: Domain) -> Self {
+        Self {
+            sub_destination: Some(SubDestination::Domain(domain as i32)),
+        }
+    }
+
+    /// Random address the vehicle routes its response to.
+    fn routing_address() -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            sub_destination: Some(SubDestination::RoutingAddress(random_bytes(16)?)),
+        })
+    }
+}
+
+/// Tag-length-value encoded fields a signature covers, in increasing tag order.
+#[derive(Default)]
+struct Metadata {
+    bytes: Vec<u8>,
+}
+
+impl Metadata {
+    fn add(&mut self, tag: u8, value: &[u8]) {
+        self.bytes.push(tag);
+        self.bytes.push(value.len() as u8);
+        self.bytes.extend_from_slice(value);
+    }
+
+    fn with_message(&self, message: &[u8]) -> Vec<u8> {
+        let mut bytes = self.bytes.clone();
+        bytes.push(TAG_END);
+        bytes.extend_from_slice(message);
+        bytes
+    }
+
+    /// Sha256 of the metadata, the additional authenticated data of aes-gcm.
+    fn checksum(&self) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.with_message(&[]));
+        hasher.finish().to_vec()
+    }
+}
+
+/// Aes-128-gcm with the metadata checksum as additional authenticated data, returning the
+/// ciphertext and the tag.
+fn encrypt(
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+    let mut tag = vec![0u8; GCM_TAG_LENGTH];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_128_gcm(),
+        key,
+        Some(nonce),
+        aad,
+        plaintext,
+        &mut tag,
+    )?;
+
+    Ok((ciphertext, tag))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+
+    Ok(signer.sign_to_vec()?)
+}
+
+fn random_bytes(length: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut bytes = vec![0u8; length];
+    rand_bytes(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::bn::BigNum;
+    use openssl::sha::sha256;
+
+    const VIN: &str = "5YJ3E7EB2KF000000";
+
+    fn hex(value: &str) -> Vec<u8> {
+        (0..value.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&value[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn generated_key() -> CommandKey {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        CommandKey {
+            key: EcKey::generate(&group).unwrap(),
+        }
+    }
+
+    /// Session info as the vehicle answers it, tagged with the session key.
+    fn session_info_response(
+        vehicle_key: &CommandKey,
+        command_key: &CommandKey,
+        challenge: &[u8],
+    ) -> Vec<u8> {
+        let session_info = SessionInfo {
+            counter: 7,
+            public_key: vehicle_key.public_key().unwrap(),
+            epoch: vec![1; 16],
+            clock_time: 1000,
+        }
+        .encode_to_vec();
+        let key = vehicle_key
+            .session_key(&command_key.public_key().unwrap())
+            .unwrap();
+        let mut metadata = Metadata::default();
+        metadata.add(TAG_SIGNATURE_TYPE, &[SIGNATURE_TYPE_HMAC]);
+        metadata.add(TAG_PERSONALIZATION, VIN.as_bytes());
+        metadata.add(TAG_CHALLENGE, challenge);
+        let tag = hmac_sha256(
+            &hmac_sha256(&key, b"session info").unwrap(),
+            &metadata.with_message(&session_info),
+        )
+        .unwrap();
+
+        RoutableMessage {
+            payload: Some(Payload::SessionInfo(session_info)),
+            signature_data: Some(SignatureData {
+                signer_identity: None,
+                signature_type: Some(SignatureType::SessionInfoTag(HmacSignatureData { tag })),
+            }),
+            request_uuid: challenge.to_vec(),
+            ..Default::default()
+        }
+        .encode_to_vec()
+    }
+
+    #[test]
+    fn hmac_sha256_matches_rfc_4231_test_case_2() {
+        // act
+        let tag = hmac_sha256(b"Jefe", b"what do ya want for nothing?").unwrap();
+
+        assert_eq!(
+            tag,
+            hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+        );
+    }
+
+    #[test]
+    fn session_key_matches_nist_p256_ecdh_vector() {
+        // first P-256 case of the nist cavs ecc cdh primitive test vectors
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let private = BigNum::from_slice(&hex(
+            "7d7dc5f71eb29ddaf80d6214632eeae03d9058af1fb6d22ed80badb62bc1a534",
+        ))
+        .unwrap();
+        let mut public = EcPoint::new(&group).unwrap();
+        public
+            .mul_generator(&group, &private, &BigNumContext::new().unwrap())
+            .unwrap();
+        let command_key = CommandKey {
+            key: EcKey::from_private_components(&group, &private, &public).unwrap(),
+        };
+        let vehicle_public_key = [
+            vec![0x04],
+            hex("700c48f77f56584c5cc632ca65640db91b6bacce3a4df6b42ce7cc838833d287"),
+            hex("db71e509e3fd9b060ddb20ba5c51dcc5948d46fbf640dfe0441782cab85fa4ac"),
+        ]
+        .concat();
+
+        // act
+        let session_key = command_key.session_key(&vehicle_public_key).unwrap();
+
+        let shared_secret = hex("46fc62106420ff012e54a434fbdd2d25ccc5852060561e68040dd7778997bd7b");
+        assert_eq!(session_key, sha1(&shared_secret)[..SESSION_KEY_LENGTH]);
+    }
+
+    #[test]
+    fn encrypt_matches_gcm_spec_test_case_4() {
+        // act
+        let (ciphertext, tag) = encrypt(
+            &hex("feffe9928665731c6d6a8f9467308308"),
+            &hex("cafebabefacedbaddecaf888"),
+            &hex("feedfacedeadbeeffeedfacedeadbeefabaddad2"),
+            &hex("d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a721c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            ciphertext,
+            hex("42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091")
+        );
+        assert_eq!(tag, hex("5bc94fbc3221a5db94fae95ae7121a47"));
+    }
+
+    #[test]
+    fn metadata_checksum_hashes_tag_length_value_fields_and_end_tag() {
+        let mut metadata = Metadata::default();
+        metadata.add(TAG_SIGNATURE_TYPE, &[SIGNATURE_TYPE_AES_GCM_PERSONALIZED]);
+        metadata.add(TAG_DOMAIN, &[Domain::Infotainment as u8]);
+        metadata.add(TAG_PERSONALIZATION, VIN.as_bytes());
+        metadata.add(TAG_EPOCH, &[1; 16]);
+        metadata.add(TAG_EXPIRES_AT, &1015u32.to_be_bytes());
+        metadata.add(TAG_COUNTER, &8u32.to_be_bytes());
+
+        // act
+        let checksum = metadata.checksum();
+
+        let encoded = [
+            hex("000105"),
+            hex("010103"),
+            [hex("0211"), VIN.as_bytes().to_vec()].concat(),
+            [hex("0310"), vec![1; 16]].concat(),
+            hex("0404000003f7"),
+            hex("050400000008"),
+            hex("ff"),
+        ]
+        .concat();
+        assert_eq!(checksum, sha256(&encoded));
+    }
+
+    #[test]
+    fn sign_increases_counter_and_sets_expiry_from_vehicle_clock() {
+        let command_key = generated_key();
+        let mut session = Session {
+            vin: VIN.into(),
+            domain: Domain::Infotainment,
+            key: vec![2; SESSION_KEY_LENGTH],
+            epoch: vec![1; 16],
+            counter: 7,
+            clock_time: 1000,
+            started_at: Instant::now(),
+        };
+
+        // act
+        let signed =
+            RoutableMessage::decode(session.sign(&command_key, &[0x0a]).unwrap().as_slice())
+                .unwrap();
+
+        match signed.signature_data.unwrap().signature_type {
+            Some(SignatureType::AesGcmPersonalized(signature)) => {
+                assert_eq!(signature.counter, 8);
+                assert_eq!(signature.expires_at, 1000 + COMMAND_EXPIRY_SECONDS);
+                assert_eq!(signature.epoch, vec![1; 16]);
+            }
+            _ => panic!("expected aes-gcm signature"),
+        }
+    }
+
+    #[test]
+    fn session_from_response_rejects_other_challenge() {
+        let command_key = generated_key();
+        let vehicle_key = generated_key();
+        let response = session_info_response(&vehicle_key, &command_key, &[1; 16]);
+
+        // act
+        let result =
+            session_from_response(&command_key, VIN, Domain::Infotainment, &[2; 16], &response);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn command_outcome_returns_fault_and_failure_reason() {
+        let fault = RoutableMessage {
+            signed_message_status: Some(MessageStatus {
+                operation_status: 2,
+                signed_message_fault: 3,
+            }),
+            ..Default::default()
+        };
+        // car_server Response{actionStatus: {result: OPERATIONSTATUS_ERROR, resultReason: {plainText: "is_charging"}}}
+        let failed = RoutableMessage {
+            from_destination: Some(Destination::domain(Domain::Infotainment)),
+            payload: Some(Payload::ProtobufMessageAsBytes(
+                [hex("0a110801120d0a0b"), b"is_charging".to_vec()].concat(),
+            )),
+            ..Default::default()
+        };
+        // car_server Response{actionStatus: {result: OPERATIONSTATUS_OK}}
+        let executed = RoutableMessage {
+            from_destination: Some(Destination::domain(Domain::Infotainment)),
+            payload: Some(Payload::ProtobufMessageAsBytes(hex("0a020800"))),
+            ..Default::default()
+        };
+
+        // act
+        let outcomes = (
+            command_outcome(&fault.encode_to_vec()).unwrap(),
+            command_outcome(&failed.encode_to_vec()).unwrap(),
+            command_outcome(&executed.encode_to_vec()).unwrap(),
+        );
+
+        assert_eq!(
+            outcomes,
+            (
+                CommandOutcome::Fault(3),
+                CommandOutcome::Failed("is_charging".into()),
+                CommandOutcome::Executed
+            )
+        );
+    }
+}
@@ -0,0 +1,34 @@
+use serde::Serialize;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+const SNAPSHOT_DIRECTORY: &str = "test-data/snapshots";
+
+/// Compares the pretty json of `value` with `test-data/snapshots/<name>.json`; run the tests with
+/// `UPDATE_SNAPSHOTS=1` to write the snapshots after an intended change and review their diff.
+pub fn assert_json_snapshot<T: Serialize>(name: &str, value: &T) {
+    let actual = format!("{}\n", serde_json::to_string_pretty(value).unwrap());
+    let path = Path::new(SNAPSHOT_DIRECTORY).join(format!("{}.json", name));
+
+    if env::var("UPDATE_SNAPSHOTS").is_ok() {
+        fs::create_dir_all(SNAPSHOT_DIRECTORY).unwrap();
+        fs::write(&path, actual).unwrap();
+        return;
+    }
+
+    let expected = fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!(
+            "Failed reading snapshot {}, create it with UPDATE_SNAPSHOTS=1: {}",
+            path.display(),
+            e
+        )
+    });
+
+    assert_eq!(
+        actual,
+        expected,
+        "snapshot {} doesn't match, update it with UPDATE_SNAPSHOTS=1 if the change is intended",
+        path.display()
+    );
+}
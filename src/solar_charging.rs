@@ -0,0 +1,916 @@
+use crate::cli::ControllerArgs;
+use crate::config_validation;
+use crate::model::{
+    AccountConfig, Config, SolarChargingConfig, TeslaAccessToken, TeslaVehicle, TeslaVehicleState,
+};
+use crate::tesla_api_client::TeslaApiClient;
+use crate::tesla_api_error::TeslaApiError;
+use async_trait::async_trait;
+use chrono::{Duration, Local, NaiveDateTime, NaiveTime};
+use jarvis_lib::config_client::{ConfigClient, ConfigClientConfig, SetDefaults};
+use jarvis_lib::model::{Measurement, MetricType};
+use std::env;
+use std::error::Error;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
+
+// the vehicle reports a few volts while not charging
+const MIN_PLAUSIBLE_CHARGER_VOLTAGE: f64 = 100.0;
+// don't let a deadline that has almost passed ask for an infinite current
+const MIN_HOURS_UNTIL_DEADLINE: f64 = 0.25;
+// poll the vehicle data again after this, to notice the cable or charge limit changed
+const SNAPSHOT_MAX_AGE_SECONDS: u64 = 900;
+// don't keep waking a vehicle that stays asleep or offline, that drains its 12V battery
+const WAKE_UP_BACKOFF_SECONDS: u64 = 3600;
+
+/// What the controller needs to know about the vehicle to decide on the charging current.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChargingSnapshot {
+    pub plugged_in: bool,
+    pub at_geofence: bool,
+    pub charging: bool,
+    /// percent
+    pub soc: f64,
+    /// percent
+    pub charge_limit_soc: f64,
+    /// amps the vehicle is set to charge with
+    pub amps: u32,
+    /// reported by the vehicle while charging, otherwise taken from the config
+    pub watts_per_amp: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargingDecision {
+    Keep,
+    Start { amps: u32 },
+    SetAmps { amps: u32 },
+    Stop,
+}
+
+/// Charging commands of a single vehicle, so the controller can be tested without the tesla api.
+#[async_trait(?Send)]
+pub trait ChargingApi {
+    /// Polls the vehicle data; None when the vehicle is asleep or offline.
+    async fn snapshot(&mut self) -> Result<Option<ChargingSnapshot>, Box<dyn Error>>;
+    /// Updates the state of charge, location and whether it's charging of a polled snapshot from
+    /// telemetry or streaming data, which doesn't keep the vehicle awake; None when it's asleep.
+    async fn refresh(
+        &mut self,
+        snapshot: &ChargingSnapshot,
+    ) -> Result<Option<ChargingSnapshot>, Box<dyn Error>>;
+    async fn wake_up(&mut self) -> Result<(), Box<dyn Error>>;
+    async fn start_charging(&mut self) -> Result<(), Box<dyn Error>>;
+    async fn stop_charging(&mut self) -> Result<(), Box<dyn Error>>;
+    async fn set_charging_amps(&mut self, amps: u32) -> Result<(), Box<dyn Error>>;
+}
+
+/// Decides on the charging current from the grid power, so the vehicle only uses power that would
+/// otherwise be exported, unless it's needed to reach the minimum state of charge by the deadline.
+pub fn decide(
+    config: &SolarChargingConfig,
+    snapshot: &ChargingSnapshot,
+    grid_power: f64,
+    battery_capacity_kwh: f64,
+    now: NaiveDateTime,
+) -> ChargingDecision {
+    // leave charging elsewhere, and the charge limit, to the vehicle
+    if !snapshot.plugged_in || !snapshot.at_geofence || snapshot.soc >= snapshot.charge_limit_soc {
+        return ChargingDecision::Keep;
+    }
+
+    let car_power = if snapshot.charging {
+        snapshot.amps as f64 * snapshot.watts_per_amp
+    } else {
+        0.0
+    };
+    // what the house would export if the vehicle weren't charging
+    let surplus = car_power - grid_power;
+    let solar_amps = (surplus / snapshot.watts_per_amp).floor().max(0.0) as u32;
+    let required_amps = required_amps(config, snapshot, battery_capacity_kwh, now);
+
+    let mut amps = solar_amps.max(required_amps).min(config.max_amps);
+    if amps < config.min_amps {
+        if required_amps == 0 {
+            return if snapshot.charging {
+                ChargingDecision::Stop
+            } else {
+                ChargingDecision::Keep
+            };
+        }
+        amps = config.min_amps;
+    }
+
+    if !snapshot.charging {
+        ChargingDecision::Start { amps }
+    } else if amps != snapshot.amps {
+        ChargingDecision::SetAmps { amps }
+    } else {
+        ChargingDecision::Keep
+    }
+}
+
+/// Current needed to reach the minimum state of charge by the next deadline, 0 without one.
+fn required_amps(
+    config: &SolarChargingConfig,
+    snapshot: &ChargingSnapshot,
+    battery_capacity_kwh: f64,
+    now: NaiveDateTime,
+) -> u32 {
+    let (minimum_soc, deadline) = match (config.minimum_soc, next_deadline(config, now)) {
+        (Some(minimum_soc), Some(deadline)) if snapshot.soc < minimum_soc => {
+            (minimum_soc, deadline)
+        }
+        _ => return 0,
+    };
+
+    let hours = ((deadline - now).num_seconds() as f64 / 3600.0).max(MIN_HOURS_UNTIL_DEADLINE);
+    let energy_wh = (minimum_soc - snapshot.soc) / 100.0 * battery_capacity_kwh * 1000.0;
+
+    (energy_wh / hours / snapshot.watts_per_amp).ceil() as u32
+}
+
+fn next_deadline(config: &SolarChargingConfig, now: NaiveDateTime) -> Option<NaiveDateTime> {
+    let deadline = config.minimum_soc_deadline.as_ref()?;
+    let time = match NaiveTime::parse_from_str(deadline, "%H:%M") {
+        Ok(time) => time,
+        Err(e) => {
+            warn!("Invalid minimum soc deadline {}: {}", deadline, e);
+            return None;
+        }
+    };
+
+    let today = now.date().and_time(time);
+    if today > now {
+        Some(today)
+    } else {
+        Some(today + Duration::days(1))
+    }
+}
+
+/// Adjusts charging whenever the grid power is published by another exporter.
+pub struct SolarChargingController<A: ChargingApi> {
+    api: A,
+    config: SolarChargingConfig,
+    battery_capacity_kwh: f64,
+    dry_run: bool,
+    last_decided_at: Option<Instant>,
+    /// last snapshot with the time the vehicle data was polled for it
+    known_snapshot: Option<(ChargingSnapshot, Instant)>,
+    /// last time waking the vehicle didn't get a snapshot of it
+    failed_wake_up_at: Option<Instant>,
+}
+
+impl<A: ChargingApi> SolarChargingController<A> {
+    pub fn new(
+        api: A,
+        config: SolarChargingConfig,
+        battery_capacity_kwh: f64,
+        dry_run: bool,
+    ) -> Self {
+        Self {
+            api,
+            config,
+            battery_capacity_kwh,
+            dry_run,
+            last_decided_at: None,
+            known_snapshot: None,
+            failed_wake_up_at: None,
+        }
+    }
+
+    fn decide(&self, snapshot: &ChargingSnapshot, grid_power: f64) -> ChargingDecision {
+        decide(
+            &self.config,
+            snapshot,
+            grid_power,
+            self.battery_capacity_kwh,
+            Local::now().naive_local(),
+        )
+    }
+
+    /// Current snapshot of the vehicle, only polling the vehicle data when the decision could
+    /// change and only waking the vehicle when it would start charging; None while it sleeps.
+    async fn current_snapshot(
+        &mut self,
+        grid_power: f64,
+    ) -> Result<Option<ChargingSnapshot>, Box<dyn Error>> {
+        if let Some((known_snapshot, polled_at)) = self.known_snapshot.clone() {
+            if polled_at.elapsed().as_secs() < SNAPSHOT_MAX_AGE_SECONDS {
+                match self.api.refresh(&known_snapshot).await? {
+                    Some(snapshot)
+                        if self.decide(&snapshot, grid_power) == ChargingDecision::Keep =>
+                    {
+                        self.known_snapshot = Some((snapshot.clone(), polled_at));
+                        return Ok(Some(snapshot));
+                    }
+                    Some(_) => debug!("Charging may need to change, polling vehicle data"),
+                    None => return self.wake_up_to_start(grid_power).await,
+                }
+            }
+        }
+
+        match self.api.snapshot().await? {
+            Some(snapshot) => {
+                self.known_snapshot = Some((snapshot.clone(), Instant::now()));
+                Ok(Some(snapshot))
+            }
+            None => self.wake_up_to_start(grid_power).await,
+        }
+    }
+
+    /// Wakes the sleeping vehicle if it would start charging as it was last seen, or if nothing
+    /// is known about it yet; never in a dry run and not again soon after it didn't wake up.
+    async fn wake_up_to_start(
+        &mut self,
+        grid_power: f64,
+    ) -> Result<Option<ChargingSnapshot>, Box<dyn Error>> {
+        let would_start = match &self.known_snapshot {
+            Some((known_snapshot, _)) => {
+                let sleeping_snapshot = ChargingSnapshot {
+                    charging: false,
+                    ..known_snapshot.clone()
+                };
+                matches!(
+                    self.decide(&sleeping_snapshot, grid_power),
+                    ChargingDecision::Start { .. }
+                )
+            }
+            None => true,
+        };
+        if !would_start {
+            return Ok(None);
+        }
+
+        if self.dry_run {
+            info!("Not waking up vehicle in a dry run");
+            return Ok(None);
+        }
+
+        if let Some(failed_wake_up_at) = self.failed_wake_up_at {
+            if failed_wake_up_at.elapsed().as_secs() < WAKE_UP_BACKOFF_SECONDS {
+                debug!("Vehicle didn't wake up recently, not waking it up again yet");
+                return Ok(None);
+            }
+        }
+
+        info!("Waking up vehicle to check on charging");
+        // counts as failed until there's a snapshot, also when waking up returns an error
+        self.failed_wake_up_at = Some(Instant::now());
+        self.api.wake_up().await?;
+        let snapshot = self.api.snapshot().await?;
+        self.known_snapshot = snapshot.clone().map(|s| (s, Instant::now()));
+        if snapshot.is_some() {
+            self.failed_wake_up_at = None;
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Keeps the known snapshot in line with the commands sent, until it's polled again.
+    fn apply_decision(&mut self, decision: ChargingDecision) {
+        if let Some((snapshot, _)) = &mut self.known_snapshot {
+            match decision {
+                ChargingDecision::Keep => {}
+                ChargingDecision::Start { amps } => {
+                    snapshot.charging = true;
+                    snapshot.amps = amps;
+                }
+                ChargingDecision::SetAmps { amps } => snapshot.amps = amps,
+                ChargingDecision::Stop => snapshot.charging = false,
+            }
+        }
+    }
+
+    /// Handles a nats message with a measurement; returns the decision if it held the grid power.
+    pub async fn handle_message(
+        &mut self,
+        payload: &[u8],
+    ) -> Result<Option<ChargingDecision>, Box<dyn Error>> {
+        let measurement: Measurement = serde_json::from_slice(payload)?;
+
+        let grid_power = match measurement.samples.iter().find(|sample| {
+            sample.sample_name == self.config.grid_sample_name
+                && matches!(sample.metric_type, MetricType::Gauge)
+        }) {
+            Some(sample) => sample.value,
+            None => return Ok(None),
+        };
+
+        if let Some(last_decided_at) = self.last_decided_at {
+            if last_decided_at.elapsed().as_secs() < self.config.interval_seconds {
+                debug!("Skipping grid power {} W, decided recently", grid_power);
+                return Ok(None);
+            }
+        }
+        self.last_decided_at = Some(Instant::now());
+
+        let snapshot = match self.current_snapshot(grid_power).await? {
+            Some(snapshot) => snapshot,
+            None => {
+                debug!("Vehicle is asleep, not adjusting charging");
+                return Ok(Some(ChargingDecision::Keep));
+            }
+        };
+
+        let decision = self.decide(&snapshot, grid_power);
+        info!(
+            "Grid power {} W with vehicle at {}%: {:?}",
+            grid_power, snapshot.soc, decision
+        );
+
+        if self.dry_run {
+            return Ok(Some(decision));
+        }
+
+        match decision {
+            ChargingDecision::Keep => {}
+            ChargingDecision::Start { amps } => {
+                self.api.set_charging_amps(amps).await?;
+                self.api.start_charging().await?;
+            }
+            ChargingDecision::SetAmps { amps } => self.api.set_charging_amps(amps).await?,
+            ChargingDecision::Stop => self.api.stop_charging().await?,
+        }
+        self.apply_decision(decision);
+
+        Ok(Some(decision))
+    }
+}
+
+/// Charging commands through the tesla api, for the vehicle with the configured vin.
+pub struct TeslaChargingApi<'a> {
+    client: &'a TeslaApiClient,
+    config: &'a Config,
+    solar_charging_config: &'a SolarChargingConfig,
+    vehicle: Option<(AccountConfig, TeslaVehicle)>,
+}
+
+impl<'a> TeslaChargingApi<'a> {
+    pub fn new(
+        client: &'a TeslaApiClient,
+        config: &'a Config,
+        solar_charging_config: &'a SolarChargingConfig,
+    ) -> Self {
+        Self {
+            client,
+            config,
+            solar_charging_config,
+            vehicle: None,
+        }
+    }
+
+    /// Vehicle with the configured vin with a valid access token for its account.
+    async fn session(
+        &mut self,
+    ) -> Result<(AccountConfig, TeslaAccessToken, TeslaVehicle), Box<dyn Error>> {
+        let client = self.client;
+
+        if let Some((account, vehicle)) = self.vehicle.clone() {
+            let vehicle_id = vehicle.id.to_string();
+            // fetched again for its current state
+            let (token, vehicle) = client
+                .with_cached_account_token(&account, |token| {
+                    let (account, vehicle_id) = (&account, &vehicle_id);
+                    async move {
+                        let vehicle = client.get_vehicle(account, &token, vehicle_id).await?;
+                        Ok((token, vehicle))
+                    }
+                })
+                .await?;
+            return Ok((account, token, vehicle));
+        }
+
+        for account in &self.config.accounts {
+            let (token, vehicles) = client
+                .with_cached_account_token(account, |token| async move {
+                    let vehicles = client.get_vehicles(account, &token).await?;
+                    Ok((token, vehicles))
+                })
+                .await?;
+            let vehicle = vehicles.into_iter().find(|vehicle| {
+                vehicle
+                    .vin
+                    .eq_ignore_ascii_case(&self.solar_charging_config.vin)
+            });
+
+            if let Some(vehicle) = vehicle {
+                self.vehicle = Some((account.clone(), vehicle.clone()));
+                return Ok((account.clone(), token, vehicle));
+            }
+        }
+
+        Err(format!(
+            "Vehicle {} not found in any account",
+            self.solar_charging_config.vin
+        )
+        .into())
+    }
+
+    /// Whether the coordinates are inside the configured geofence, None without coordinates.
+    fn at_geofence(&self, latitude: Option<f64>, longitude: Option<f64>) -> Option<bool> {
+        let geofence = self
+            .config
+            .geofences
+            .iter()
+            .find(|geofence| geofence.location == self.solar_charging_config.geofence);
+
+        match (latitude?, longitude?, geofence) {
+            (latitude, longitude, Some(geofence)) => Some(geofence.contains(latitude, longitude)),
+            _ => Some(false),
+        }
+    }
+}
+
+/// A command that finds the vehicle in the requested state already succeeded.
+fn ignore_already_done(result: Result<(), TeslaApiError>) -> Result<(), Box<dyn Error>> {
+    match result {
+        Err(e) if e.is_already_done() => {
+            debug!("{}, ignoring", e);
+            Ok(())
+        }
+        result => Ok(result?),
+    }
+}
+
+#[async_trait(?Send)]
+impl<'a> ChargingApi for TeslaChargingApi<'a> {
+    async fn snapshot(&mut self) -> Result<Option<ChargingSnapshot>, Box<dyn Error>> {
+        let (account, token, vehicle) = self.session().await?;
+        if vehicle.in_service || vehicle.state != TeslaVehicleState::Online {
+            return Ok(None);
+        }
+
+        let vehicle_data = self
+            .client
+            .get_vehicle_data(&account, &token, &vehicle)
+            .await?;
+        let charge_state = match vehicle_data.charge_state {
+            Some(charge_state) => charge_state,
+            None => return Ok(None),
+        };
+
+        let at_geofence = vehicle_data
+            .drive_state
+            .as_ref()
+            .and_then(|drive_state| self.at_geofence(drive_state.latitude, drive_state.longitude))
+            .unwrap_or(false);
+
+        let charging = charge_state.charging_state == "Charging";
+        let watts_per_amp = match (charge_state.charger_voltage, charge_state.charger_phases) {
+            (Some(voltage), Some(phases))
+                if charging && voltage > MIN_PLAUSIBLE_CHARGER_VOLTAGE && phases > 0.0 =>
+            {
+                voltage * phases
+            }
+            _ => self.solar_charging_config.voltage * self.solar_charging_config.phases as f64,
+        };
+
+        Ok(Some(ChargingSnapshot {
+            plugged_in: charge_state.charge_port_latch == "Engaged",
+            at_geofence,
+            charging,
+            soc: charge_state.battery_level,
+            charge_limit_soc: charge_state.charge_limit_soc,
+            amps: charge_state.charge_current_request.unwrap_or(0.0) as u32,
+            watts_per_amp,
+        }))
+    }
+
+    async fn refresh(
+        &mut self,
+        snapshot: &ChargingSnapshot,
+    ) -> Result<Option<ChargingSnapshot>, Box<dyn Error>> {
+        let (account, token, vehicle) = self.session().await?;
+        if vehicle.in_service || vehicle.state != TeslaVehicleState::Online {
+            return Ok(None);
+        }
+
+        let streaming_data = match self
+            .client
+            .get_telemetry(&vehicle.vin)
+            .and_then(|telemetry| telemetry.streaming_data())
+        {
+            Some(streaming_data) => streaming_data,
+            None => match self
+                .client
+                .get_streaming_data(&account, &token, &vehicle)
+                .await
+            {
+                Ok(streaming_data) => streaming_data,
+                Err(e) if e.is_vehicle_unavailable() => return Ok(None),
+                Err(e) => return Err(e.into()),
+            },
+        };
+
+        Ok(Some(ChargingSnapshot {
+            at_geofence: self
+                .at_geofence(streaming_data.latitude, streaming_data.longitude)
+                .unwrap_or(snapshot.at_geofence),
+            // power is only drawn while parked when charging
+            charging: streaming_data.power > 0.0 && streaming_data.speed == 0.0,
+            soc: streaming_data.soc,
+            ..snapshot.clone()
+        }))
+    }
+
+    async fn wake_up(&mut self) -> Result<(), Box<dyn Error>> {
+        let (account, token, vehicle) = self.session().await?;
+        self.client.wake_up(&account, &token, &vehicle).await?;
+        Ok(())
+    }
+
+    async fn start_charging(&mut self) -> Result<(), Box<dyn Error>> {
+        let (account, token, vehicle) = self.session().await?;
+        ignore_already_done(self.client.charge_start(&account, &token, &vehicle).await)
+    }
+
+    async fn stop_charging(&mut self) -> Result<(), Box<dyn Error>> {
+        let (account, token, vehicle) = self.session().await?;
+        ignore_already_done(self.client.charge_stop(&account, &token, &vehicle).await)
+    }
+
+    async fn set_charging_amps(&mut self, amps: u32) -> Result<(), Box<dyn Error>> {
+        let (account, token, vehicle) = self.session().await?;
+        ignore_already_done(
+            self.client
+                .set_charging_amps(&account, &token, &vehicle, amps)
+                .await,
+        )
+    }
+}
+
+/// Subscribes to the measurements of the other exporters and adjusts charging until stopped.
+pub async fn run_controller(
+    args: ControllerArgs,
+    tesla_api_client: &TeslaApiClient,
+) -> Result<(), Box<dyn Error>> {
+    let config_client = ConfigClient::new(ConfigClientConfig::from_env()?);
+    let mut config: Config = config_client.read_config_from_file()?;
+    config.set_defaults();
+    config_validation::validate(&config)?;
+
+    let solar_charging_config = config
+        .solar_charging
+        .clone()
+        .ok_or("Config has no solarCharging section")?;
+
+    let nats_host = env::var("NATS_HOST").unwrap_or_else(|_| "jarvis-nats".to_string());
+    let nats_subject =
+        env::var("NATS_SUBJECT").unwrap_or_else(|_| "jarvis-measurements".to_string());
+
+    let connection = nats::connect(&nats_host)?;
+    let subscription = connection.subscribe(&nats_subject)?;
+    info!("Subscribed to nats subject {}", nats_subject);
+
+    // the nats client blocks, so messages are handed over from a thread of its own
+    let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(100);
+    std::thread::spawn(move || {
+        for message in subscription.messages() {
+            if sender.blocking_send(message.data).is_err() {
+                break;
+            }
+        }
+    });
+
+    let api = TeslaChargingApi::new(tesla_api_client, &config, &solar_charging_config);
+    let mut controller = SolarChargingController::new(
+        api,
+        solar_charging_config.clone(),
+        config.battery_capacity_kwh,
+        args.dry_run,
+    );
+
+    while let Some(payload) = receiver.recv().await {
+        if let Err(e) = controller.handle_message(&payload).await {
+            error!("Failed adjusting charging: {}", e);
+        }
+    }
+
+    Err("Nats subscription closed".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, Utc};
+    use jarvis_lib::model::{EntityType, Sample, SampleType};
+
+    fn solar_charging_config() -> SolarChargingConfig {
+        serde_json::from_value(serde_json::json!({
+            "vin": "5YJ3E7EB2KF000000",
+            "gridSampleName": "grid",
+            "minimumSoc": 50.0,
+            "minimumSocDeadline": "07:00"
+        }))
+        .unwrap()
+    }
+
+    fn snapshot() -> ChargingSnapshot {
+        ChargingSnapshot {
+            plugged_in: true,
+            at_geofence: true,
+            charging: false,
+            soc: 60.0,
+            charge_limit_soc: 80.0,
+            amps: 16,
+            watts_per_amp: 690.0,
+        }
+    }
+
+    fn noon() -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2023, 6, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn decide_starts_charging_with_surplus() {
+        // act
+        let decision = decide(&solar_charging_config(), &snapshot(), -5000.0, 75.0, noon());
+
+        assert_eq!(decision, ChargingDecision::Start { amps: 7 });
+    }
+
+    #[test]
+    fn decide_lowers_amps_when_importing() {
+        let snapshot = ChargingSnapshot {
+            charging: true,
+            amps: 10,
+            ..snapshot()
+        };
+
+        // act
+        let decision = decide(&solar_charging_config(), &snapshot, 1000.0, 75.0, noon());
+
+        assert_eq!(decision, ChargingDecision::SetAmps { amps: 8 });
+    }
+
+    #[test]
+    fn decide_stops_charging_without_enough_surplus() {
+        let snapshot = ChargingSnapshot {
+            charging: true,
+            amps: 5,
+            ..snapshot()
+        };
+
+        // act
+        let decision = decide(&solar_charging_config(), &snapshot, 2000.0, 75.0, noon());
+
+        assert_eq!(decision, ChargingDecision::Stop);
+    }
+
+    #[test]
+    fn decide_leaves_vehicle_away_from_geofence_alone() {
+        let snapshot = ChargingSnapshot {
+            at_geofence: false,
+            ..snapshot()
+        };
+
+        // act
+        let decision = decide(&solar_charging_config(), &snapshot, -5000.0, 75.0, noon());
+
+        assert_eq!(decision, ChargingDecision::Keep);
+    }
+
+    #[test]
+    fn decide_charges_from_grid_to_reach_minimum_soc_by_deadline() {
+        let snapshot = ChargingSnapshot {
+            soc: 30.0,
+            ..snapshot()
+        };
+        let five_am = noon().date().and_hms_opt(5, 0, 0).unwrap();
+
+        // act
+        let decision = decide(&solar_charging_config(), &snapshot, 500.0, 75.0, five_am);
+
+        // 15 kWh in 2 hours at 690 W per amp
+        assert_eq!(decision, ChargingDecision::Start { amps: 11 });
+    }
+
+    #[derive(Default)]
+    struct MockChargingApi {
+        snapshot: Option<ChargingSnapshot>,
+        asleep: bool,
+        stays_asleep: bool,
+        requests: Vec<String>,
+        commands: Vec<String>,
+    }
+
+    #[async_trait(?Send)]
+    impl ChargingApi for MockChargingApi {
+        async fn snapshot(&mut self) -> Result<Option<ChargingSnapshot>, Box<dyn Error>> {
+            if self.asleep {
+                return Ok(None);
+            }
+            self.requests.push("vehicle_data".into());
+            Ok(self.snapshot.clone())
+        }
+
+        async fn refresh(
+            &mut self,
+            _snapshot: &ChargingSnapshot,
+        ) -> Result<Option<ChargingSnapshot>, Box<dyn Error>> {
+            if self.asleep {
+                return Ok(None);
+            }
+            self.requests.push("stream".into());
+            Ok(self.snapshot.clone())
+        }
+
+        async fn wake_up(&mut self) -> Result<(), Box<dyn Error>> {
+            self.requests.push("wake_up".into());
+            self.asleep = self.stays_asleep;
+            Ok(())
+        }
+
+        async fn start_charging(&mut self) -> Result<(), Box<dyn Error>> {
+            self.commands.push("charge_start".into());
+            Ok(())
+        }
+
+        async fn stop_charging(&mut self) -> Result<(), Box<dyn Error>> {
+            self.commands.push("charge_stop".into());
+            Ok(())
+        }
+
+        async fn set_charging_amps(&mut self, amps: u32) -> Result<(), Box<dyn Error>> {
+            self.commands.push(format!("set_charging_amps {}", amps));
+            Ok(())
+        }
+    }
+
+    fn grid_message(sample_name: &str, value: f64) -> Vec<u8> {
+        serde_json::to_vec(&Measurement {
+            id: "id".into(),
+            source: "jarvis-p1-exporter".into(),
+            location: "My Home".into(),
+            samples: vec![Sample {
+                entity_type: EntityType::Device,
+                entity_name: "jarvis-p1-exporter".into(),
+                sample_type: SampleType::ElectricityConsumption,
+                sample_name: sample_name.into(),
+                metric_type: MetricType::Gauge,
+                value,
+            }],
+            measured_at_time: Utc::now(),
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn handle_message_sends_commands_for_grid_power() {
+        let api = MockChargingApi {
+            snapshot: Some(ChargingSnapshot {
+                soc: 70.0,
+                ..snapshot()
+            }),
+            ..Default::default()
+        };
+        let mut controller =
+            SolarChargingController::new(api, solar_charging_config(), 75.0, false);
+
+        // act
+        let other = controller
+            .handle_message(&grid_message("solar", -5000.0))
+            .await
+            .unwrap();
+        let decision = controller
+            .handle_message(&grid_message("grid", -5000.0))
+            .await
+            .unwrap();
+        let throttled = controller
+            .handle_message(&grid_message("grid", -5000.0))
+            .await
+            .unwrap();
+
+        assert_eq!(other, None);
+        assert_eq!(decision, Some(ChargingDecision::Start { amps: 7 }));
+        assert_eq!(throttled, None);
+        assert_eq!(
+            controller.api.commands,
+            vec![
+                "set_charging_amps 7".to_string(),
+                "charge_start".to_string()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn handle_message_streams_instead_of_polling_while_nothing_changes() {
+        let api = MockChargingApi {
+            snapshot: Some(ChargingSnapshot {
+                soc: 70.0,
+                ..snapshot()
+            }),
+            ..Default::default()
+        };
+        let config = SolarChargingConfig {
+            interval_seconds: 0,
+            ..solar_charging_config()
+        };
+        let mut controller = SolarChargingController::new(api, config, 75.0, false);
+
+        // act
+        for _ in 0..3 {
+            controller
+                .handle_message(&grid_message("grid", 500.0))
+                .await
+                .unwrap();
+        }
+        let decision = controller
+            .handle_message(&grid_message("grid", -5000.0))
+            .await
+            .unwrap();
+
+        assert_eq!(decision, Some(ChargingDecision::Start { amps: 7 }));
+        assert_eq!(
+            controller.api.requests,
+            vec!["vehicle_data", "stream", "stream", "stream", "vehicle_data"]
+        );
+    }
+
+    #[tokio::test]
+    async fn handle_message_wakes_vehicle_only_to_start_charging() {
+        let api = MockChargingApi {
+            snapshot: Some(ChargingSnapshot {
+                soc: 70.0,
+                ..snapshot()
+            }),
+            ..Default::default()
+        };
+        let config = SolarChargingConfig {
+            interval_seconds: 0,
+            ..solar_charging_config()
+        };
+        let mut controller = SolarChargingController::new(api, config, 75.0, false);
+        controller
+            .handle_message(&grid_message("grid", 500.0))
+            .await
+            .unwrap();
+        controller.api.asleep = true;
+
+        // act
+        let importing = controller
+            .handle_message(&grid_message("grid", 500.0))
+            .await
+            .unwrap();
+        let exporting = controller
+            .handle_message(&grid_message("grid", -5000.0))
+            .await
+            .unwrap();
+
+        assert_eq!(importing, Some(ChargingDecision::Keep));
+        assert_eq!(exporting, Some(ChargingDecision::Start { amps: 7 }));
+        assert_eq!(
+            controller.api.requests,
+            vec!["vehicle_data", "wake_up", "vehicle_data"]
+        );
+    }
+
+    #[tokio::test]
+    async fn handle_message_does_not_wake_vehicle_in_dry_run() {
+        let api = MockChargingApi {
+            snapshot: Some(snapshot()),
+            asleep: true,
+            ..Default::default()
+        };
+        let mut controller = SolarChargingController::new(api, solar_charging_config(), 75.0, true);
+
+        // act
+        let decision = controller
+            .handle_message(&grid_message("grid", -5000.0))
+            .await
+            .unwrap();
+
+        assert_eq!(decision, Some(ChargingDecision::Keep));
+        assert!(controller.api.requests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn handle_message_backs_off_waking_vehicle_that_stays_asleep() {
+        let api = MockChargingApi {
+            snapshot: Some(snapshot()),
+            asleep: true,
+            stays_asleep: true,
+            ..Default::default()
+        };
+        let config = SolarChargingConfig {
+            interval_seconds: 0,
+            ..solar_charging_config()
+        };
+        let mut controller = SolarChargingController::new(api, config, 75.0, false);
+
+        // act
+        for _ in 0..3 {
+            controller
+                .handle_message(&grid_message("grid", -5000.0))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(controller.api.requests, vec!["wake_up"]);
+        assert!(controller.api.commands.is_empty());
+    }
+}
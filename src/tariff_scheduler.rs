@@ -0,0 +1,271 @@
+use chrono::{DateTime, Duration, Local, NaiveTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use tracing::debug;
+
+/// Price of electricity during a period, usually an hour of the day-ahead market.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PricePeriod {
+    pub start: DateTime<Utc>,
+    /// an hour after `start` when not set
+    #[serde(default)]
+    pub end: Option<DateTime<Utc>>,
+    /// per kWh
+    pub price: f64,
+}
+
+impl PricePeriod {
+    fn end(&self) -> DateTime<Utc> {
+        self.end.unwrap_or(self.start + Duration::hours(1))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedPeriod {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub price: f64,
+    /// Wh charged in this period
+    pub energy: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChargePlan {
+    /// ordered by start
+    pub periods: Vec<PlannedPeriod>,
+    /// Wh that still has to be charged
+    pub energy: f64,
+    /// Wh the known prices leave unplanned before the departure
+    pub unplanned_energy: f64,
+    pub expected_cost: f64,
+}
+
+impl ChargePlan {
+    pub fn is_charging_at(&self, time: DateTime<Utc>) -> bool {
+        self.periods
+            .iter()
+            .any(|period| period.start <= time && time < period.end)
+    }
+}
+
+/// Reads prices from an http endpoint or a file, as a json array of periods or csv with a
+/// `start,price` header.
+pub async fn load_prices(
+    source: &str,
+    http_client: &reqwest::Client,
+) -> Result<Vec<PricePeriod>, Box<dyn Error>> {
+    let content = if source.starts_with("http://") || source.starts_with("https://") {
+        http_client
+            .get(source)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?
+    } else {
+        fs::read_to_string(source)?
+    };
+
+    parse_prices(&content)
+}
+
+fn parse_prices(content: &str) -> Result<Vec<PricePeriod>, Box<dyn Error>> {
+    let content = content.trim();
+    if content.starts_with('[') {
+        return Ok(serde_json::from_str(content)?);
+    }
+
+    let mut lines = content.lines();
+    let header: Vec<String> = lines
+        .next()
+        .ok_or("Price csv is empty")?
+        .split(',')
+        .map(|column| column.trim().to_lowercase())
+        .collect();
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|column| column == name)
+            .ok_or(format!("Price csv has no {} column", name))
+    };
+    let (start_column, price_column) = (column("start")?, column("price")?);
+    let end_column = column("end").ok();
+
+    let mut prices = vec![];
+    for line in lines.filter(|line| !line.trim().is_empty()) {
+        let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+        let field = |index: usize| {
+            fields
+                .get(index)
+                .copied()
+                .ok_or(format!("Price csv line {} has too few fields", line))
+        };
+
+        prices.push(PricePeriod {
+            start: DateTime::parse_from_rfc3339(field(start_column)?)?.with_timezone(&Utc),
+            end: match end_column {
+                Some(end_column) => {
+                    Some(DateTime::parse_from_rfc3339(field(end_column)?)?.with_timezone(&Utc))
+                }
+                None => None,
+            },
+            price: field(price_column)?.parse()?,
+        });
+    }
+
+    Ok(prices)
+}
+
+/// Next occurrence of a local time of day like `07:30`.
+pub fn next_departure(
+    departure_time: &str,
+    now: DateTime<Utc>,
+) -> Result<DateTime<Utc>, Box<dyn Error>> {
+    let time = NaiveTime::parse_from_str(departure_time, "%H:%M")?;
+    let local_now = now.with_timezone(&Local).naive_local();
+
+    let mut departure = local_now.date().and_time(time);
+    if departure <= local_now {
+        departure += Duration::days(1);
+    }
+
+    Local
+        .from_local_datetime(&departure)
+        .earliest()
+        .map(|departure| departure.with_timezone(&Utc))
+        .ok_or_else(|| format!("Departure time {} doesn't exist today", departure_time).into())
+}
+
+/// Picks the cheapest periods between now and the departure to charge `energy` Wh at `power` W.
+pub fn plan_charging(
+    prices: &[PricePeriod],
+    now: DateTime<Utc>,
+    departure: DateTime<Utc>,
+    energy: f64,
+    power: f64,
+) -> ChargePlan {
+    let mut candidates: Vec<PlannedPeriod> = prices
+        .iter()
+        .filter_map(|period| {
+            // only the part of a period that's left before the departure can be used
+            let start = period.start.max(now);
+            let end = period.end().min(departure);
+            if end <= start {
+                return None;
+            }
+            let hours = (end - start).num_seconds() as f64 / 3600.0;
+
+            Some(PlannedPeriod {
+                start,
+                end,
+                price: period.price,
+                energy: power * hours,
+            })
+        })
+        .collect();
+    candidates.sort_by(|a, b| {
+        a.price
+            .partial_cmp(&b.price)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(a.start.cmp(&b.start))
+    });
+
+    let mut plan = ChargePlan {
+        energy: energy.max(0.0),
+        ..Default::default()
+    };
+    let mut remaining = plan.energy;
+    for mut candidate in candidates {
+        if remaining <= 0.0 {
+            break;
+        }
+        candidate.energy = candidate.energy.min(remaining);
+        remaining -= candidate.energy;
+        plan.expected_cost += candidate.energy / 1000.0 * candidate.price;
+        plan.periods.push(candidate);
+    }
+    plan.unplanned_energy = remaining.max(0.0);
+    plan.periods.sort_by_key(|period| period.start);
+
+    debug!("Planned charging: {:?}", plan);
+
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hour(hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 6, 1, hour, 0, 0).unwrap()
+    }
+
+    fn prices() -> Vec<PricePeriod> {
+        [0.30, 0.10, 0.25, 0.05, 0.20]
+            .iter()
+            .enumerate()
+            .map(|(i, price)| PricePeriod {
+                start: hour(i as u32),
+                end: None,
+                price: *price,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn plan_charging_picks_cheapest_hours_before_departure() {
+        // act
+        let plan = plan_charging(&prices(), hour(0), hour(4), 15000.0, 11000.0);
+
+        assert_eq!(plan.periods.len(), 2);
+        assert_eq!(plan.periods[0].start, hour(1));
+        assert_eq!(plan.periods[0].energy, 4000.0);
+        assert_eq!(plan.periods[1].start, hour(3));
+        assert_eq!(plan.periods[1].energy, 11000.0);
+        assert!((plan.expected_cost - 0.95).abs() < 1e-9);
+        assert_eq!(plan.unplanned_energy, 0.0);
+        assert!(plan.is_charging_at(hour(1) + Duration::minutes(30)));
+        assert!(!plan.is_charging_at(hour(2)));
+    }
+
+    #[test]
+    fn plan_charging_uses_remainder_of_current_hour_and_reports_shortfall() {
+        let now = hour(1) + Duration::minutes(30);
+
+        // act
+        let plan = plan_charging(&prices(), now, hour(2), 11000.0, 11000.0);
+
+        assert_eq!(plan.periods.len(), 1);
+        assert_eq!(plan.periods[0].start, now);
+        assert_eq!(plan.periods[0].energy, 5500.0);
+        assert_eq!(plan.unplanned_energy, 5500.0);
+    }
+
+    #[test]
+    fn parse_prices_reads_json_and_csv() {
+        let json = r#"[{"start": "2023-06-01T00:00:00Z", "price": 0.3}]"#;
+        let csv = "start,price\n2023-06-01T00:00:00Z,0.3\n";
+
+        // act
+        let from_json = parse_prices(json).unwrap();
+        let from_csv = parse_prices(csv).unwrap();
+
+        assert_eq!(from_json, vec![prices()[0].clone()]);
+        assert_eq!(from_csv, from_json);
+    }
+
+    #[tokio::test]
+    async fn load_prices_reads_file() {
+        // act
+        let prices = load_prices("test-data/prices.csv", &reqwest::Client::new())
+            .await
+            .unwrap();
+
+        assert_eq!(prices.len(), 24);
+        assert_eq!(prices[0].start, hour(0));
+    }
+}
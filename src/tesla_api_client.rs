@@ -1,18 +1,47 @@
+use crate::battery::BatteryObservation;
+use crate::cassette::{CassetteMode, FrameDirection};
+use crate::event_client::EventClient;
+use crate::fleet_telemetry::{TelemetryStore, VehicleTelemetry};
+use crate::metrics::Metrics;
 use crate::model::{
-    Config, TeslaAccessToken, TeslaAccessTokenRequest, TeslaApiResponse, TeslaStreamingApiMessage,
-    TeslaVehicle, TeslaVehicleData, TeslaVehicleState, TeslaVehicleStreamingData,
+    AccountConfig, AccountState, Config, ExporterState, TariffChargingConfig, TeslaAccessToken,
+    TeslaAccessTokenRequest, TeslaApiResponse, TeslaAuthorizationCodeRequest, TeslaBackend,
+    TeslaCommandResult, TeslaStreamingApiMessage, TeslaVehicle, TeslaVehicleData,
+    TeslaVehicleState, TeslaVehicleStreamingData, VehicleState, VehicleStatus,
 };
-use chrono::Utc;
+use crate::mqtt_client::MqttClient;
+use crate::output_sink;
+use crate::rate_limiter::{RateLimiter, MAX_RETRY_AFTER};
+use crate::signed_command::{self, CommandKey, CommandOutcome, Domain, Session};
+use crate::tariff_scheduler;
+use crate::tesla_api_error::TeslaApiError;
+use crate::units::Units;
+use crate::vehicle_events;
+use crate::vehicle_state_client::VehicleStateClient;
+use crate::webhook_client::WebhookClient;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use futures::{stream, SinkExt, StreamExt, TryStreamExt};
 use jarvis_lib::model::{EntityType, MetricType, Sample, SampleType};
 use jarvis_lib::{measurement_client::MeasurementClient, model::Measurement};
-use reqwest::Url;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{RequestBuilder, Response, StatusCode};
 use retry::delay::{jitter, Exponential};
-use retry::retry;
-use serde_json::Value;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::env;
 use std::error::Error;
-use std::time::Instant;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tokio::time::{sleep, timeout};
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
 use tracing::{debug, error, info, warn};
-use tungstenite::{connect, Message};
 use uuid::Uuid;
 
 const RETRY_INTERVAL_MS: u64 = 100;
@@ -20,357 +49,1947 @@ const RETRY_FACTOR: f64 = 2.0;
 const RETRY_TAKES: usize = 3;
 const LOCATION_OTHER: &str = "Other";
 const DEFAULT_DISPLAY_NAME: &str = "Unknown";
-pub struct TeslaApiClient {}
+// availability when polling the vehicle failed, next to 1 awake, 0 asleep, -1 offline and -2 in service
+const AVAILABILITY_ERROR: f64 = -3.0;
+const CHARGING_STATE_COMPLETE: &str = "Complete";
+// refresh a cached access token before it expires halfway through a run
+const ACCESS_TOKEN_EXPIRY_MARGIN_SECONDS: i64 = 300;
+const WAKE_UP_POLL_INTERVAL: Duration = Duration::from_secs(2);
+// fleet telemetry older than this means the vehicle stopped streaming, so the streaming api is used
+const TELEMETRY_MAX_AGE_MINUTES: i64 = 10;
+const FLEET_API_VEHICLE_DATA_ENDPOINTS: &str =
+    "charge_state;climate_state;drive_state;gui_settings;location_data;vehicle_state";
+
+pub struct TeslaApiClientConfig {
+    vehicle_state_client: VehicleStateClient,
+    mqtt_client: Option<MqttClient>,
+    event_client: Option<EventClient>,
+    telemetry_store: Option<Arc<TelemetryStore>>,
+    /// records the api traffic into a cassette file, or replays one instead of calling the api
+    cassette: Option<CassetteMode>,
+    http_client: reqwest::Client,
+    request_timeout: Duration,
+    vehicle_data_timeout: Duration,
+    streaming_timeout: Duration,
+    wake_up_timeout: Duration,
+}
+
+/// timeouts for the calls to the tesla api
+#[derive(Debug, Clone, Copy)]
+pub struct TeslaApiTimeouts {
+    pub connect: Duration,
+    pub request: Duration,
+    /// vehicle data takes longer, because the api has to reach the car
+    pub vehicle_data: Duration,
+    pub streaming: Duration,
+    pub wake_up: Duration,
+}
+
+impl TeslaApiTimeouts {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            connect: duration_from_env("TESLA_API_CONNECT_TIMEOUT_SECONDS", 10)?,
+            request: duration_from_env("TESLA_API_REQUEST_TIMEOUT_SECONDS", 20)?,
+            vehicle_data: duration_from_env("TESLA_API_VEHICLE_DATA_TIMEOUT_SECONDS", 30)?,
+            streaming: duration_from_env("TESLA_API_STREAMING_TIMEOUT_SECONDS", 30)?,
+            wake_up: duration_from_env("TESLA_API_WAKE_UP_TIMEOUT_SECONDS", 60)?,
+        })
+    }
+}
+
+impl TeslaApiClientConfig {
+    pub fn new(
+        vehicle_state_client: VehicleStateClient,
+        mqtt_client: Option<MqttClient>,
+        event_client: Option<EventClient>,
+        telemetry_store: Option<Arc<TelemetryStore>>,
+        cassette: Option<CassetteMode>,
+        timeouts: TeslaApiTimeouts,
+    ) -> Result<Self, Box<dyn Error>> {
+        debug!("TeslaApiClientConfig::new(timeouts: {:?})", timeouts);
+
+        // one client for all requests, so connections are pooled instead of doing a tls handshake per call
+        let http_client = reqwest::Client::builder()
+            .connect_timeout(timeouts.connect)
+            .build()?;
+
+        Ok(Self {
+            vehicle_state_client,
+            mqtt_client,
+            event_client,
+            telemetry_store,
+            cassette,
+            http_client,
+            request_timeout: timeouts.request,
+            vehicle_data_timeout: timeouts.vehicle_data,
+            streaming_timeout: timeouts.streaming,
+            wake_up_timeout: timeouts.wake_up,
+        })
+    }
+
+    pub fn from_env(
+        vehicle_state_client: VehicleStateClient,
+        mqtt_client: Option<MqttClient>,
+        event_client: Option<EventClient>,
+        telemetry_store: Option<Arc<TelemetryStore>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::new(
+            vehicle_state_client,
+            mqtt_client,
+            event_client,
+            telemetry_store,
+            CassetteMode::from_env()?,
+            TeslaApiTimeouts::from_env()?,
+        )
+    }
+}
+
+fn duration_from_env(key: &str, default_seconds: u64) -> Result<Duration, Box<dyn Error>> {
+    let seconds: u64 = env::var(key)
+        .unwrap_or_else(|_| default_seconds.to_string())
+        .parse()?;
+
+    Ok(Duration::from_secs(seconds))
+}
+
+/// the measurement of a single vehicle together with its updated state
+type VehicleMeasurementResult = Result<(Measurement, VehicleState), TeslaApiError>;
+
+pub struct TeslaApiClient {
+    config: TeslaApiClientConfig,
+    /// rate limiters by account name, as the api limits each account separately
+    rate_limiters: Mutex<HashMap<String, Arc<RateLimiter>>>,
+    metrics: Arc<Metrics>,
+    /// signed command sessions by vin and domain, kept for the lifetime of the process
+    command_sessions: Mutex<HashMap<String, Session>>,
+}
 
 impl MeasurementClient<Config> for TeslaApiClient {
     fn get_measurements(
         &self,
         config: Config,
         last_measurements: Option<Vec<Measurement>>,
+    ) -> Result<Vec<Measurement>, Box<dyn Error>> {
+        // the exporter service calls this synchronously from within the tokio runtime
+        tokio::task::block_in_place(|| {
+            Handle::current().block_on(self.get_measurements_async(config, last_measurements))
+        })
+    }
+}
+
+impl TeslaApiClient {
+    pub fn new(config: TeslaApiClientConfig) -> Self {
+        Self {
+            config,
+            rate_limiters: Mutex::new(HashMap::new()),
+            metrics: Arc::new(Metrics::new()),
+            command_sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn rate_limiter(&self, account: &AccountConfig) -> Arc<RateLimiter> {
+        self.rate_limiters
+            .lock()
+            .unwrap()
+            .entry(account.name.clone())
+            .or_insert_with(|| Arc::new(RateLimiter::new()))
+            .clone()
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub fn telemetry_store(&self) -> Option<Arc<TelemetryStore>> {
+        self.config.telemetry_store.clone()
+    }
+
+    /// Fleet telemetry of the vehicle, if it's still streaming it.
+    pub fn get_telemetry(&self, vin: &str) -> Option<VehicleTelemetry> {
+        self.config
+            .telemetry_store
+            .as_ref()
+            .and_then(|store| store.get(vin, chrono::Duration::minutes(TELEMETRY_MAX_AGE_MINUTES)))
+    }
+
+    async fn get_measurements_async(
+        &self,
+        config: Config,
+        last_measurements: Option<Vec<Measurement>>,
     ) -> Result<Vec<Measurement>, Box<dyn Error>> {
         let mut measurements: Vec<Measurement> = vec![];
 
-        let token = self.get_access_token(&config)?;
+        // in daemon mode the client outlives a single run
+        self.rate_limiters.lock().unwrap().clear();
 
-        for vehicle_id in config.vehicle_ids {
-            // let vehicles = self.get_vehicles(&token)?;
-            // for vehicle in vehicles {
-            let vehicle = self.get_vehicle(&token, &vehicle_id)?;
-            debug!("State for vehicle {}: {:?}", vehicle.id, vehicle.state);
+        let mut state = self
+            .config
+            .vehicle_state_client
+            .read_state()
+            .unwrap_or_else(|e| {
+                warn!("Failed reading vehicle state, starting without: {}", e);
+                Default::default()
+            });
 
-            let (last_location, last_charger_power, last_charge_energy_added, last_odometer) =
-                self.get_last_values(&last_measurements, &vehicle);
+        let mut account_states = self
+            .config
+            .vehicle_state_client
+            .read_account_states()
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Failed reading account tokens, starting without: {}", e);
+                Default::default()
+            });
 
-            let (location, charger_power, charge_energy_added, odometer, availability) = if vehicle
-                .in_service
-                || vehicle.state == TeslaVehicleState::Asleep
-                || vehicle.state == TeslaVehicleState::Offline
-            {
-                info!("Vehicle is asleep, offline or in service");
+        // an account failing to authenticate shouldn't stop the vehicles of other accounts
+        let mut sessions: Vec<(AccountConfig, TeslaAccessToken)> = vec![];
+        let mut failed_vehicle_ids: Vec<String> = vec![];
+        for account in &config.accounts {
+            let account = match account.with_resolved_refresh_token().await {
+                Ok(account) => account,
+                Err(e) => {
+                    error!(
+                        "Failed reading refresh token for account {}: {}",
+                        account.name, e
+                    );
+                    failed_vehicle_ids.extend(account.vehicle_ids.iter().cloned());
+                    continue;
+                }
+            };
 
-                let availability = if vehicle.in_service {
-                    -2.0
-                } else if vehicle.state == TeslaVehicleState::Offline {
-                    -1.0
-                } else {
-                    0.0
-                };
+            let account_state = account_states.entry(account.name.clone()).or_default();
+
+            match self.get_account_token(&account, account_state).await {
+                Ok(token) => sessions.push((account, token)),
+                Err(e) => {
+                    error!(
+                        "Failed getting access token for account {}: {}",
+                        account.name, e
+                    );
+                    self.metrics.record_token_refresh_failure(&account.name);
+                    failed_vehicle_ids.extend(account.vehicle_ids.iter().cloned());
+                }
+            }
+        }
 
-                // vehicle is asleep, offline or in service, return last values
-                (
-                    last_location,
-                    0.0,
-                    last_charge_energy_added,
-                    last_odometer,
-                    availability,
+        let config = &config;
+        let last_measurements = &last_measurements;
+        let vehicles = &state.vehicles;
+
+        // poll vehicles concurrently, but in order so measurements keep the order of the config
+        let mut results: Vec<(String, VehicleMeasurementResult)> =
+            stream::iter(sessions.iter().flat_map(|(account, token)| {
+                account.vehicle_ids.iter().map(move |vehicle_id| {
+                    let vehicle_state = vehicles.get(vehicle_id).cloned().unwrap_or_default();
+
+                    async move {
+                        let result = self
+                            .get_vehicle_measurement(
+                                config,
+                                account,
+                                token,
+                                vehicle_id,
+                                last_measurements,
+                                vehicle_state,
+                            )
+                            .await;
+
+                        (vehicle_id.clone(), result)
+                    }
+                })
+            }))
+            .buffered(config.max_concurrent_vehicles)
+            .collect()
+            .await;
+
+        // a cached access token can be revoked before it expires, so refresh it once and poll the
+        // vehicles of the account again instead of failing them until the token expires
+        for (account, token) in &mut sessions {
+            let is_unauthorized = |(vehicle_id, result): &(String, VehicleMeasurementResult)| {
+                account.vehicle_ids.contains(vehicle_id)
+                    && matches!(result, Err(TeslaApiError::Unauthorized { .. }))
+            };
+            if !results.iter().any(is_unauthorized) {
+                continue;
+            }
+
+            warn!(
+                "Access token for account {} was rejected, refreshing it",
+                account.name
+            );
+            let account_state = account_states.entry(account.name.clone()).or_default();
+            account_state.access_token = None;
+            account_state.access_token_expires_at = None;
+            *token = match self.get_account_token(account, account_state).await {
+                Ok(token) => token,
+                Err(e) => {
+                    error!(
+                        "Failed refreshing access token for account {}: {}",
+                        account.name, e
+                    );
+                    self.metrics.record_token_refresh_failure(&account.name);
+                    continue;
+                }
+            };
+
+            for entry in results.iter_mut().filter(|entry| is_unauthorized(entry)) {
+                let vehicle_state = vehicles.get(&entry.0).cloned().unwrap_or_default();
+                entry.1 = self
+                    .get_vehicle_measurement(
+                        config,
+                        account,
+                        token,
+                        &entry.0,
+                        last_measurements,
+                        vehicle_state,
+                    )
+                    .await;
+            }
+        }
+
+        for (vehicle_id, result) in results {
+            match result {
+                Ok((measurement, vehicle_state)) => {
+                    measurements.push(measurement);
+                    let previous_vehicle_state =
+                        state.vehicles.insert(vehicle_id.clone(), vehicle_state);
+
+                    if let Some(status) = &state.vehicles[&vehicle_id].status {
+                        let previous_status = previous_vehicle_state
+                            .as_ref()
+                            .and_then(|s| s.status.as_ref());
+                        self.publish_vehicle_status(
+                            config,
+                            status,
+                            previous_status,
+                            &mut state.webhooks_sent_at,
+                        )
+                        .await;
+                    }
+                }
+                Err(e) => {
+                    error!(
+                        "Failed getting measurement for vehicle {}: {}",
+                        vehicle_id, e
+                    );
+                    self.push_last_known_measurement(
+                        &mut measurements,
+                        &state,
+                        last_measurements,
+                        &vehicle_id,
+                    );
+                }
+            }
+        }
+
+        for vehicle_id in failed_vehicle_ids {
+            self.push_last_known_measurement(
+                &mut measurements,
+                &state,
+                last_measurements,
+                &vehicle_id,
+            );
+        }
+
+        if let Err(e) = self.config.vehicle_state_client.store_state(&state).await {
+            warn!("Failed storing vehicle state: {}", e);
+        }
+        if let Err(e) = self
+            .config
+            .vehicle_state_client
+            .store_account_states(&account_states)
+            .await
+        {
+            warn!("Failed storing account tokens: {}", e);
+        }
+
+        if let Some(mqtt_client) = &self.config.mqtt_client {
+            mqtt_client.flush(Duration::from_secs(10)).await;
+        }
+
+        self.write_to_sinks(config, &measurements).await;
+
+        for (account_name, rate_limiter) in self.rate_limiters.lock().unwrap().iter() {
+            let stats = rate_limiter.stats();
+            info!(
+                "Sent {} requests for account {}, of which {} retries; rate limited {} times",
+                stats.requests, account_name, stats.retries, stats.rate_limited
+            );
+        }
+
+        Ok(measurements)
+    }
+
+    /// Publishes the status to mqtt, the transitions since the previous status as events and
+    /// notifies the webhooks of matching rules.
+    async fn publish_vehicle_status(
+        &self,
+        config: &Config,
+        status: &VehicleStatus,
+        previous_status: Option<&VehicleStatus>,
+        webhooks_sent_at: &mut HashMap<String, DateTime<Utc>>,
+    ) {
+        if let Some(mqtt_client) = &self.config.mqtt_client {
+            if let Err(e) = mqtt_client.publish_vehicle(status, previous_status).await {
+                warn!("Failed publishing vehicle {} to mqtt: {}", status.vin, e);
+            }
+        }
+
+        let events = previous_status
+            .map(|previous_status| vehicle_events::detect_events(previous_status, status))
+            .unwrap_or_default();
+
+        if let Some(event_client) = &self.config.event_client {
+            if let Err(e) = event_client.publish(&events) {
+                warn!("Failed publishing events of vehicle {}: {}", status.vin, e);
+            }
+        }
+
+        if !config.webhooks.is_empty() && !self.config.vehicle_state_client.read_only() {
+            WebhookClient::new(self.config.http_client.clone())
+                .notify(
+                    &config.webhooks,
+                    webhooks_sent_at,
+                    status,
+                    previous_status,
+                    &events,
                 )
+                .await;
+        }
+    }
+
+    /// A failing sink is logged, so it doesn't hold back publishing to nats.
+    async fn write_to_sinks(&self, config: &Config, measurements: &[Measurement]) {
+        if self.config.vehicle_state_client.read_only() {
+            debug!("Not writing to output sinks, state is read only");
+            return;
+        }
+
+        let sinks =
+            match output_sink::sinks_from_config(&config.outputs, &self.config.http_client).await {
+                Ok(sinks) => sinks,
+                Err(e) => {
+                    error!("Failed creating output sinks: {}", e);
+                    return;
+                }
+            };
+
+        for sink in sinks {
+            if let Err(e) = sink.write(measurements).await {
+                error!("Failed writing measurements to {} sink: {}", sink.name(), e);
+            }
+        }
+    }
+
+    /// Keeps the last known values so a vehicle that can't be polled doesn't disappear from graphs.
+    fn push_last_known_measurement(
+        &self,
+        measurements: &mut Vec<Measurement>,
+        state: &ExporterState,
+        last_measurements: &Option<Vec<Measurement>>,
+        vehicle_id: &str,
+    ) {
+        let display_name = state
+            .vehicles
+            .get(vehicle_id)
+            .and_then(|vehicle_state| vehicle_state.display_name.clone());
+
+        match self.get_last_known_measurement(last_measurements, display_name) {
+            Some(measurement) => measurements.push(measurement),
+            None => warn!("No last known measurement for vehicle {}", vehicle_id),
+        }
+
+        if let Some(status) = state
+            .vehicles
+            .get(vehicle_id)
+            .and_then(|vehicle_state| vehicle_state.status.as_ref())
+        {
+            self.metrics.record_vehicle(&VehicleStatus {
+                availability: AVAILABILITY_ERROR,
+                ..status.clone()
+            });
+        }
+    }
+
+    /// Reuses the access token of an earlier run while it's valid and continues with the refresh
+    /// token tesla handed out last, falling back to the configured one if that got revoked.
+    async fn get_account_token(
+        &self,
+        account: &AccountConfig,
+        account_state: &mut AccountState,
+    ) -> Result<TeslaAccessToken, TeslaApiError> {
+        let fingerprint = account.refresh_token_fingerprint();
+        if account_state.configured_refresh_token_fingerprint.as_ref() != Some(&fingerprint) {
+            // the configured refresh token was replaced, forget tokens obtained with the old one
+            *account_state = AccountState {
+                configured_refresh_token_fingerprint: Some(fingerprint),
+                ..Default::default()
+            };
+        }
+
+        if let (Some(access_token), Some(expires_at)) = (
+            &account_state.access_token,
+            account_state.access_token_expires_at,
+        ) {
+            let expires_in = (expires_at - Utc::now()).num_seconds();
+            if expires_in > ACCESS_TOKEN_EXPIRY_MARGIN_SECONDS {
+                debug!("Reusing access token for account {}", account.name);
+                return Ok(TeslaAccessToken {
+                    access_token: access_token.clone(),
+                    token_type: "Bearer".into(),
+                    expires_in: expires_in as usize,
+                    refresh_token: None,
+                });
+            }
+        }
+
+        let token = match account_state.refresh_token.clone() {
+            Some(rotated_refresh_token) => {
+                match self.get_access_token(account, &rotated_refresh_token).await {
+                    Err(TeslaApiError::Unauthorized { body }) => {
+                        warn!(
+                            "Rotated refresh token for account {} was rejected, falling back to the configured one: {}",
+                            account.name, body
+                        );
+                        account_state.refresh_token = None;
+                        self.get_access_token(account, &account.refresh_token)
+                            .await?
+                    }
+                    result => result?,
+                }
+            }
+            None => {
+                self.get_access_token(account, &account.refresh_token)
+                    .await?
+            }
+        };
+
+        account_state.access_token = Some(token.access_token.clone());
+        account_state.access_token_expires_at =
+            Some(Utc::now() + chrono::Duration::seconds(token.expires_in as i64));
+        if let Some(refresh_token) = &token.refresh_token {
+            if account_state.refresh_token.as_ref() != Some(refresh_token) {
+                info!("Storing rotated refresh token for account {}", account.name);
+            }
+            account_state.refresh_token = Some(refresh_token.clone());
+        }
+
+        Ok(token)
+    }
+
+    /// Access token for processes running next to the exporter, sharing the tokens it stored.
+    pub async fn get_cached_account_token(
+        &self,
+        account: &AccountConfig,
+    ) -> Result<TeslaAccessToken, Box<dyn Error>> {
+        let account = account.with_resolved_refresh_token().await?;
+        let vehicle_state_client = &self.config.vehicle_state_client;
+        let mut account_states = vehicle_state_client.read_account_states().await?;
+        let account_state = account_states.entry(account.name.clone()).or_default();
+        let access_token = account_state.access_token.clone();
+
+        let token = self.get_account_token(&account, account_state).await?;
+
+        if account_state.access_token != access_token {
+            let account_state = account_state.clone();
+
+            // read again to only replace this account, keeping what the exporter stored meanwhile
+            let mut account_states = vehicle_state_client.read_account_states().await?;
+            account_states.insert(account.name.clone(), account_state);
+            vehicle_state_client
+                .store_account_states(&account_states)
+                .await?;
+        }
+
+        Ok(token)
+    }
+
+    /// Calls the api with the cached access token of the account, refreshing the token once when
+    /// the api rejects it, as it can be revoked before it expires.
+    pub async fn with_cached_account_token<T, F, Fut>(
+        &self,
+        account: &AccountConfig,
+        call: F,
+    ) -> Result<T, Box<dyn Error>>
+    where
+        F: Fn(TeslaAccessToken) -> Fut,
+        Fut: Future<Output = Result<T, TeslaApiError>>,
+    {
+        let token = self.get_cached_account_token(account).await?;
+
+        match call(token).await {
+            Err(TeslaApiError::Unauthorized { body }) => {
+                warn!(
+                    "Access token for account {} was rejected, refreshing it: {}",
+                    account.name, body
+                );
+                self.forget_cached_access_token(account).await?;
+
+                let token = self.get_cached_account_token(account).await?;
+                Ok(call(token).await?)
+            }
+            result => Ok(result?),
+        }
+    }
+
+    async fn forget_cached_access_token(
+        &self,
+        account: &AccountConfig,
+    ) -> Result<(), Box<dyn Error>> {
+        let vehicle_state_client = &self.config.vehicle_state_client;
+        let mut account_states = vehicle_state_client.read_account_states().await?;
+        if let Some(account_state) = account_states.get_mut(&account.name) {
+            account_state.access_token = None;
+            account_state.access_token_expires_at = None;
+            vehicle_state_client
+                .store_account_states(&account_states)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn get_vehicle_measurement(
+        &self,
+        config: &Config,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        vehicle_id: &str,
+        last_measurements: &Option<Vec<Measurement>>,
+        mut vehicle_state: VehicleState,
+    ) -> VehicleMeasurementResult {
+        let vehicle = self.get_vehicle(account, token, vehicle_id).await?;
+        debug!("State for vehicle {}: {:?}", vehicle.id, vehicle.state);
+
+        vehicle_state.display_name = vehicle.display_name.clone();
+
+        let (last_location, last_charger_power, last_charge_energy_added, last_odometer) =
+            self.get_last_values(last_measurements, &vehicle);
+
+        // only known while the vehicle is awake, otherwise the last known values are kept
+        let mut coordinates: Option<(f64, f64)> = None;
+        let mut soc: Option<f64> = None;
+        let mut locked: Option<bool> = None;
+        let mut plugged_in: Option<bool> = None;
+        let mut charge_limit_soc: Option<f64> = None;
+        let mut min_tire_pressure: Option<f64> = None;
+        let mut software_update_version: Option<Option<String>> = None;
+
+        let (location, charger_power, charge_energy_added, odometer, availability) = if vehicle
+            .in_service
+            || vehicle.state == TeslaVehicleState::Asleep
+            || vehicle.state == TeslaVehicleState::Offline
+        {
+            info!("Vehicle is asleep, offline or in service");
+
+            let availability = if vehicle.in_service {
+                -2.0
+            } else if vehicle.state == TeslaVehicleState::Offline {
+                -1.0
             } else {
-                info!("Vehicle is awake");
+                0.0
+            };
+
+            // vehicle is asleep, offline or in service, return last values
+            (
+                last_location,
+                0.0,
+                last_charge_energy_added,
+                last_odometer,
+                availability,
+            )
+        } else {
+            info!("Vehicle is awake");
+            let telemetry = self.get_telemetry(&vehicle.vin);
+            let streaming_data = match telemetry.and_then(|t| t.streaming_data().map(|s| (t, s))) {
+                Some((telemetry, streaming_data)) => {
+                    info!(
+                        "Using fleet telemetry in gear {:?} charging at {:?} A",
+                        telemetry.gear, telemetry.charge_amps
+                    );
+                    Ok(streaming_data)
+                }
                 // vehicle is online; get stream to check location and power without keeping vehicle awake
-                match retry(
-                    Exponential::from_millis_with_factor(RETRY_INTERVAL_MS, RETRY_FACTOR)
-                        .map(jitter)
-                        .take(RETRY_TAKES),
-                    || self.get_streaming_data(&token, &vehicle),
-                ) {
-                    Ok(vehicle_streaming_data) => {
-                        debug!("vehicle_streaming_data: {:?}", vehicle_streaming_data);
-
-                        let location = if let Some(geofence) =
-                            vehicle_streaming_data.in_geofence(&config.geofences)
-                        {
-                            info!("Vehicle is inside geofence {}", geofence.location);
-                            geofence.location
-                        } else {
-                            info!("Vehicle is outside all geofences");
-                            LOCATION_OTHER.to_string()
-                        };
+                None => {
+                    self.with_retries(account, "Streaming api", || {
+                        self.get_streaming_data(account, token, &vehicle)
+                    })
+                    .await
+                }
+            };
+            match streaming_data {
+                Ok(vehicle_streaming_data) => {
+                    debug!("vehicle_streaming_data: {:?}", vehicle_streaming_data);
+
+                    let location = match (
+                        vehicle_streaming_data.latitude,
+                        vehicle_streaming_data.longitude,
+                    ) {
+                        (Some(latitude), Some(longitude)) => {
+                            coordinates = Some((latitude, longitude));
+
+                            if let Some(geofence) =
+                                vehicle_streaming_data.in_geofence(&config.geofences)
+                            {
+                                info!("Vehicle is inside geofence {}", geofence.location);
+                                geofence.location
+                            } else {
+                                info!("Vehicle is outside all geofences");
+                                LOCATION_OTHER.to_string()
+                            }
+                        }
+                        _ => {
+                            info!("Vehicle reported no location, keeping the last known one");
+                            last_location
+                        }
+                    };
 
-                        // convert miles to meters
-                        let current_odometer = vehicle_streaming_data.odometer * 1609.344;
+                    let current_odometer = vehicle_streaming_data.odometer;
+                    soc = Some(vehicle_streaming_data.soc);
 
-                        let (current_charge_energy_added, current_charger_power) =
-                            if vehicle_streaming_data.power > 0.0
-                                || vehicle_streaming_data.speed > 0.0
-                                || current_odometer - last_odometer > 0.0
-                                || last_charger_power > 0.0
-                            {
-                                // get vehicle data through regular api if vehicle is driving, charging or has just finished charging
-                                // skip otherwise, because it keeps the vehicle awake
-                                let vehicle_data = self.get_vehicle_data(&token, &vehicle)?;
-
-                                debug!("vehicle_data: {:?}", vehicle_data);
-
-                                if let Some(charge_state) = vehicle_data.charge_state {
-                                    if charge_state.charge_port_latch == "Engaged" {
-                                        (
-                                            charge_state.charge_energy_added * 1000.0 * 3600.0,
-                                            charge_state.charger_power * 1000.0,
-                                        )
+                    let (current_charge_energy_added, current_charger_power) =
+                        if vehicle_streaming_data.power > 0.0
+                            || vehicle_streaming_data.speed > 0.0
+                            || current_odometer - last_odometer > 0.0
+                            || last_charger_power > 0.0
+                        {
+                            // get vehicle data through regular api if vehicle is driving, charging or has just finished charging
+                            // skip otherwise, because it keeps the vehicle awake
+                            match self.get_vehicle_data(account, token, &vehicle).await {
+                                Ok(vehicle_data) => {
+                                    debug!("vehicle_data: {:?}", vehicle_data);
+
+                                    locked = vehicle_data
+                                        .vehicle_state
+                                        .as_ref()
+                                        .and_then(|vehicle_state| vehicle_state.locked);
+                                    software_update_version =
+                                        vehicle_data.vehicle_state.as_ref().map(|vehicle_state| {
+                                            vehicle_state
+                                                .software_update
+                                                .as_ref()
+                                                .and_then(|update| update.pending_version())
+                                        });
+                                    plugged_in =
+                                        vehicle_data.charge_state.as_ref().map(|charge_state| {
+                                            charge_state.charge_port_latch == "Engaged"
+                                        });
+                                    charge_limit_soc = vehicle_data
+                                        .charge_state
+                                        .as_ref()
+                                        .map(|charge_state| charge_state.charge_limit_soc);
+                                    if let Some(charge_state) = &vehicle_data.charge_state {
+                                        soc = Some(charge_state.battery_level);
+                                    }
+                                    min_tire_pressure =
+                                        vehicle_data.vehicle_state.as_ref().and_then(
+                                            |vehicle_state| vehicle_state.min_tire_pressure(),
+                                        );
+
+                                    self.observe_battery(
+                                        &vehicle_data,
+                                        current_odometer,
+                                        &mut vehicle_state,
+                                    );
+
+                                    if let Some(charge_state) = vehicle_data.charge_state {
+                                        if charge_state.charge_port_latch == "Engaged" {
+                                            (
+                                                charge_state.charge_energy_added * 1000.0 * 3600.0,
+                                                charge_state.charger_power * 1000.0,
+                                            )
+                                        } else {
+                                            (0.0, 0.0)
+                                        }
                                     } else {
-                                        (0.0, 0.0)
+                                        (last_charge_energy_added, 0.0)
                                     }
-                                } else {
+                                }
+                                Err(e) if e.is_vehicle_unavailable() => {
+                                    info!("Vehicle became unavailable, keeping last values");
                                     (last_charge_energy_added, 0.0)
                                 }
-                            } else {
-                                (last_charge_energy_added, 0.0)
-                            };
-
-                        (
-                            location,
-                            current_charger_power,
-                            current_charge_energy_added,
-                            current_odometer,
-                            1.0,
-                        )
-                    }
-                    Err(e) => {
-                        warn!("Stream returned error {}", e);
-                        info!("Vehicle doesn't seem awake, handling like it's asleep");
-
-                        (
-                            last_location,
-                            0.0,
-                            last_charge_energy_added,
-                            last_odometer,
-                            0.0,
-                        )
+                                Err(e) => return Err(e),
+                            }
+                        } else {
+                            (last_charge_energy_added, 0.0)
+                        };
+
+                    if let Some(segment) = vehicle_state.driving_efficiency.observe(
+                        vehicle_streaming_data.soc,
+                        current_odometer,
+                        current_charger_power > 0.0 || last_charger_power > 0.0,
+                        config.battery_capacity_kwh * 1000.0,
+                    ) {
+                        info!(
+                            "Drove {} meters using {} Wh ({} Wh/km)",
+                            segment.distance,
+                            segment.energy,
+                            segment.consumption()
+                        );
                     }
+
+                    (
+                        location,
+                        current_charger_power,
+                        current_charge_energy_added,
+                        current_odometer,
+                        1.0,
+                    )
                 }
-            };
+                Err(e) if e.is_vehicle_unavailable() => {
+                    info!("Stream returned {}, handling vehicle like it's asleep", e);
+
+                    (
+                        last_location,
+                        0.0,
+                        last_charge_energy_added,
+                        last_odometer,
+                        0.0,
+                    )
+                }
+                Err(e) => {
+                    error!("Stream returned error {}", e);
+                    return Err(e);
+                }
+            }
+        };
 
-            let mut measurement = Measurement {
-                id: Uuid::new_v4().to_string(),
-                source: String::from("jarvis-tesla-exporter"),
-                location,
-                samples: Vec::new(),
-                measured_at_time: Utc::now(),
-            };
+        let mut measurement = Measurement {
+            id: Uuid::new_v4().to_string(),
+            source: String::from("jarvis-tesla-exporter"),
+            location,
+            samples: Vec::new(),
+            measured_at_time: Utc::now(),
+        };
 
-            let display_name = vehicle
-                .display_name
-                .map_or(DEFAULT_DISPLAY_NAME.to_string(), |n| n);
+        let display_name = vehicle
+            .display_name
+            .clone()
+            .map_or(DEFAULT_DISPLAY_NAME.to_string(), |n| n);
+
+        // store as gauge for timeline graphs
+        measurement.samples.push(Sample {
+            entity_type: EntityType::Device,
+            entity_name: "jarvis-tesla-exporter".into(),
+            sample_type: SampleType::ElectricityConsumption,
+            sample_name: display_name.clone(),
+            metric_type: MetricType::Gauge,
+            value: charger_power,
+        });
+
+        // store as counter for totals
+        measurement.samples.push(Sample {
+            entity_type: EntityType::Device,
+            entity_name: "jarvis-tesla-exporter".into(),
+            sample_type: SampleType::ElectricityConsumption,
+            sample_name: display_name.clone(),
+            metric_type: MetricType::Counter,
+            value: charge_energy_added,
+        });
+
+        // odometer counter
+        measurement.samples.push(Sample {
+            entity_type: EntityType::Device,
+            entity_name: "jarvis-tesla-exporter".into(),
+            sample_type: SampleType::DistanceTraveled,
+            sample_name: display_name.clone(),
+            metric_type: MetricType::Counter,
+            value: odometer,
+        });
+
+        if let Some(estimated_full_range) = vehicle_state.battery_history.estimated_full_range() {
+            // rated range extrapolated to a full battery, to follow battery health
+            measurement.samples.push(Sample {
+                entity_type: EntityType::Device,
+                entity_name: "jarvis-tesla-exporter".into(),
+                sample_type: SampleType::DistanceTraveled,
+                sample_name: format!("{} estimated full range", display_name),
+                metric_type: MetricType::Gauge,
+                value: estimated_full_range,
+            });
+        }
 
-            // store as gauge for timeline graphs
+        if let Some(degradation_percentage) = vehicle_state.battery_history.degradation_percentage()
+        {
+            // lost full range in percent compared to the first estimate, told apart from the
+            // distances by its sample name
             measurement.samples.push(Sample {
                 entity_type: EntityType::Device,
                 entity_name: "jarvis-tesla-exporter".into(),
-                sample_type: SampleType::ElectricityConsumption,
-                sample_name: display_name.clone(),
+                sample_type: SampleType::DistanceTraveled,
+                sample_name: format!("{} range degradation", display_name),
                 metric_type: MetricType::Gauge,
-                value: charger_power,
+                value: degradation_percentage,
             });
+        }
 
-            // store as counter for totals
+        // the driving samples are energy the car used on the road instead of power drawn from the
+        // grid, consumers tell them apart from the charging samples by the driving sample names
+        if let Some(consumption) = vehicle_state.driving_efficiency.consumption() {
+            // consumption in Wh/km over the last driven segment
             measurement.samples.push(Sample {
                 entity_type: EntityType::Device,
                 entity_name: "jarvis-tesla-exporter".into(),
                 sample_type: SampleType::ElectricityConsumption,
-                sample_name: display_name.clone(),
-                metric_type: MetricType::Counter,
-                value: charge_energy_added,
+                sample_name: format!("{} driving consumption", display_name),
+                metric_type: MetricType::Gauge,
+                value: consumption,
             });
+        }
 
-            // odometer counter
+        if let Some(average_consumption) = vehicle_state.driving_efficiency.average_consumption() {
+            // consumption in Wh/km over the recent driven segments
             measurement.samples.push(Sample {
                 entity_type: EntityType::Device,
                 entity_name: "jarvis-tesla-exporter".into(),
-                sample_type: SampleType::DistanceTraveled,
-                sample_name: display_name.clone(),
-                metric_type: MetricType::Counter,
-                value: odometer,
+                sample_type: SampleType::ElectricityConsumption,
+                sample_name: format!("{} average driving consumption", display_name),
+                metric_type: MetricType::Gauge,
+                value: average_consumption,
             });
+        }
 
-            // availability
-            measurement.samples.push(Sample {
+        // energy spent driving as counter, in joules like the charging counter
+        measurement.samples.push(Sample {
+            entity_type: EntityType::Device,
+            entity_name: "jarvis-tesla-exporter".into(),
+            sample_type: SampleType::ElectricityConsumption,
+            sample_name: format!("{} driving energy", display_name),
+            metric_type: MetricType::Counter,
+            value: vehicle_state.driving_efficiency.energy_spent * 3600.0,
+        });
+
+        // availability
+        measurement.samples.push(Sample {
+            entity_type: EntityType::Device,
+            entity_name: "jarvis-tesla-exporter".into(),
+            sample_type: SampleType::Availability,
+            sample_name: display_name.clone(),
+            metric_type: MetricType::Gauge,
+            value: availability,
+        });
+
+        let previous_status = vehicle_state.status.take().unwrap_or_default();
+        let status = VehicleStatus {
+            vin: vehicle.vin.clone(),
+            display_name: display_name.clone(),
+            location: measurement.location.clone(),
+            latitude: coordinates
+                .map(|(latitude, _)| latitude)
+                .or(previous_status.latitude),
+            longitude: coordinates
+                .map(|(_, longitude)| longitude)
+                .or(previous_status.longitude),
+            charger_power,
+            charge_energy_added,
+            odometer,
+            availability,
+            soc: soc.or(previous_status.soc),
+            locked: locked.or(previous_status.locked),
+            charge_limit_soc: charge_limit_soc.or(previous_status.charge_limit_soc),
+            min_tire_pressure: min_tire_pressure.or(previous_status.min_tire_pressure),
+            plugged_in: plugged_in.or(previous_status.plugged_in),
+            software_update_version: software_update_version
+                .unwrap_or(previous_status.software_update_version),
+            estimated_full_range: vehicle_state.battery_history.estimated_full_range(),
+            range_degradation: vehicle_state.battery_history.degradation_percentage(),
+            driving_consumption: vehicle_state.driving_efficiency.consumption(),
+            average_driving_consumption: vehicle_state.driving_efficiency.average_consumption(),
+            driving_energy: vehicle_state.driving_efficiency.energy_spent * 3600.0,
+        };
+
+        if let Some(tariff_charging) = config
+            .tariff_charging
+            .as_ref()
+            .filter(|tariff_charging| tariff_charging.vin == status.vin)
+        {
+            match self
+                .schedule_tariff_charging(
+                    config,
+                    tariff_charging,
+                    account,
+                    token,
+                    &vehicle,
+                    &status,
+                )
+                .await
+            {
+                Ok(samples) => measurement.samples.extend(samples),
+                Err(e) => warn!("Failed scheduling tariff charging: {}", e),
+            }
+        }
+
+        self.metrics.record_vehicle(&status);
+        vehicle_state.status = Some(status);
+
+        debug!("measurement: {:?}", measurement);
+
+        Ok((measurement, vehicle_state))
+    }
+
+    pub async fn get_access_token(
+        &self,
+        account: &AccountConfig,
+        refresh_token: &str,
+    ) -> Result<TeslaAccessToken, TeslaApiError> {
+        info!("Fetching access token for account {}...", account.name);
+        let url = account.auth_url();
+
+        debug!("POST {}", url);
+
+        let request_body: TeslaAccessTokenRequest = TeslaAccessTokenRequest {
+            grant_type: "refresh_token".into(),
+            scope: "openid email offline_access".into(),
+            client_id: account.client_id().into(),
+            refresh_token: refresh_token.into(),
+        };
+
+        let access_token: TeslaAccessToken = self
+            .send_request(account, url, self.config.request_timeout, || {
+                self.config.http_client.post(url).json(&request_body)
+            })
+            .await?;
+
+        Ok(access_token)
+    }
+
+    /// Exchanges the code from the login redirect for the first access and refresh token. The code
+    /// can only be used once, so a failed exchange isn't retried; the login has to start over.
+    pub async fn exchange_authorization_code(
+        &self,
+        account: &AccountConfig,
+        code: &str,
+        code_verifier: &str,
+        redirect_uri: &str,
+        client_secret: Option<&str>,
+    ) -> Result<TeslaAccessToken, TeslaApiError> {
+        info!(
+            "Exchanging authorization code for account {}...",
+            account.name
+        );
+        let url = account.auth_url();
+
+        debug!("POST {}", url);
+
+        let request_body = TeslaAuthorizationCodeRequest {
+            grant_type: "authorization_code".into(),
+            client_id: account.client_id().into(),
+            code: code.into(),
+            code_verifier: code_verifier.into(),
+            redirect_uri: redirect_uri.into(),
+            client_secret: client_secret.map(str::to_string),
+            audience: match account.backend {
+                TeslaBackend::FleetApi => Some(account.api_url().to_string()),
+                TeslaBackend::OwnerApi => None,
+            },
+        };
+
+        let access_token: TeslaAccessToken = self
+            .send_request_once(account, url, self.config.request_timeout, &|| {
+                self.config.http_client.post(url).json(&request_body)
+            })
+            .await?;
+
+        Ok(access_token)
+    }
+
+    /// Plans the cheapest hours to charge before departure and starts or stops charging to follow
+    /// the plan while the vehicle is plugged in at the geofence.
+    async fn schedule_tariff_charging(
+        &self,
+        config: &Config,
+        tariff_charging: &TariffChargingConfig,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        vehicle: &TeslaVehicle,
+        status: &VehicleStatus,
+    ) -> Result<Vec<Sample>, Box<dyn Error>> {
+        let now = Utc::now();
+        let prices =
+            tariff_scheduler::load_prices(&tariff_charging.prices, &self.config.http_client)
+                .await?;
+        let departure = tariff_scheduler::next_departure(&tariff_charging.departure_time, now)?;
+
+        let energy = match (status.soc, status.charge_limit_soc) {
+            (Some(soc), Some(charge_limit_soc)) => {
+                (charge_limit_soc - soc) / 100.0 * config.battery_capacity_kwh * 1000.0
+            }
+            _ => 0.0,
+        };
+        let charging = status.charger_power > 0.0;
+        // the actual charger power is more accurate than the configured one once charging
+        let power = if charging {
+            status.charger_power
+        } else {
+            tariff_charging.charge_power_watts
+        };
+        let plan = tariff_scheduler::plan_charging(&prices, now, departure, energy, power);
+        let charge_now = plan.is_charging_at(now);
+
+        info!(
+            "Planned charging {} Wh in {} periods before {} for {} per kWh in total",
+            plan.energy,
+            plan.periods.len(),
+            departure,
+            plan.expected_cost
+        );
+        if plan.unplanned_energy > 0.0 {
+            warn!(
+                "Known prices leave {} Wh unplanned before departure",
+                plan.unplanned_energy
+            );
+        }
+
+        if status.location == tariff_charging.geofence
+            && status.plugged_in == Some(true)
+            && !self.config.vehicle_state_client.read_only()
+        {
+            if charge_now && !charging {
+                if status.availability == 1.0 {
+                    info!("Starting charging for planned period");
+                    self.charge_start(account, token, vehicle).await?;
+                } else if status.availability == 0.0 {
+                    info!("Waking up vehicle to start charging for planned period");
+                    let vehicle = self.wake_up(account, token, vehicle).await?;
+                    self.charge_start(account, token, &vehicle).await?;
+                }
+            } else if !charge_now && charging {
+                info!("Stopping charging outside planned periods");
+                self.charge_stop(account, token, vehicle).await?;
+            }
+        }
+
+        let planned_hours: f64 = plan
+            .periods
+            .iter()
+            .map(|period| (period.end - period.start).num_seconds() as f64 / 3600.0)
+            .sum();
+
+        let planned_power = if charge_now { power } else { 0.0 };
+        self.metrics
+            .record_charging_plan(status, planned_power, planned_hours, plan.expected_cost);
+
+        // jarvis has no sample types for a plan or money, the planned sample names keep them apart
+        // from the consumed energy
+        Ok(vec![
+            Sample {
+                entity_type: EntityType::Device,
+                entity_name: "jarvis-tesla-exporter".into(),
+                sample_type: SampleType::ElectricityConsumption,
+                sample_name: format!("{} planned charging", status.display_name),
+                metric_type: MetricType::Gauge,
+                value: planned_power,
+            },
+            Sample {
+                entity_type: EntityType::Device,
+                entity_name: "jarvis-tesla-exporter".into(),
+                sample_type: SampleType::ElectricityConsumption,
+                sample_name: format!("{} planned charging hours", status.display_name),
+                metric_type: MetricType::Gauge,
+                value: planned_hours,
+            },
+            Sample {
                 entity_type: EntityType::Device,
                 entity_name: "jarvis-tesla-exporter".into(),
-                sample_type: SampleType::Availability,
-                sample_name: display_name,
+                sample_type: SampleType::ElectricityConsumption,
+                sample_name: format!("{} expected charging cost", status.display_name),
                 metric_type: MetricType::Gauge,
-                value: availability,
+                value: plan.expected_cost,
+            },
+        ])
+    }
+
+    pub async fn get_vehicles(
+        &self,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+    ) -> Result<Vec<TeslaVehicle>, TeslaApiError> {
+        info!("Fetching vehicles...");
+        let url = format!("{}/api/1/vehicles", account.api_url());
+
+        debug!("GET {}", url);
+
+        let vehicles_response: TeslaApiResponse<Vec<TeslaVehicle>> = self
+            .send_request(account, &url, self.config.request_timeout, || {
+                self.config
+                    .http_client
+                    .get(&url)
+                    .bearer_auth(&token.access_token)
+            })
+            .await?;
+
+        Ok(vehicles_response.response)
+    }
+
+    pub async fn get_vehicle(
+        &self,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        vehicle_id: &str,
+    ) -> Result<TeslaVehicle, TeslaApiError> {
+        info!("Fetching vehicles...");
+        let url = format!("{}/api/1/vehicles/{}", account.api_url(), vehicle_id);
+
+        debug!("GET {}", url);
+
+        let vehicles_response: TeslaApiResponse<TeslaVehicle> = self
+            .send_request(account, &url, self.config.request_timeout, || {
+                self.config
+                    .http_client
+                    .get(&url)
+                    .bearer_auth(&token.access_token)
+            })
+            .await?;
+
+        Ok(vehicles_response.response)
+    }
+
+    pub async fn get_vehicle_data(
+        &self,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        vehicle: &TeslaVehicle,
+    ) -> Result<TeslaVehicleData, TeslaApiError> {
+        info!("Fetching vehicle data for {:?}...", vehicle.display_name);
+        let mut url = format!(
+            "{}/api/1/vehicles/{}/vehicle_data",
+            account.api_url(),
+            vehicle.id
+        );
+        // the fleet api leaves out the location unless it's asked for explicitly
+        if account.backend == TeslaBackend::FleetApi {
+            url = format!("{}?endpoints={}", url, FLEET_API_VEHICLE_DATA_ENDPOINTS);
+        }
+
+        debug!("GET {}", url);
+
+        let vehicle_data_response: TeslaApiResponse<TeslaVehicleData> = match self
+            .send_request(account, &url, self.config.vehicle_data_timeout, || {
+                self.config
+                    .http_client
+                    .get(&url)
+                    .bearer_auth(&token.access_token)
+            })
+            .await
+        {
+            Ok(vehicle_data_response) => vehicle_data_response,
+            Err(e) => {
+                error!("Error: {}", e);
+                return Err(e);
+            }
+        };
+
+        let mut vehicle_data = vehicle_data_response.response;
+        vehicle_data.convert_to_si_units();
+
+        Ok(vehicle_data)
+    }
+
+    /// Configures the vehicles in `body` to stream fleet telemetry to our receiver.
+    pub async fn create_fleet_telemetry_config(
+        &self,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        body: &Value,
+    ) -> Result<Value, TeslaApiError> {
+        info!("Creating fleet telemetry config...");
+        let url = format!(
+            "{}/api/1/vehicles/fleet_telemetry_config",
+            account.api_url()
+        );
+
+        debug!("POST {}", url);
+
+        let config_response: TeslaApiResponse<Value> = self
+            .send_request(account, &url, self.config.request_timeout, || {
+                self.config
+                    .http_client
+                    .post(&url)
+                    .bearer_auth(&token.access_token)
+                    .json(body)
+            })
+            .await?;
+
+        Ok(config_response.response)
+    }
+
+    /// Wakes up the vehicle and waits until it's online, so it accepts commands.
+    pub async fn wake_up(
+        &self,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        vehicle: &TeslaVehicle,
+    ) -> Result<TeslaVehicle, TeslaApiError> {
+        info!("Waking up vehicle {:?}...", vehicle.display_name);
+        let url = format!(
+            "{}/api/1/vehicles/{}/wake_up",
+            account.api_url(),
+            vehicle.id
+        );
+        let started_at = Instant::now();
+
+        loop {
+            debug!("POST {}", url);
+
+            let vehicle_response: TeslaApiResponse<TeslaVehicle> = self
+                .send_request(account, &url, self.config.request_timeout, || {
+                    self.config
+                        .http_client
+                        .post(&url)
+                        .bearer_auth(&token.access_token)
+                })
+                .await?;
+            if vehicle_response.response.state == TeslaVehicleState::Online {
+                return Ok(vehicle_response.response);
+            }
+
+            if started_at.elapsed() + WAKE_UP_POLL_INTERVAL > self.config.wake_up_timeout {
+                return Err(TeslaApiError::WakeUpTimeout {
+                    seconds: self.config.wake_up_timeout.as_secs(),
+                });
+            }
+            debug!(
+                "Vehicle is {:?}, polling again",
+                vehicle_response.response.state
+            );
+            sleep(WAKE_UP_POLL_INTERVAL).await;
+        }
+    }
+
+    pub async fn charge_start(
+        &self,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        vehicle: &TeslaVehicle,
+    ) -> Result<(), TeslaApiError> {
+        self.send_command(account, token, vehicle, "charge_start", Value::Null)
+            .await
+    }
+
+    pub async fn charge_stop(
+        &self,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        vehicle: &TeslaVehicle,
+    ) -> Result<(), TeslaApiError> {
+        self.send_command(account, token, vehicle, "charge_stop", Value::Null)
+            .await
+    }
+
+    /// `percent` between 50 and 100
+    // commands not used by the exporter yet are kept as the api for automations
+    #[allow(dead_code)]
+    pub async fn set_charge_limit(
+        &self,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        vehicle: &TeslaVehicle,
+        percent: u32,
+    ) -> Result<(), TeslaApiError> {
+        self.send_command(
+            account,
+            token,
+            vehicle,
+            "set_charge_limit",
+            json!({ "percent": percent }),
+        )
+        .await
+    }
+
+    pub async fn set_charging_amps(
+        &self,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        vehicle: &TeslaVehicle,
+        charging_amps: u32,
+    ) -> Result<(), TeslaApiError> {
+        self.send_command(
+            account,
+            token,
+            vehicle,
+            "set_charging_amps",
+            json!({ "charging_amps": charging_amps }),
+        )
+        .await
+    }
+
+    #[allow(dead_code)]
+    pub async fn auto_conditioning_start(
+        &self,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        vehicle: &TeslaVehicle,
+    ) -> Result<(), TeslaApiError> {
+        self.send_command(
+            account,
+            token,
+            vehicle,
+            "auto_conditioning_start",
+            Value::Null,
+        )
+        .await
+    }
+
+    #[allow(dead_code)]
+    pub async fn auto_conditioning_stop(
+        &self,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        vehicle: &TeslaVehicle,
+    ) -> Result<(), TeslaApiError> {
+        self.send_command(
+            account,
+            token,
+            vehicle,
+            "auto_conditioning_stop",
+            Value::Null,
+        )
+        .await
+    }
+
+    #[allow(dead_code)]
+    pub async fn door_lock(
+        &self,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        vehicle: &TeslaVehicle,
+    ) -> Result<(), TeslaApiError> {
+        self.send_command(account, token, vehicle, "door_lock", Value::Null)
+            .await
+    }
+
+    #[allow(dead_code)]
+    pub async fn set_sentry_mode(
+        &self,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        vehicle: &TeslaVehicle,
+        on: bool,
+    ) -> Result<(), TeslaApiError> {
+        self.send_command(
+            account,
+            token,
+            vehicle,
+            "set_sentry_mode",
+            json!({ "on": on }),
+        )
+        .await
+    }
+
+    /// Posts a command, failing with the reason the vehicle gave when it didn't execute it. A
+    /// command is posted once, as the vehicle may have executed it even when the response failed.
+    async fn send_command(
+        &self,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        vehicle: &TeslaVehicle,
+        command: &str,
+        body: Value,
+    ) -> Result<(), TeslaApiError> {
+        if let Some(command_key_file) = &account.command_key_file {
+            return self
+                .send_signed_command(account, token, vehicle, command_key_file, command, &body)
+                .await;
+        }
+
+        info!(
+            "Sending command {} to vehicle {:?}...",
+            command, vehicle.display_name
+        );
+        let url = format!(
+            "{}/api/1/vehicles/{}/command/{}",
+            account.api_url(),
+            vehicle.id,
+            command
+        );
+
+        debug!("POST {}", url);
+
+        let command_response: TeslaApiResponse<TeslaCommandResult> = match self
+            .send_request_once(account, &url, self.config.vehicle_data_timeout, &|| {
+                let request = self
+                    .config
+                    .http_client
+                    .post(&url)
+                    .bearer_auth(&token.access_token);
+                if body.is_null() {
+                    request
+                } else {
+                    request.json(&body)
+                }
+            })
+            .await
+        {
+            Ok(command_response) => command_response,
+            Err(TeslaApiError::RateLimited { retry_after }) => {
+                self.rate_limiter(account).record_rate_limited(retry_after);
+                return Err(TeslaApiError::RateLimited { retry_after });
+            }
+            Err(e) => return Err(e),
+        };
+
+        let result = command_response.response;
+        if !result.result {
+            return Err(TeslaApiError::CommandFailed {
+                command: command.to_string(),
+                reason: result.reason.as_str().into(),
             });
+        }
+
+        Ok(())
+    }
+
+    /// Sends the command through the signed command protocol, starting a new session once when the
+    /// vehicle rejects the signature of a stale one. A signed message is never sent twice, as the
+    /// vehicle may have executed it already; a failed post starts a new session instead.
+    async fn send_signed_command(
+        &self,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        vehicle: &TeslaVehicle,
+        command_key_file: &str,
+        command: &str,
+        body: &Value,
+    ) -> Result<(), TeslaApiError> {
+        info!(
+            "Sending signed command {} to vehicle {:?}...",
+            command, vehicle.display_name
+        );
+        let (domain, payload) =
+            signed_command::command_payload(command, body).ok_or_else(|| {
+                TeslaApiError::SignedCommand {
+                    message: format!("Command {} has no signed equivalent", command),
+                }
+            })?;
+        let command_key = CommandKey::load(command_key_file).map_err(signing_error)?;
+        let session_key = format!("{}/{:?}", vehicle.vin, domain);
+
+        let mut retried = false;
+        loop {
+            let cached_session = self.command_sessions.lock().unwrap().remove(&session_key);
+            let mut session = match cached_session {
+                Some(session) => session,
+                None => {
+                    self.start_command_session(account, token, vehicle, &command_key, domain)
+                        .await?
+                }
+            };
 
-            debug!("measurement: {:?}", measurement);
+            let message = session
+                .sign(&command_key, &payload)
+                .map_err(signing_error)?;
+            let response = match self
+                .post_routable_message(account, token, vehicle, &message)
+                .await
+            {
+                Ok(response) => response,
+                Err(TeslaApiError::RateLimited { retry_after }) => {
+                    self.rate_limiter(account).record_rate_limited(retry_after);
+                    return Err(TeslaApiError::RateLimited { retry_after });
+                }
+                Err(e) if e.is_retryable() && !retried => {
+                    warn!(
+                        "Sending signed command {} failed, starting a new session: {}",
+                        command, e
+                    );
+                    retried = true;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
 
-            measurements.push(measurement);
+            match signed_command::command_outcome(&response).map_err(signing_error)? {
+                CommandOutcome::Executed => {
+                    self.command_sessions
+                        .lock()
+                        .unwrap()
+                        .insert(session_key, session);
+                    return Ok(());
+                }
+                CommandOutcome::Failed(reason) => {
+                    self.command_sessions
+                        .lock()
+                        .unwrap()
+                        .insert(session_key, session);
+                    return Err(TeslaApiError::CommandFailed {
+                        command: command.to_string(),
+                        reason: reason.as_str().into(),
+                    });
+                }
+                CommandOutcome::Fault(fault) if !retried => {
+                    warn!(
+                        "Vehicle returned message fault {}, starting a new session",
+                        fault
+                    );
+                    retried = true;
+                }
+                CommandOutcome::Fault(fault) => {
+                    return Err(TeslaApiError::SignedCommand {
+                        message: format!("Vehicle returned message fault {}", fault),
+                    })
+                }
+            }
         }
+    }
 
-        Ok(measurements)
+    async fn start_command_session(
+        &self,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        vehicle: &TeslaVehicle,
+        command_key: &CommandKey,
+        domain: Domain,
+    ) -> Result<Session, TeslaApiError> {
+        debug!(
+            "Requesting session info of {:?} for vehicle {}",
+            domain, vehicle.vin
+        );
+        let (request, challenge) =
+            signed_command::session_info_request(command_key, domain).map_err(signing_error)?;
+        // session info requests don't execute anything on the vehicle, so they can be retried
+        let response = self
+            .with_retries(account, "Requesting session info", || {
+                self.post_routable_message(account, token, vehicle, &request)
+            })
+            .await?;
+
+        signed_command::session_from_response(
+            command_key,
+            &vehicle.vin,
+            domain,
+            &challenge,
+            &response,
+        )
+        .map_err(signing_error)
+    }
+
+    async fn post_routable_message(
+        &self,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        vehicle: &TeslaVehicle,
+        message: &[u8],
+    ) -> Result<Vec<u8>, TeslaApiError> {
+        let url = format!(
+            "{}/api/1/vehicles/{}/signed_command",
+            account.api_url(),
+            vehicle.id
+        );
+        let body = json!({ "routable_message": STANDARD.encode(message) });
+
+        debug!("POST {}", url);
+
+        let signed_command_response: TeslaApiResponse<String> = self
+            .send_request_once(account, &url, self.config.vehicle_data_timeout, &|| {
+                self.config
+                    .http_client
+                    .post(&url)
+                    .bearer_auth(&token.access_token)
+                    .json(&body)
+            })
+            .await?;
+
+        STANDARD
+            .decode(signed_command_response.response)
+            .map_err(signing_error)
     }
-}
 
-impl TeslaApiClient {
-    pub fn new() -> Self {
-        Self {}
+    /// Sends a request with the shared http client, retrying it when that's safe.
+    async fn send_request<T, F>(
+        &self,
+        account: &AccountConfig,
+        url: &str,
+        timeout: Duration,
+        build_request: F,
+    ) -> Result<T, TeslaApiError>
+    where
+        T: DeserializeOwned,
+        F: Fn() -> RequestBuilder,
+    {
+        let build_request = &build_request;
+
+        self.with_retries(account, url, move || {
+            self.send_request_once(account, url, timeout, build_request)
+        })
+        .await
     }
 
-    pub fn get_access_token(
+    /// Sends a request once with the shared http client, waiting first if the api rate limited the
+    /// account, or failing right away when it asked to wait longer than `MAX_RETRY_AFTER`.
+    async fn send_request_once<T, F>(
         &self,
-        config: &Config,
-    ) -> Result<TeslaAccessToken, Box<dyn std::error::Error>> {
-        info!("Fetching access token...");
-        let url = "https://auth.tesla.com/oauth2/v3/token";
+        account: &AccountConfig,
+        url: &str,
+        timeout: Duration,
+        build_request: &F,
+    ) -> Result<T, TeslaApiError>
+    where
+        T: DeserializeOwned,
+        F: Fn() -> RequestBuilder,
+    {
+        let rate_limiter = self.rate_limiter(account);
+        if let Some(retry_after) = rate_limiter.exhausted_for() {
+            return Err(TeslaApiError::RateLimited {
+                retry_after: Some(retry_after),
+            });
+        }
 
-        debug!("POST {}", url);
+        let delay = rate_limiter.delay();
+        if !delay.is_zero() {
+            info!("Waiting {} ms before requesting {}", delay.as_millis(), url);
+            sleep(delay).await;
+        }
 
-        let request_body: TeslaAccessTokenRequest = TeslaAccessTokenRequest {
-            grant_type: "refresh_token".into(),
-            scope: "openid email offline_access".into(),
-            client_id: "ownerapi".into(),
-            refresh_token: config.refresh_token.clone(),
-        };
+        rate_limiter.record_request();
 
-        let access_token: TeslaAccessToken = retry(
-            Exponential::from_millis_with_factor(RETRY_INTERVAL_MS, RETRY_FACTOR)
-                .map(jitter)
-                .take(RETRY_TAKES),
-            || {
-                reqwest::blocking::Client::new()
-                    .post(url)
-                    .json(&request_body)
-                    .send()
-            },
-        )?
-        .json()?;
+        let request = build_request().timeout(timeout).build()?;
+        let (status, retry_after, body) = match &self.config.cassette {
+            Some(cassette) if cassette.is_replay() => {
+                let interaction = cassette
+                    .replay_http(request.method().as_str(), request.url().as_str())
+                    .ok_or_else(|| TeslaApiError::CassetteMiss {
+                        request: format!("{} {}", request.method(), request.url()),
+                    })?;
+                (
+                    StatusCode::from_u16(interaction.status)
+                        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+                    interaction.retry_after_seconds.map(Duration::from_secs),
+                    interaction.response_body(),
+                )
+            }
+            _ => {
+                let method = request.method().to_string();
+                let request_url = request.url().to_string();
+                let request_body = request
+                    .body()
+                    .and_then(|body| body.as_bytes())
+                    .map(<[u8]>::to_vec);
+
+                let response = match self.config.http_client.execute(request).await {
+                    Ok(response) => response,
+                    Err(e) => {
+                        self.metrics.record_api_request(url, None);
+                        return Err(e.into());
+                    }
+                };
+                let (status, retry_after, body) = Self::read_response(response).await?;
+
+                if let Some(cassette) = &self.config.cassette {
+                    cassette.record_http(
+                        &method,
+                        &request_url,
+                        request_body.as_deref(),
+                        status.as_u16(),
+                        retry_after.map(|r| r.as_secs()),
+                        &body,
+                    );
+                }
 
-        Ok(access_token)
+                (status, retry_after, body)
+            }
+        };
+        self.metrics.record_api_request(url, Some(status));
+
+        Self::parse_response(status, retry_after, &body)
     }
 
-    #[allow(dead_code)]
-    pub fn get_vehicles(
+    /// Retries transport errors, 429 and 5xx responses and broken streams only; obeys
+    /// `Retry-After` and keeps spacing out requests for the rest of the run once rate limited.
+    async fn with_retries<T, F, Fut>(
         &self,
-        token: &TeslaAccessToken,
-    ) -> Result<Vec<TeslaVehicle>, Box<dyn std::error::Error>> {
-        info!("Fetching vehicles...");
-        let url = "https://owner-api.teslamotors.com/api/1/vehicles";
+        account: &AccountConfig,
+        description: &str,
+        mut operation: F,
+    ) -> Result<T, TeslaApiError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, TeslaApiError>>,
+    {
+        let rate_limiter = self.rate_limiter(account);
+        let mut delays = Exponential::from_millis_with_factor(RETRY_INTERVAL_MS, RETRY_FACTOR)
+            .map(jitter)
+            .take(RETRY_TAKES);
 
-        debug!("GET {}", url);
+        loop {
+            match operation().await {
+                Ok(result) => return Ok(result),
+                Err(TeslaApiError::RateLimited { retry_after }) if matches!(retry_after, Some(r) if r > MAX_RETRY_AFTER) =>
+                {
+                    rate_limiter.record_rate_limited(retry_after);
+                    return Err(TeslaApiError::RateLimited { retry_after });
+                }
+                Err(e) if e.is_retryable() => {
+                    if let TeslaApiError::RateLimited { retry_after } = &e {
+                        rate_limiter.record_rate_limited(*retry_after);
+                    }
 
-        let vehicles_response: TeslaApiResponse<Vec<TeslaVehicle>> = retry(
-            Exponential::from_millis_with_factor(RETRY_INTERVAL_MS, RETRY_FACTOR)
-                .map(jitter)
-                .take(RETRY_TAKES),
-            || {
-                reqwest::blocking::Client::new()
-                    .get(url)
-                    .bearer_auth(token.access_token.clone())
-                    .send()
-            },
-        )?
-        .json()?;
+                    match delays.next() {
+                        Some(delay) => {
+                            rate_limiter.record_retry();
+                            warn!(
+                                "{} failed, retrying in {} ms: {}",
+                                description,
+                                delay.as_millis(),
+                                e
+                            );
+                            sleep(delay).await;
+                        }
+                        None => return Err(e),
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
 
-        Ok(vehicles_response.response)
+    /// Checks the status before deserializing, so error bodies end up in a typed error.
+    async fn read_response(
+        response: Response,
+    ) -> Result<(StatusCode, Option<Duration>, String), TeslaApiError> {
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_secs);
+
+        let body = response.text().await?;
+
+        Ok((status, retry_after, body))
     }
 
-    pub fn get_vehicle(
-        &self,
-        token: &TeslaAccessToken,
-        vehicle_id: &str,
-    ) -> Result<TeslaVehicle, Box<dyn std::error::Error>> {
-        info!("Fetching vehicles...");
-        let url = format!(
-            "https://owner-api.teslamotors.com/api/1/vehicles/{}",
-            vehicle_id
-        );
+    fn parse_response<T: DeserializeOwned>(
+        status: StatusCode,
+        retry_after: Option<Duration>,
+        body: &str,
+    ) -> Result<T, TeslaApiError> {
+        if !status.is_success() {
+            return Err(TeslaApiError::from_status(status, body, retry_after));
+        }
 
-        debug!("GET {}", url);
+        serde_json::from_str(body).map_err(|e| TeslaApiError::deserialization(e, body))
+    }
 
-        let vehicles_response: TeslaApiResponse<TeslaVehicle> = retry(
-            Exponential::from_millis_with_factor(RETRY_INTERVAL_MS, RETRY_FACTOR)
-                .map(jitter)
-                .take(RETRY_TAKES),
-            || {
-                reqwest::blocking::Client::new()
-                    .get(&url)
-                    .bearer_auth(token.access_token.clone())
-                    .send()
-            },
-        )?
-        .json()?;
+    pub async fn get_streaming_data(
+        &self,
+        account: &AccountConfig,
+        token: &TeslaAccessToken,
+        vehicle: &TeslaVehicle,
+    ) -> Result<TeslaVehicleStreamingData, TeslaApiError> {
+        let streaming_timeout = self.config.streaming_timeout;
+        let started_at = Instant::now();
 
-        Ok(vehicles_response.response)
+        match timeout(
+            streaming_timeout,
+            self.read_streaming_data(account, token, vehicle),
+        )
+        .await
+        {
+            Ok(Ok(streaming_data)) => {
+                self.metrics.record_streaming_duration(started_at.elapsed());
+                Ok(streaming_data)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(TeslaApiError::StreamingTimeout {
+                seconds: streaming_timeout.as_secs(),
+            }),
+        }
     }
 
-    pub fn get_vehicle_data(
+    async fn read_streaming_data(
         &self,
+        account: &AccountConfig,
         token: &TeslaAccessToken,
         vehicle: &TeslaVehicle,
-    ) -> Result<TeslaVehicleData, Box<dyn std::error::Error>> {
-        info!("Fetching vehicle data for {:?}...", vehicle.display_name);
-        let url = format!(
-            "https://owner-api.teslamotors.com/api/1/vehicles/{}/vehicle_data",
-            vehicle.id
-        );
+    ) -> Result<TeslaVehicleStreamingData, TeslaApiError> {
+        let mut streaming_data = None;
 
-        debug!("GET {}", url);
-
-        let vehicle_data_response: TeslaApiResponse<TeslaVehicleData> = match retry(
-            Exponential::from_millis_with_factor(RETRY_INTERVAL_MS, RETRY_FACTOR)
-                .map(jitter)
-                .take(RETRY_TAKES),
-            || {
-                reqwest::blocking::Client::new()
-                    .get(&url)
-                    .bearer_auth(token.access_token.clone())
-                    .send()
-            },
-        ) {
-            Ok(response) => {
-                debug!("Response: {:?}", response);
-                match response.json() {
-                    Ok(vehicle_data_response) => vehicle_data_response,
-                    Err(e) => {
-                        error!("Error: {}", e);
-                        return Err(Box::<dyn Error>::from(e));
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Error: {}", e);
-                return Err(Box::<dyn Error>::from(e));
-            }
-        };
+        self.stream_updates(account, token, vehicle, |update| {
+            streaming_data = Some(update);
+            false
+        })
+        .await?;
 
-        Ok(vehicle_data_response.response)
+        streaming_data.ok_or(TeslaApiError::StreamingClosed)
     }
 
-    pub fn get_streaming_data(
+    /// Passes every `data:update` frame to `on_update` until it returns false.
+    pub async fn stream_updates<F>(
         &self,
+        account: &AccountConfig,
         token: &TeslaAccessToken,
         vehicle: &TeslaVehicle,
-    ) -> Result<TeslaVehicleStreamingData, Box<dyn Error>> {
+        mut on_update: F,
+    ) -> Result<(), TeslaApiError>
+    where
+        F: FnMut(TeslaVehicleStreamingData) -> bool,
+    {
         info!(
             "Connecting to streaming api for vehicle {:?}",
             vehicle.display_name
         );
 
-        let (mut socket, response) =
-            connect(Url::parse("wss://streaming.vn.teslamotors.com/streaming/")?)?;
+        let url = account.streaming_url();
+        let mut recording = None;
+        let mut messages: BoxStream<'_, Result<Message, TeslaApiError>> = match &self
+            .config
+            .cassette
+        {
+            Some(cassette) if cassette.is_replay() => {
+                let frames =
+                    cassette
+                        .replay_websocket(url)
+                        .ok_or_else(|| TeslaApiError::CassetteMiss {
+                            request: format!("websocket {}", url),
+                        })?;
+
+                stream::iter(frames.into_iter().map(|frame| Ok(Message::Binary(frame)))).boxed()
+            }
+            cassette => {
+                let (mut socket, response) = connect_async(url).await?;
 
-        debug!("Connected to the server");
-        debug!("Response HTTP code: {}", response.status());
+                debug!("Connected to the server");
+                debug!("Response HTTP code: {}", response.status());
 
-        let subscribe_message = TeslaStreamingApiMessage {
-            msg_type: "data:subscribe_oauth".into(),
-            tag: vehicle.vehicle_id.to_string(),
-            token: Some(token.access_token.clone()),
-            value: "speed,odometer,soc,elevation,est_heading,est_lat,est_lng,power,shift_state,range,est_range,heading".into(),
-        };
+                let subscribe_message = serde_json::to_string(&TeslaStreamingApiMessage {
+                    msg_type: "data:subscribe_oauth".into(),
+                    tag: vehicle.vehicle_id.to_string(),
+                    token: Some(token.access_token.clone()),
+                    value: "speed,odometer,soc,elevation,est_heading,est_lat,est_lng,power,shift_state,range,est_range,heading".into(),
+                })
+                .map_err(|e| TeslaApiError::deserialization(e, ""))?;
+
+                socket
+                    .send(Message::Text(subscribe_message.clone()))
+                    .await?;
+
+                recording = cassette
+                    .as_ref()
+                    .and_then(|c| c.record_websocket(url).map(|index| (c, index)));
+                if let Some((cassette, index)) = recording {
+                    cassette.record_frame(
+                        index,
+                        FrameDirection::Sent,
+                        subscribe_message.as_bytes(),
+                    );
+                }
 
-        socket.write_message(Message::Text(serde_json::to_string(&subscribe_message)?))?;
-        let start = Instant::now();
-        loop {
-            if start.elapsed().as_secs() > 30 {
-                return Err(Box::<dyn Error>::from("Timed out after 30 seconds"));
-            };
+                socket.map_err(TeslaApiError::from).boxed()
+            }
+        };
 
-            let msg = socket.read_message()?;
+        while let Some(msg) = messages.next().await {
+            let msg = msg?;
             debug!("Received: {}", msg);
 
+            if let Some((cassette, index)) = recording {
+                cassette.record_frame(index, FrameDirection::Received, &msg.clone().into_data());
+            }
+
             if msg.is_close() {
-                return Err(Box::<dyn Error>::from("Received close message"));
+                return Err(TeslaApiError::StreamingClosed);
             }
 
             if !msg.is_binary() {
@@ -378,72 +1997,89 @@ impl TeslaApiClient {
                 continue;
             }
 
-            let msg_data = msg.into_data();
+            if let Some(update) = parse_streaming_frame(&msg.into_data(), vehicle.vehicle_id)? {
+                if !on_update(update) {
+                    return Ok(());
+                }
+            }
+        }
 
-            let msg_value: serde_json::Value = serde_json::from_slice(&msg_data)?;
-            if let Value::String(msg_type) = &msg_value["msg_type"] {
-                match msg_type.as_str() {
-                    "data:update" => {
-                        let data_update_message: TeslaStreamingApiMessage =
-                            serde_json::from_slice(&msg_data)?;
+        Err(TeslaApiError::StreamingClosed)
+    }
 
-                        if data_update_message.tag != vehicle.vehicle_id.to_string() {
-                            warn!("Receiving data for another vehicle");
-                            continue;
-                        }
+    /// Records the rated range once a charge has finished, to estimate the range of a full battery.
+    fn observe_battery(
+        &self,
+        vehicle_data: &TeslaVehicleData,
+        odometer: f64,
+        vehicle_state: &mut VehicleState,
+    ) {
+        let charge_state = match &vehicle_data.charge_state {
+            Some(charge_state) => charge_state,
+            None => return,
+        };
 
-                        let values: Vec<String> = data_update_message
-                            .value
-                            .split(',')
-                            .map(str::to_string)
-                            .collect();
+        if charge_state.charging_state != CHARGING_STATE_COMPLETE
+            || charge_state.battery_level < charge_state.charge_limit_soc - 1.0
+        {
+            return;
+        }
 
-                        if values.len() != 13 {
-                            warn!("Receiving incorrect number of values");
-                            continue;
-                        }
+        let observation = BatteryObservation {
+            observed_at: Utc::now(),
+            odometer,
+            battery_level: charge_state.battery_level,
+            battery_range: charge_state.battery_range,
+            outside_temperature: vehicle_data
+                .climate_state
+                .as_ref()
+                .and_then(|c| c.outside_temp),
+            battery_heater_on: charge_state.battery_heater_on,
+        };
 
-                        return Ok(TeslaVehicleStreamingData {
-                            latitude: values
-                                .get(6)
-                                .unwrap_or(&"0.0".to_string())
-                                .parse()
-                                .unwrap_or(0.0),
-                            longitude: values
-                                .get(7)
-                                .unwrap_or(&"0.0".to_string())
-                                .parse()
-                                .unwrap_or(0.0),
-                            power: values
-                                .get(8)
-                                .unwrap_or(&"0.0".to_string())
-                                .parse::<f64>()
-                                .unwrap_or(0.0)
-                                .abs(),
-                            speed: values
-                                .get(1)
-                                .unwrap_or(&"0.0".to_string())
-                                .parse()
-                                .unwrap_or(0.0),
-                            odometer: values
-                                .get(2)
-                                .unwrap_or(&"0.0".to_string())
-                                .parse()
-                                .unwrap_or(0.0),
-                        });
-                    }
-                    "data:error" => {
-                        return Err(Box::<dyn Error>::from(format!(
-                            "Received error message: {}",
-                            &msg_value["error_type"]
-                        )));
-                    }
-                    _ => {
-                        debug!("Unhandled message type {}", msg_type);
-                    }
-                }
+        if vehicle_state.battery_history.add_observation(observation) {
+            info!(
+                "Added battery observation at {}%, estimated full range is {:?} meters",
+                charge_state.battery_level,
+                vehicle_state.battery_history.estimated_full_range()
+            );
+        }
+    }
+
+    /// Repeats the last measurement of a vehicle that couldn't be polled, marked as unavailable due to
+    /// an error.
+    fn get_last_known_measurement(
+        &self,
+        last_measurements: &Option<Vec<Measurement>>,
+        display_name: Option<String>,
+    ) -> Option<Measurement> {
+        let display_name = display_name.unwrap_or_else(|| DEFAULT_DISPLAY_NAME.to_string());
+
+        let last_measurement = last_measurements
+            .as_ref()?
+            .iter()
+            .find(|lm| lm.samples.iter().any(|s| s.sample_name == display_name))?;
+
+        let mut measurement = last_measurement.clone();
+        measurement.id = Uuid::new_v4().to_string();
+        measurement.measured_at_time = Utc::now();
+
+        for sample in measurement.samples.iter_mut() {
+            if sample.sample_type == SampleType::Availability && sample.sample_name == display_name
+            {
+                sample.value = AVAILABILITY_ERROR;
+            }
+
+            // nothing is being charged while we don't know
+            if sample.sample_type == SampleType::ElectricityConsumption
+                && sample.sample_name == display_name
+                && sample.metric_type == MetricType::Gauge
+            {
+                sample.value = 0.0;
             }
         }
+
+        Some(measurement)
     }
 
     pub fn get_last_values(
@@ -528,82 +2164,699 @@ impl TeslaApiClient {
     }
 }
 
+fn signing_error<E: std::fmt::Display>(e: E) -> TeslaApiError {
+    TeslaApiError::SignedCommand {
+        message: e.to_string(),
+    }
+}
+
+/// Parses a frame of the streaming api, `None` for frames without an update for this vehicle.
+fn parse_streaming_frame(
+    msg_data: &[u8],
+    vehicle_id: usize,
+) -> Result<Option<TeslaVehicleStreamingData>, TeslaApiError> {
+    let msg_value: serde_json::Value = serde_json::from_slice(msg_data)
+        .map_err(|e| TeslaApiError::deserialization(e, &String::from_utf8_lossy(msg_data)))?;
+    let msg_type = match &msg_value["msg_type"] {
+        Value::String(msg_type) => msg_type,
+        _ => return Ok(None),
+    };
+
+    match msg_type.as_str() {
+        "data:update" => {
+            let data_update_message: TeslaStreamingApiMessage = serde_json::from_slice(msg_data)
+                .map_err(|e| {
+                    TeslaApiError::deserialization(e, &String::from_utf8_lossy(msg_data))
+                })?;
+
+            if data_update_message.tag != vehicle_id.to_string() {
+                warn!("Receiving data for another vehicle");
+                return Ok(None);
+            }
+
+            let values: Vec<&str> = data_update_message.value.split(',').collect();
+
+            if values.len() != 13 {
+                warn!("Receiving incorrect number of values");
+                return Ok(None);
+            }
+
+            // fields are empty while the vehicle goes to sleep
+            let optional_value = |index: usize| values[index].parse::<f64>().ok();
+            let value = |index: usize| optional_value(index).unwrap_or(0.0);
+
+            // the streaming api reports miles like the owner api
+            let units = Units::default();
+
+            Ok(Some(TeslaVehicleStreamingData {
+                latitude: optional_value(6),
+                longitude: optional_value(7),
+                power: value(8).abs(),
+                speed: units.distance.to_meters_per_second(value(1)),
+                odometer: units.distance.to_meters(value(2)),
+                soc: value(3),
+            }))
+        }
+        "data:error" => Err(TeslaApiError::StreamingError {
+            error_type: msg_value["error_type"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            value: msg_value["value"].as_str().unwrap_or_default().to_string(),
+        }),
+        _ => {
+            debug!("Unhandled message type {}", msg_type);
+            Ok(None)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::env;
 
-    use crate::model::GeofenceConfig;
+    use crate::model::{AccountStates, TeslaBackend, TeslaRegion};
+    use crate::snapshot::assert_json_snapshot;
+    use crate::tesla_api_error::CommandFailureReason;
+    use crate::vehicle_state_client::VehicleStateClientConfig;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Server};
+    use jarvis_lib::config_client::SetDefaults;
+    use std::convert::Infallible;
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
 
     use super::*;
 
-    #[test]
-    #[ignore]
-    fn vehicle_data() {
-        let tesla_api_client = TeslaApiClient::new();
+    fn local_tesla_api_client() -> TeslaApiClient {
+        let vehicle_state_client = VehicleStateClient::new(
+            VehicleStateClientConfig::new(
+                None,
+                "vehicle-state.json".into(),
+                "jarvis-tesla-exporter".into(),
+                false,
+            )
+            .unwrap(),
+        );
+
+        TeslaApiClient::new(
+            TeslaApiClientConfig::from_env(vehicle_state_client, None, None, None).unwrap(),
+        )
+    }
 
+    fn account_from_env() -> AccountConfig {
         let refresh_token = env::var("TESLA_AUTH_REFRESH_TOKEN")
             .expect("Environment variable TESLA_AUTH_REFRESH_TOKEN not set");
 
-        let config: Config = Config {
-            refresh_token: refresh_token,
-            geofences: vec![GeofenceConfig {
-                location: "My Home".into(),
-                latitude: 0.0,
-                longitude: 0.0,
-                geofence_radius_meters: 100.0,
-            }],
+        account(&refresh_token)
+    }
+
+    /// Serves the responses in order and records the method, path and body of each request.
+    fn mock_tesla_api(responses: Vec<String>) -> (AccountConfig, Arc<Mutex<Vec<String>>>) {
+        mock_tesla_api_responses(
+            responses
+                .into_iter()
+                .map(|response| hyper::Response::new(Body::from(response)))
+                .collect(),
+        )
+    }
+
+    /// Like `mock_tesla_api`, for responses with another status or headers.
+    fn mock_tesla_api_responses(
+        responses: Vec<hyper::Response<Body>>,
+    ) -> (AccountConfig, Arc<Mutex<Vec<String>>>) {
+        let requests: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(vec![]));
+        let server_requests = requests.clone();
+        let responses = Arc::new(Mutex::new(responses.into_iter()));
+        let make_service = make_service_fn(move |_| {
+            let requests = server_requests.clone();
+            let responses = responses.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let requests = requests.clone();
+                    let responses = responses.clone();
+                    async move {
+                        let method = request.method().to_string();
+                        let path = request.uri().path().to_string();
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        requests.lock().unwrap().push(format!(
+                            "{} {} {}",
+                            method,
+                            path,
+                            String::from_utf8(body.to_vec()).unwrap()
+                        ));
+                        let response = responses
+                            .lock()
+                            .unwrap()
+                            .next()
+                            .unwrap_or_else(|| hyper::Response::new(Body::from("{}")));
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let account = AccountConfig {
+            api_base_url: Some(format!("http://{}", server.local_addr())),
+            ..account("")
+        };
+        tokio::spawn(server);
+
+        (account, requests)
+    }
+
+    fn account(refresh_token: &str) -> AccountConfig {
+        AccountConfig {
+            name: "default".into(),
+            refresh_token: refresh_token.into(),
+            backend: TeslaBackend::OwnerApi,
+            region: TeslaRegion::Europe,
+            refresh_token_file: None,
+            refresh_token_env: None,
+            refresh_token_secret_ref: None,
+            client_id: None,
+            api_base_url: None,
+            command_key_file: None,
             vehicle_ids: vec!["23498074342".into()],
+        }
+    }
+
+    fn test_timeouts() -> TeslaApiTimeouts {
+        TeslaApiTimeouts {
+            connect: Duration::from_secs(1),
+            request: Duration::from_secs(1),
+            vehicle_data: Duration::from_secs(1),
+            streaming: Duration::from_secs(1),
+            wake_up: Duration::from_secs(1),
+        }
+    }
+
+    fn token() -> TeslaAccessToken {
+        TeslaAccessToken {
+            access_token: "access".into(),
+            token_type: "Bearer".into(),
+            expires_in: 28800,
+            refresh_token: None,
+        }
+    }
+
+    fn vehicle(state: &str) -> TeslaVehicle {
+        TeslaVehicle {
+            id: 23498074342,
+            vehicle_id: 1,
+            vin: "5YJ3E7EB2KF000000".into(),
+            display_name: Some("Tessie".into()),
+            state: TeslaVehicleState::from(state.to_string()),
+            in_service: false,
+        }
+    }
+
+    fn vehicle_response(state: &str) -> String {
+        serde_json::to_string(&TeslaApiResponse {
+            response: vehicle(state),
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn set_charge_limit_posts_command_with_body() {
+        let tesla_api_client = local_tesla_api_client();
+        let (account, requests) = mock_tesla_api(vec![
+            r#"{"response": {"result": true, "reason": ""}}"#.into(),
+        ]);
+
+        // act
+        tesla_api_client
+            .set_charge_limit(&account, &token(), &vehicle("online"), 80)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![r#"POST /api/1/vehicles/23498074342/command/set_charge_limit {"percent":80}"#]
+        );
+    }
+
+    #[tokio::test]
+    async fn charge_start_returns_typed_failure_reason() {
+        let tesla_api_client = local_tesla_api_client();
+        let (account, _) = mock_tesla_api(vec![
+            r#"{"response": {"result": false, "reason": "is_charging"}}"#.into(),
+            r#"{"response": {"result": false, "reason": "disconnected"}}"#.into(),
+        ]);
+
+        // act
+        let already_charging = tesla_api_client
+            .charge_start(&account, &token(), &vehicle("online"))
+            .await
+            .unwrap_err();
+        let disconnected = tesla_api_client
+            .charge_start(&account, &token(), &vehicle("online"))
+            .await
+            .unwrap_err();
+
+        assert!(already_charging.is_already_done());
+        assert!(matches!(
+            disconnected,
+            TeslaApiError::CommandFailed {
+                reason: CommandFailureReason::Disconnected,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn charge_start_is_not_resent_after_server_error() {
+        let tesla_api_client = local_tesla_api_client();
+        let (account, requests) = mock_tesla_api_responses(vec![hyper::Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::empty())
+            .unwrap()]);
+
+        // act
+        let result = tesla_api_client
+            .charge_start(&account, &token(), &vehicle("online"))
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn oversized_retry_after_fails_later_requests_of_the_account_right_away() {
+        let tesla_api_client = local_tesla_api_client();
+        let (account, requests) = mock_tesla_api_responses(vec![
+            hyper::Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header(RETRY_AFTER, "3600")
+                .body(Body::empty())
+                .unwrap(),
+            hyper::Response::new(Body::from(vehicle_response("online"))),
+        ]);
+        let other_account = AccountConfig {
+            name: "other".into(),
+            ..account.clone()
+        };
+
+        // act
+        let rate_limited = tesla_api_client
+            .get_vehicle(&account, &token(), "23498074342")
+            .await
+            .unwrap_err();
+        let started_at = Instant::now();
+        let exhausted = tesla_api_client
+            .get_vehicle(&account, &token(), "23498074342")
+            .await
+            .unwrap_err();
+        let other_vehicle = tesla_api_client
+            .get_vehicle(&other_account, &token(), "23498074342")
+            .await
+            .unwrap();
+
+        assert!(matches!(rate_limited, TeslaApiError::RateLimited { .. }));
+        assert!(matches!(exhausted, TeslaApiError::RateLimited { .. }));
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        assert_eq!(other_vehicle.state, TeslaVehicleState::Online);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn wake_up_polls_until_online() {
+        let tesla_api_client = local_tesla_api_client();
+        let (account, requests) =
+            mock_tesla_api(vec![vehicle_response("asleep"), vehicle_response("online")]);
+
+        // act
+        let vehicle = tesla_api_client
+            .wake_up(&account, &token(), &vehicle("asleep"))
+            .await
+            .unwrap();
+
+        assert_eq!(vehicle.state, TeslaVehicleState::Online);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn wake_up_times_out_while_asleep() {
+        let vehicle_state_client = VehicleStateClient::new(
+            VehicleStateClientConfig::new(
+                None,
+                "vehicle-state.json".into(),
+                "jarvis-tesla-exporter".into(),
+                false,
+            )
+            .unwrap(),
+        );
+        let tesla_api_client = TeslaApiClient::new(
+            TeslaApiClientConfig::new(
+                vehicle_state_client,
+                None,
+                None,
+                None,
+                None,
+                test_timeouts(),
+            )
+            .unwrap(),
+        );
+        let (account, _) = mock_tesla_api(vec![vehicle_response("asleep")]);
+
+        // act
+        let result = tesla_api_client
+            .wake_up(&account, &token(), &vehicle("asleep"))
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(TeslaApiError::WakeUpTimeout { seconds: 1 })
+        ));
+    }
+
+    #[test]
+    fn parse_streaming_frame_leaves_out_empty_coordinates_of_sleeping_vehicle() {
+        let frame = fs::read("test-data/streaming_update_asleep.json").unwrap();
+
+        // act
+        let update = parse_streaming_frame(&frame, 1689111262082909).unwrap();
+
+        assert_json_snapshot("streaming_update_asleep", &update);
+    }
+
+    #[test]
+    fn parse_streaming_frame_skips_updates_of_other_vehicles() {
+        let frame = fs::read("test-data/streaming_update_asleep.json").unwrap();
+
+        // act
+        let update = parse_streaming_frame(&frame, 1).unwrap();
+
+        assert!(update.is_none());
+    }
+
+    #[test]
+    fn parse_streaming_frame_returns_data_errors() {
+        let frames: Vec<Value> =
+            serde_json::from_str(&fs::read_to_string("test-data/streaming_errors.json").unwrap())
+                .unwrap();
+
+        // act
+        let errors: Vec<TeslaApiError> = frames
+            .iter()
+            .map(|frame| {
+                parse_streaming_frame(frame.to_string().as_bytes(), 1689111262082909).unwrap_err()
+            })
+            .collect();
+
+        let errors: Vec<(String, bool)> = errors
+            .iter()
+            .map(|e| (e.to_string(), e.is_vehicle_unavailable()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    "Received error message vehicle_disconnected: disconnected".to_string(),
+                    true
+                ),
+                (
+                    "Received error message vehicle_error: Vehicle is offline".to_string(),
+                    true
+                ),
+                (
+                    "Received error message client_error: Can't validate token. ".to_string(),
+                    false
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_streaming_frame_ignores_control_messages() {
+        // act
+        let update = parse_streaming_frame(
+            br#"{"msg_type":"control:hello","connection_timeout":30000}"#,
+            1689111262082909,
+        )
+        .unwrap();
+
+        assert!(update.is_none());
+    }
+
+    #[tokio::test]
+    async fn replays_cassette_of_online_vehicle() {
+        let vehicle_state_client = VehicleStateClient::new(
+            VehicleStateClientConfig::new(
+                None,
+                "test-data/cassettes/vehicle-state.json".into(),
+                "jarvis-tesla-exporter".into(),
+                true,
+            )
+            .unwrap(),
+        );
+        let tesla_api_client = TeslaApiClient::new(
+            TeslaApiClientConfig::new(
+                vehicle_state_client,
+                None,
+                None,
+                None,
+                Some(CassetteMode::replay("test-data/cassettes/online-vehicle.json").unwrap()),
+                test_timeouts(),
+            )
+            .unwrap(),
+        );
+        let mut config: Config =
+            serde_yaml::from_str(&fs::read_to_string("test-data/test-config.yaml").unwrap())
+                .unwrap();
+        config.set_defaults();
+
+        // act
+        let measurements = tesla_api_client
+            .get_measurements_async(config, None)
+            .await
+            .unwrap();
+
+        assert_eq!(measurements.len(), 1);
+        // coordinates are redacted in cassettes
+        assert_eq!(measurements[0].location, LOCATION_OTHER);
+        let value = |sample_type: SampleType, metric_type: MetricType| {
+            measurements[0]
+                .samples
+                .iter()
+                .find(|s| {
+                    s.sample_type == sample_type
+                        && s.metric_type == metric_type
+                        && s.sample_name == DEFAULT_DISPLAY_NAME
+                })
+                .map(|s| s.value)
+        };
+        assert_eq!(
+            value(SampleType::ElectricityConsumption, MetricType::Counter),
+            Some(30.23 * 1000.0 * 3600.0)
+        );
+        assert_eq!(
+            value(SampleType::Availability, MetricType::Gauge),
+            Some(1.0)
+        );
+    }
+
+    #[tokio::test]
+    async fn refreshes_revoked_access_token_once() {
+        let vehicle_state_client = VehicleStateClient::new(
+            VehicleStateClientConfig::new(
+                None,
+                "test-data/cassettes/vehicle-state.json".into(),
+                "jarvis-tesla-exporter".into(),
+                true,
+            )
+            .unwrap(),
+        );
+        let mut config: Config =
+            serde_yaml::from_str(&fs::read_to_string("test-data/test-config.yaml").unwrap())
+                .unwrap();
+        config.set_defaults();
+        let account = config.accounts[0]
+            .with_resolved_refresh_token()
+            .await
+            .unwrap();
+        let mut account_states = AccountStates::new();
+        account_states.insert(
+            account.name.clone(),
+            AccountState {
+                configured_refresh_token_fingerprint: Some(account.refresh_token_fingerprint()),
+                refresh_token: None,
+                access_token: Some("revoked".into()),
+                access_token_expires_at: Some(Utc::now() + chrono::Duration::hours(8)),
+            },
+        );
+        vehicle_state_client
+            .store_account_states(&account_states)
+            .await
+            .unwrap();
+        let tesla_api_client = TeslaApiClient::new(
+            TeslaApiClientConfig::new(
+                vehicle_state_client,
+                None,
+                None,
+                None,
+                Some(
+                    CassetteMode::replay("test-data/cassettes/revoked-access-token.json").unwrap(),
+                ),
+                test_timeouts(),
+            )
+            .unwrap(),
+        );
+
+        // act
+        let measurements = tesla_api_client
+            .get_measurements_async(config, None)
+            .await
+            .unwrap();
+
+        assert_eq!(measurements.len(), 1);
+        assert!(measurements[0]
+            .samples
+            .iter()
+            .any(|s| s.sample_type == SampleType::Availability && s.value == 0.0));
+        let account_states = tesla_api_client
+            .config
+            .vehicle_state_client
+            .read_account_states()
+            .await
+            .unwrap();
+        assert_ne!(
+            account_states[&account.name].access_token.as_deref(),
+            Some("revoked")
+        );
+    }
+
+    #[test]
+    fn get_last_known_measurement_marks_vehicle_as_failed() {
+        let tesla_api_client = local_tesla_api_client();
+
+        let sample = |sample_type: SampleType, metric_type: MetricType, value: f64| Sample {
+            entity_type: EntityType::Device,
+            entity_name: "jarvis-tesla-exporter".into(),
+            sample_type,
+            sample_name: "Tessie".into(),
+            metric_type,
+            value,
         };
 
+        let last_measurements = Some(vec![Measurement {
+            id: "last".into(),
+            source: "jarvis-tesla-exporter".into(),
+            location: "My Home".into(),
+            samples: vec![
+                sample(
+                    SampleType::ElectricityConsumption,
+                    MetricType::Gauge,
+                    11000.0,
+                ),
+                sample(
+                    SampleType::ElectricityConsumption,
+                    MetricType::Counter,
+                    3600000.0,
+                ),
+                sample(SampleType::Availability, MetricType::Gauge, 1.0),
+            ],
+            measured_at_time: Utc::now(),
+        }]);
+
+        // act
+        let measurement = tesla_api_client
+            .get_last_known_measurement(&last_measurements, Some("Tessie".into()))
+            .unwrap();
+
+        assert_ne!(measurement.id, "last");
+        assert_eq!(measurement.location, "My Home");
+        assert_eq!(measurement.samples[0].value, 0.0);
+        assert_eq!(measurement.samples[1].value, 3600000.0);
+        assert_eq!(measurement.samples[2].value, AVAILABILITY_ERROR);
+        assert!(tesla_api_client
+            .get_last_known_measurement(&last_measurements, Some("Other car".into()))
+            .is_none());
+    }
+
+    #[test]
+    fn push_last_known_measurement_records_polling_failed_availability() {
+        let tesla_api_client = local_tesla_api_client();
+        let mut state = ExporterState::default();
+        state.vehicles.insert(
+            "1".into(),
+            VehicleState {
+                display_name: Some("Tessie".into()),
+                status: Some(VehicleStatus {
+                    vin: "5YJ3E7EB2KF000000".into(),
+                    display_name: "Tessie".into(),
+                    location: "My Home".into(),
+                    availability: 1.0,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+        let mut measurements = vec![];
+
+        // act
+        tesla_api_client.push_last_known_measurement(&mut measurements, &state, &None, "1");
+
+        let metrics = tesla_api_client.metrics().gather().unwrap();
+        assert!(metrics
+            .lines()
+            .any(|line| line.starts_with("tesla_availability{")
+                && line.contains("vin=\"5YJ3E7EB2KF000000\"")
+                && line.ends_with(" -3")));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn vehicle_data() {
+        let tesla_api_client = local_tesla_api_client();
+
+        let account = account_from_env();
+
         // act
         let token = tesla_api_client
-            .get_access_token(&config)
+            .get_access_token(&account, &account.refresh_token)
+            .await
             .expect("Failed getting access token");
 
         let vehicles = tesla_api_client
-            .get_vehicles(&token)
+            .get_vehicles(&account, &token)
+            .await
             .expect("Failed retrieving vehicles");
 
         for vehicle in vehicles {
             let vehicle_data = tesla_api_client
-                .get_vehicle_data(&token, &vehicle)
+                .get_vehicle_data(&account, &token, &vehicle)
+                .await
                 .expect("Failed getting vehicle data");
 
             debug!("{:?}", vehicle_data);
         }
     }
 
-    #[test]
+    #[tokio::test]
     #[ignore]
-    fn get_streaming_data() {
-        let tesla_api_client = TeslaApiClient::new();
-
-        let refresh_token = env::var("TESLA_AUTH_REFRESH_TOKEN")
-            .expect("Environment variable TESLA_AUTH_REFRESH_TOKEN not set");
+    async fn get_streaming_data() {
+        let tesla_api_client = local_tesla_api_client();
 
-        let config: Config = Config {
-            refresh_token: refresh_token,
-            geofences: vec![GeofenceConfig {
-                location: "My Home".into(),
-                latitude: 0.0,
-                longitude: 0.0,
-                geofence_radius_meters: 100.0,
-            }],
-            vehicle_ids: vec!["23498074342".into()],
-        };
+        let account = account_from_env();
 
         // act
         let token = tesla_api_client
-            .get_access_token(&config)
+            .get_access_token(&account, &account.refresh_token)
+            .await
             .expect("Failed getting access token");
 
         let vehicles = tesla_api_client
-            .get_vehicles(&token)
+            .get_vehicles(&account, &token)
+            .await
             .expect("Failed retrieving vehicles");
 
         for vehicle in vehicles {
             let vehicle_charge_state = tesla_api_client
-                .get_streaming_data(&token, &vehicle)
+                .get_streaming_data(&account, &token, &vehicle)
+                .await
                 .expect("Failed getting vehicle charge state");
 
             debug!("{:?}", vehicle_charge_state);
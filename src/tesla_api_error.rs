@@ -0,0 +1,390 @@
+use reqwest::StatusCode;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite;
+
+const BODY_EXCERPT_LENGTH: usize = 200;
+const STREAMING_ERROR_VEHICLE_DISCONNECTED: &str = "vehicle_disconnected";
+const STREAMING_ERROR_VEHICLE_ERROR: &str = "vehicle_error";
+const STREAMING_VALUE_VEHICLE_OFFLINE: &str = "Vehicle is offline";
+
+#[derive(Debug)]
+pub enum TeslaApiError {
+    /// 401, the access or refresh token is invalid or revoked
+    Unauthorized {
+        body: String,
+    },
+    /// 408, the vehicle is asleep or offline
+    VehicleUnavailable,
+    /// 429, too many requests
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    /// 5xx, the tesla api is down
+    ServerError {
+        status: StatusCode,
+        body: String,
+    },
+    /// any other non-success status
+    UnexpectedStatus {
+        status: StatusCode,
+        body: String,
+    },
+    /// request failed before a response was received
+    Transport(reqwest::Error),
+    /// response body doesn't match the expected structure
+    Deserialization {
+        source: serde_json::Error,
+        body_excerpt: String,
+    },
+    /// boxed, as it would make every result with this error large
+    WebSocket(Box<tungstenite::Error>),
+    StreamingClosed,
+    /// `data:error` message from the streaming api
+    StreamingError {
+        error_type: String,
+        value: String,
+    },
+    StreamingTimeout {
+        seconds: u64,
+    },
+    /// the vehicle received a command but didn't execute it
+    CommandFailed {
+        command: String,
+        reason: CommandFailureReason,
+    },
+    /// the vehicle didn't come online after a wake up
+    WakeUpTimeout {
+        seconds: u64,
+    },
+    /// signing a command or verifying the session with the vehicle failed
+    SignedCommand {
+        message: String,
+    },
+    /// replaying a cassette that has no recorded response for the request
+    CassetteMiss {
+        request: String,
+    },
+}
+
+/// `reason` of a command response with `result: false`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandFailureReason {
+    AlreadyCharging,
+    NotCharging,
+    ChargingComplete,
+    /// the setting already has the requested value
+    AlreadySet,
+    /// the charge cable isn't connected
+    Disconnected,
+    /// someone is in the vehicle
+    UserPresent,
+    Other(String),
+}
+
+impl From<&str> for CommandFailureReason {
+    fn from(reason: &str) -> Self {
+        use CommandFailureReason::*;
+
+        match reason {
+            "is_charging" => AlreadyCharging,
+            "not_charging" => NotCharging,
+            "complete" => ChargingComplete,
+            "already_set" => AlreadySet,
+            "disconnected" => Disconnected,
+            "user_present" => UserPresent,
+            reason => Other(reason.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for CommandFailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandFailureReason::AlreadyCharging => write!(f, "already charging"),
+            CommandFailureReason::NotCharging => write!(f, "not charging"),
+            CommandFailureReason::ChargingComplete => write!(f, "charging complete"),
+            CommandFailureReason::AlreadySet => write!(f, "already set"),
+            CommandFailureReason::Disconnected => write!(f, "charge cable disconnected"),
+            CommandFailureReason::UserPresent => write!(f, "user present"),
+            CommandFailureReason::Other(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl TeslaApiError {
+    /// Maps a non-success response to an error.
+    pub fn from_status(status: StatusCode, body: &str, retry_after: Option<Duration>) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => TeslaApiError::Unauthorized {
+                body: excerpt(body),
+            },
+            StatusCode::REQUEST_TIMEOUT => TeslaApiError::VehicleUnavailable,
+            StatusCode::TOO_MANY_REQUESTS => TeslaApiError::RateLimited { retry_after },
+            status if status.is_server_error() => TeslaApiError::ServerError {
+                status,
+                body: excerpt(body),
+            },
+            status => TeslaApiError::UnexpectedStatus {
+                status,
+                body: excerpt(body),
+            },
+        }
+    }
+
+    pub fn deserialization(source: serde_json::Error, body: &str) -> Self {
+        TeslaApiError::Deserialization {
+            source,
+            body_excerpt: excerpt(body),
+        }
+    }
+
+    /// Whether the vehicle is asleep or offline rather than the api failing. A stream that stays
+    /// silent or gets closed means the vehicle fell asleep while connecting.
+    pub fn is_vehicle_unavailable(&self) -> bool {
+        match self {
+            TeslaApiError::VehicleUnavailable
+            | TeslaApiError::StreamingClosed
+            | TeslaApiError::StreamingTimeout { .. } => true,
+            TeslaApiError::StreamingError { error_type, value } => {
+                error_type == STREAMING_ERROR_VEHICLE_DISCONNECTED
+                    || (error_type == STREAMING_ERROR_VEHICLE_ERROR
+                        && value.trim() == STREAMING_VALUE_VEHICLE_OFFLINE)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the vehicle was already in the state a command asked for, like starting charging
+    /// while it's charging.
+    pub fn is_already_done(&self) -> bool {
+        matches!(
+            self,
+            TeslaApiError::CommandFailed {
+                reason: CommandFailureReason::AlreadyCharging
+                    | CommandFailureReason::NotCharging
+                    | CommandFailureReason::AlreadySet,
+                ..
+            }
+        )
+    }
+
+    /// Whether trying again can succeed without changing anything.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            TeslaApiError::RateLimited { .. }
+                | TeslaApiError::ServerError { .. }
+                | TeslaApiError::Transport(_)
+                | TeslaApiError::WebSocket(_)
+                | TeslaApiError::StreamingClosed
+                | TeslaApiError::StreamingTimeout { .. }
+        )
+    }
+}
+
+impl fmt::Display for TeslaApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TeslaApiError::Unauthorized { body } => write!(f, "Unauthorized: {}", body),
+            TeslaApiError::VehicleUnavailable => write!(f, "Vehicle unavailable"),
+            TeslaApiError::RateLimited { retry_after } => match retry_after {
+                Some(retry_after) => write!(
+                    f,
+                    "Rate limited, retry after {} seconds",
+                    retry_after.as_secs()
+                ),
+                None => write!(f, "Rate limited"),
+            },
+            TeslaApiError::ServerError { status, body } => {
+                write!(f, "Tesla api returned {}: {}", status, body)
+            }
+            TeslaApiError::UnexpectedStatus { status, body } => {
+                write!(f, "Unexpected status {}: {}", status, body)
+            }
+            TeslaApiError::Transport(e) => write!(f, "Request failed: {}", e),
+            TeslaApiError::Deserialization {
+                source,
+                body_excerpt,
+            } => write!(
+                f,
+                "Failed deserializing response: {} in {}",
+                source, body_excerpt
+            ),
+            TeslaApiError::WebSocket(e) => write!(f, "Streaming connection failed: {}", e),
+            TeslaApiError::StreamingClosed => write!(f, "Received close message"),
+            TeslaApiError::StreamingError { error_type, value } => {
+                write!(f, "Received error message {}: {}", error_type, value)
+            }
+            TeslaApiError::StreamingTimeout { seconds } => {
+                write!(f, "Timed out after {} seconds", seconds)
+            }
+            TeslaApiError::CommandFailed { command, reason } => {
+                write!(f, "Command {} failed: {}", command, reason)
+            }
+            TeslaApiError::WakeUpTimeout { seconds } => {
+                write!(f, "Vehicle didn't wake up within {} seconds", seconds)
+            }
+            TeslaApiError::SignedCommand { message } => {
+                write!(f, "Signed command failed: {}", message)
+            }
+            TeslaApiError::CassetteMiss { request } => {
+                write!(f, "No recorded response in the cassette for {}", request)
+            }
+        }
+    }
+}
+
+impl Error for TeslaApiError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TeslaApiError::Transport(e) => Some(e),
+            TeslaApiError::Deserialization { source, .. } => Some(source),
+            TeslaApiError::WebSocket(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for TeslaApiError {
+    fn from(e: reqwest::Error) -> Self {
+        TeslaApiError::Transport(e)
+    }
+}
+
+impl From<tungstenite::Error> for TeslaApiError {
+    fn from(e: tungstenite::Error) -> Self {
+        TeslaApiError::WebSocket(Box::new(e))
+    }
+}
+
+fn excerpt(body: &str) -> String {
+    body.chars().take(BODY_EXCERPT_LENGTH).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn from_status_maps_status_codes() {
+        assert!(matches!(
+            TeslaApiError::from_status(StatusCode::UNAUTHORIZED, "", None),
+            TeslaApiError::Unauthorized { .. }
+        ));
+        assert!(matches!(
+            TeslaApiError::from_status(StatusCode::REQUEST_TIMEOUT, "", None),
+            TeslaApiError::VehicleUnavailable
+        ));
+        assert!(matches!(
+            TeslaApiError::from_status(
+                StatusCode::TOO_MANY_REQUESTS,
+                "",
+                Some(Duration::from_secs(30))
+            ),
+            TeslaApiError::RateLimited {
+                retry_after: Some(_)
+            }
+        ));
+        assert!(matches!(
+            TeslaApiError::from_status(StatusCode::BAD_GATEWAY, "", None),
+            TeslaApiError::ServerError { .. }
+        ));
+        assert!(matches!(
+            TeslaApiError::from_status(StatusCode::NOT_FOUND, "", None),
+            TeslaApiError::UnexpectedStatus { .. }
+        ));
+    }
+
+    #[test]
+    fn is_vehicle_unavailable_for_408_and_disconnected_stream() {
+        assert!(TeslaApiError::VehicleUnavailable.is_vehicle_unavailable());
+        assert!(TeslaApiError::StreamingClosed.is_vehicle_unavailable());
+        assert!(TeslaApiError::StreamingTimeout { seconds: 30 }.is_vehicle_unavailable());
+        assert!(TeslaApiError::StreamingError {
+            error_type: "vehicle_disconnected".into(),
+            value: "".into(),
+        }
+        .is_vehicle_unavailable());
+        assert!(TeslaApiError::StreamingError {
+            error_type: "vehicle_error".into(),
+            value: "Vehicle is offline".into(),
+        }
+        .is_vehicle_unavailable());
+        assert!(!TeslaApiError::StreamingError {
+            error_type: "vehicle_error".into(),
+            value: "".into(),
+        }
+        .is_vehicle_unavailable());
+        assert!(!TeslaApiError::Unauthorized { body: "".into() }.is_vehicle_unavailable());
+    }
+
+    #[test]
+    fn from_status_maps_vehicle_unavailable_body() {
+        let body = fs::read_to_string("test-data/vehicle_unavailable_response.json").unwrap();
+
+        // act
+        let error = TeslaApiError::from_status(StatusCode::REQUEST_TIMEOUT, &body, None);
+
+        assert!(matches!(error, TeslaApiError::VehicleUnavailable));
+        assert!(error.is_vehicle_unavailable());
+        assert!(!error.is_retryable());
+    }
+
+    #[test]
+    fn is_retryable_excludes_unauthorized() {
+        assert!(!TeslaApiError::Unauthorized { body: "".into() }.is_retryable());
+        assert!(TeslaApiError::RateLimited { retry_after: None }.is_retryable());
+        assert!(TeslaApiError::StreamingClosed.is_retryable());
+    }
+
+    #[test]
+    fn command_failure_reason_maps_known_reasons() {
+        // act
+        let reasons: Vec<CommandFailureReason> = [
+            "is_charging",
+            "already_set",
+            "cabin comfort remote settings not enabled",
+        ]
+        .iter()
+        .map(|reason| CommandFailureReason::from(*reason))
+        .collect();
+
+        assert_eq!(
+            reasons,
+            vec![
+                CommandFailureReason::AlreadyCharging,
+                CommandFailureReason::AlreadySet,
+                CommandFailureReason::Other("cabin comfort remote settings not enabled".into())
+            ]
+        );
+        assert!(TeslaApiError::CommandFailed {
+            command: "charge_start".into(),
+            reason: CommandFailureReason::AlreadyCharging,
+        }
+        .is_already_done());
+        assert!(!TeslaApiError::CommandFailed {
+            command: "charge_start".into(),
+            reason: CommandFailureReason::Disconnected,
+        }
+        .is_already_done());
+    }
+
+    #[test]
+    fn deserialization_keeps_body_excerpt() {
+        let body = "x".repeat(500);
+        let source = serde_json::from_str::<serde_json::Value>(&body).unwrap_err();
+
+        // act
+        let error = TeslaApiError::deserialization(source, &body);
+
+        match error {
+            TeslaApiError::Deserialization { body_excerpt, .. } => {
+                assert_eq!(body_excerpt.len(), BODY_EXCERPT_LENGTH)
+            }
+            _ => panic!("expected deserialization error"),
+        }
+    }
+}
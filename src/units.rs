@@ -0,0 +1,116 @@
+use crate::model::TeslaVehicleGuiSettings;
+
+const METERS_PER_MILE: f64 = 1609.344;
+const METERS_PER_KILOMETER: f64 = 1000.0;
+const SECONDS_PER_HOUR: f64 = 3600.0;
+const PASCAL_PER_BAR: f64 = 100000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DistanceUnit {
+    Miles,
+    Kilometers,
+}
+
+impl DistanceUnit {
+    /// Parses gui settings like `mi/hr` or `km/hr`.
+    pub fn from_gui_units(units: &str) -> Self {
+        if units.to_lowercase().starts_with("km") {
+            DistanceUnit::Kilometers
+        } else {
+            DistanceUnit::Miles
+        }
+    }
+
+    pub fn to_meters(self, value: f64) -> f64 {
+        match self {
+            DistanceUnit::Miles => value * METERS_PER_MILE,
+            DistanceUnit::Kilometers => value * METERS_PER_KILOMETER,
+        }
+    }
+
+    /// Converts a speed per hour in this unit to meters per second.
+    pub fn to_meters_per_second(self, value: f64) -> f64 {
+        self.to_meters(value) / SECONDS_PER_HOUR
+    }
+}
+
+/// Converts a tire pressure, which the api always reports in bar, to pascal.
+pub fn bar_to_pascal(value: f64) -> f64 {
+    value * PASCAL_PER_BAR
+}
+
+/// Units in which the api reports a vehicle's values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Units {
+    /// odometer, ranges and speeds
+    pub distance: DistanceUnit,
+    /// range added per hour while charging
+    pub charge_rate: DistanceUnit,
+}
+
+impl Default for Units {
+    fn default() -> Self {
+        Self {
+            distance: DistanceUnit::Miles,
+            charge_rate: DistanceUnit::Miles,
+        }
+    }
+}
+
+impl Units {
+    /// The api reports the odometer, ranges and speeds in miles, temperatures in celsius and tire
+    /// pressures in bar whatever the car displays; only the charge rate follows `gui_settings`.
+    pub fn from_gui_settings(gui_settings: Option<&TeslaVehicleGuiSettings>) -> Self {
+        let mut units = Self::default();
+
+        if let Some(gui_charge_rate_units) =
+            gui_settings.and_then(|g| g.gui_charge_rate_units.as_ref())
+        {
+            units.charge_rate = DistanceUnit::from_gui_units(gui_charge_rate_units);
+        }
+
+        units
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distance_unit_from_gui_units() {
+        assert_eq!(
+            DistanceUnit::from_gui_units("km/hr"),
+            DistanceUnit::Kilometers
+        );
+        assert_eq!(DistanceUnit::from_gui_units("mi/hr"), DistanceUnit::Miles);
+    }
+
+    #[test]
+    fn distance_unit_to_meters() {
+        assert!((DistanceUnit::Miles.to_meters(10.0) - 16093.44).abs() < 1e-9);
+        assert_eq!(DistanceUnit::Kilometers.to_meters(10.0), 10000.0);
+        assert_eq!(DistanceUnit::Kilometers.to_meters_per_second(36.0), 10.0);
+    }
+
+    #[test]
+    fn bar_to_pascal_converts_tire_pressure() {
+        assert_eq!(bar_to_pascal(3.0), 300000.0);
+    }
+
+    #[test]
+    fn from_gui_settings_reads_charge_rate_units() {
+        let gui_settings = TeslaVehicleGuiSettings {
+            gui_charge_rate_units: Some("km/hr".into()),
+            gui_distance_units: Some("km/hr".into()),
+            gui_temperature_units: Some("C".into()),
+            gui_tirepressure_units: Some("Bar".into()),
+        };
+
+        // act
+        let units = Units::from_gui_settings(Some(&gui_settings));
+
+        assert_eq!(units.charge_rate, DistanceUnit::Kilometers);
+        assert_eq!(units.distance, DistanceUnit::Miles);
+    }
+}
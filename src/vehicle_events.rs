@@ -0,0 +1,281 @@
+use crate::model::VehicleStatus;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Bumped on breaking changes to the event payload, so consumers can tell versions apart.
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+
+const LOCATION_OTHER: &str = "Other";
+
+/// A transition of a vehicle, detected between two polls.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct VehicleEvent {
+    pub schema_version: u32,
+    pub id: String,
+    pub vin: String,
+    pub display_name: String,
+    /// geofence the vehicle is in, or `Other`
+    pub location: String,
+    pub occurred_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: VehicleEventKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum VehicleEventKind {
+    /// `power` in watt
+    ChargingStarted {
+        power: f64,
+    },
+    /// `energy` added during the session in joule
+    ChargingStopped {
+        energy: f64,
+    },
+    PlugConnected,
+    PlugDisconnected,
+    ArrivedAtGeofence {
+        geofence: String,
+    },
+    LeftGeofence {
+        geofence: String,
+    },
+    WokeUp,
+    FellAsleep,
+    SoftwareUpdateAvailable {
+        version: String,
+    },
+    UnlockedWhileAway {
+        latitude: Option<f64>,
+        longitude: Option<f64>,
+    },
+}
+
+impl VehicleEvent {
+    fn new(status: &VehicleStatus, kind: VehicleEventKind) -> Self {
+        Self {
+            schema_version: EVENT_SCHEMA_VERSION,
+            id: Uuid::new_v4().to_string(),
+            vin: status.vin.clone(),
+            display_name: status.display_name.clone(),
+            location: status.location.clone(),
+            occurred_at: Utc::now(),
+            kind,
+        }
+    }
+}
+
+/// Events for the transitions from the status of the previous poll; none for the first poll of a vehicle.
+pub fn detect_events(previous: &VehicleStatus, current: &VehicleStatus) -> Vec<VehicleEvent> {
+    let mut kinds: Vec<VehicleEventKind> = vec![];
+
+    let was_awake = is_awake(previous);
+    let awake = is_awake(current);
+    if !was_awake && awake {
+        kinds.push(VehicleEventKind::WokeUp);
+    }
+
+    if previous.charger_power <= 0.0 && current.charger_power > 0.0 {
+        kinds.push(VehicleEventKind::ChargingStarted {
+            power: current.charger_power,
+        });
+    } else if previous.charger_power > 0.0 && current.charger_power <= 0.0 {
+        kinds.push(VehicleEventKind::ChargingStopped {
+            energy: previous.charge_energy_added,
+        });
+    }
+
+    match (previous.plugged_in, current.plugged_in) {
+        (Some(false), Some(true)) => kinds.push(VehicleEventKind::PlugConnected),
+        (Some(true), Some(false)) => kinds.push(VehicleEventKind::PlugDisconnected),
+        _ => {}
+    }
+
+    if previous.location != current.location {
+        if previous.location != LOCATION_OTHER {
+            kinds.push(VehicleEventKind::LeftGeofence {
+                geofence: previous.location.clone(),
+            });
+        }
+        if current.location != LOCATION_OTHER {
+            kinds.push(VehicleEventKind::ArrivedAtGeofence {
+                geofence: current.location.clone(),
+            });
+        }
+    }
+
+    if let Some(version) = &current.software_update_version {
+        if previous.software_update_version.as_ref() != Some(version) {
+            kinds.push(VehicleEventKind::SoftwareUpdateAvailable {
+                version: version.clone(),
+            });
+        }
+    }
+
+    if is_unlocked_while_away(current) && !is_unlocked_while_away(previous) {
+        kinds.push(VehicleEventKind::UnlockedWhileAway {
+            latitude: current.latitude,
+            longitude: current.longitude,
+        });
+    }
+
+    if was_awake && !awake {
+        kinds.push(VehicleEventKind::FellAsleep);
+    }
+
+    kinds
+        .into_iter()
+        .map(|kind| VehicleEvent::new(current, kind))
+        .collect()
+}
+
+fn is_awake(status: &VehicleStatus) -> bool {
+    status.availability == 1.0
+}
+
+fn is_unlocked_while_away(status: &VehicleStatus) -> bool {
+    status.locked == Some(false) && status.location == LOCATION_OTHER
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status() -> VehicleStatus {
+        VehicleStatus {
+            vin: "5YJ3E7EB2KF000000".into(),
+            display_name: "Tessie".into(),
+            location: "My Home".into(),
+            availability: 1.0,
+            locked: Some(true),
+            plugged_in: Some(false),
+            ..Default::default()
+        }
+    }
+
+    fn kinds(events: Vec<VehicleEvent>) -> Vec<VehicleEventKind> {
+        events.into_iter().map(|e| e.kind).collect()
+    }
+
+    #[test]
+    fn detect_events_returns_no_events_without_changes() {
+        // act
+        let events = detect_events(&status(), &status());
+
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn detect_events_returns_charging_and_plug_transitions() {
+        let previous = status();
+        let current = VehicleStatus {
+            charger_power: 11000.0,
+            plugged_in: Some(true),
+            ..status()
+        };
+
+        // act
+        let events = detect_events(&previous, &current);
+
+        assert_eq!(
+            kinds(events),
+            vec![
+                VehicleEventKind::ChargingStarted { power: 11000.0 },
+                VehicleEventKind::PlugConnected
+            ]
+        );
+    }
+
+    #[test]
+    fn detect_events_returns_geofence_transitions() {
+        let previous = status();
+        let current = VehicleStatus {
+            location: "Work".into(),
+            ..status()
+        };
+
+        // act
+        let events = detect_events(&previous, &current);
+
+        assert_eq!(
+            kinds(events),
+            vec![
+                VehicleEventKind::LeftGeofence {
+                    geofence: "My Home".into()
+                },
+                VehicleEventKind::ArrivedAtGeofence {
+                    geofence: "Work".into()
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn detect_events_returns_unlocked_while_away_once() {
+        let previous = VehicleStatus {
+            location: "Other".into(),
+            ..status()
+        };
+        let current = VehicleStatus {
+            location: "Other".into(),
+            locked: Some(false),
+            latitude: Some(52.0),
+            longitude: Some(4.0),
+            ..status()
+        };
+
+        // act
+        let events = detect_events(&previous, &current);
+        let repeated_events = detect_events(&current, &current);
+
+        assert_eq!(
+            kinds(events),
+            vec![VehicleEventKind::UnlockedWhileAway {
+                latitude: Some(52.0),
+                longitude: Some(4.0)
+            }]
+        );
+        assert!(repeated_events.is_empty());
+    }
+
+    #[test]
+    fn detect_events_returns_sleep_and_software_update_transitions() {
+        let previous = VehicleStatus {
+            availability: 0.0,
+            ..status()
+        };
+        let current = VehicleStatus {
+            software_update_version: Some("2023.20.4".into()),
+            ..status()
+        };
+
+        // act
+        let woke_up = detect_events(&previous, &current);
+        let fell_asleep = detect_events(&current, &previous);
+
+        assert_eq!(
+            kinds(woke_up),
+            vec![
+                VehicleEventKind::WokeUp,
+                VehicleEventKind::SoftwareUpdateAvailable {
+                    version: "2023.20.4".into()
+                }
+            ]
+        );
+        assert_eq!(kinds(fell_asleep), vec![VehicleEventKind::FellAsleep]);
+    }
+
+    #[test]
+    fn vehicle_event_serializes_type_and_schema_version() {
+        let event = VehicleEvent::new(&status(), VehicleEventKind::WokeUp);
+
+        // act
+        let json = serde_json::to_value(&event).unwrap();
+
+        assert_eq!(json["schemaVersion"], 1);
+        assert_eq!(json["type"], "wokeUp");
+        assert_eq!(json["vin"], "5YJ3E7EB2KF000000");
+    }
+}
@@ -0,0 +1,264 @@
+use crate::model::{AccountStates, ExporterState, VehicleState};
+use k8s_openapi::api::core::v1::{ConfigMap, Secret};
+use k8s_openapi::ByteString;
+use kube::api::{Api, PostParams};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::{debug, info, warn};
+
+const ACCOUNT_STATES_KEY: &str = "accounts.json";
+
+pub struct VehicleStateClientConfig {
+    kube_client: Option<kube::Client>,
+    state_file_path: String,
+    state_file_config_map_name: String,
+    /// secret holding the tokens per account, next to the configmap
+    token_secret_name: String,
+    /// read the state, but never store it
+    read_only: bool,
+}
+
+impl VehicleStateClientConfig {
+    pub fn new(
+        kube_client: Option<kube::Client>,
+        state_file_path: String,
+        state_file_config_map_name: String,
+        read_only: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        debug!(
+            "VehicleStateClientConfig::new(state_file_path: {}, state_file_config_map_name: {}, read_only: {})",
+            state_file_path, state_file_config_map_name, read_only
+        );
+
+        let token_secret_name = format!("{}-tokens", state_file_config_map_name);
+
+        Ok(Self {
+            kube_client,
+            state_file_path,
+            state_file_config_map_name,
+            token_secret_name,
+            read_only,
+        })
+    }
+
+    pub async fn from_env(read_only: bool) -> Result<Self, Box<dyn Error>> {
+        let kube_client: kube::Client = kube::Client::try_default().await?;
+
+        let state_file_path = env::var("VEHICLE_STATE_FILE_PATH")
+            .unwrap_or_else(|_| "/configs/vehicle-state.json".to_string());
+        let state_file_config_map_name = env::var("VEHICLE_STATE_CONFIG_MAP_NAME")
+            .or_else(|_| env::var("MEASUREMENT_FILE_CONFIG_MAP_NAME"))
+            .unwrap_or_else(|_| "jarvis-tesla-exporter".to_string());
+
+        Self::new(
+            Some(kube_client),
+            state_file_path,
+            state_file_config_map_name,
+            read_only,
+        )
+    }
+}
+
+/// Persists exporter state that doesn't fit in the last measurement, like the battery range history
+/// per vehicle; stored next to the last measurement in the exporter's configmap. The tokens per
+/// account go into a secret instead, or are only kept in memory when running outside kubernetes.
+pub struct VehicleStateClient {
+    config: VehicleStateClientConfig,
+    /// last read or stored account states
+    account_states: Mutex<Option<AccountStates>>,
+}
+
+impl VehicleStateClient {
+    pub fn new(config: VehicleStateClientConfig) -> Self {
+        Self {
+            config,
+            account_states: Mutex::new(None),
+        }
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.config.read_only
+    }
+
+    pub fn read_state(&self) -> Result<ExporterState, Box<dyn Error>> {
+        if !Path::new(&self.config.state_file_path).exists() {
+            info!(
+                "State file {} does not exist, starting without vehicle state",
+                self.config.state_file_path
+            );
+            return Ok(ExporterState::default());
+        }
+
+        let state_json = fs::read_to_string(&self.config.state_file_path)?;
+        if state_json.trim().is_empty() {
+            return Ok(ExporterState::default());
+        }
+
+        let state: serde_json::Value = serde_json::from_str(&state_json)?;
+        if state.get("vehicles").is_none() && state.get("accounts").is_none() {
+            // state stored before accounts were added only held the vehicles
+            let vehicles: HashMap<String, VehicleState> = serde_json::from_value(state)?;
+            return Ok(ExporterState {
+                vehicles,
+                ..Default::default()
+            });
+        }
+
+        Ok(serde_json::from_value(state)?)
+    }
+
+    pub async fn store_state(&self, state: &ExporterState) -> Result<(), Box<dyn Error>> {
+        if self.config.read_only {
+            info!("Not storing vehicle state, state is read only");
+            return Ok(());
+        }
+
+        let state_json = serde_json::to_string_pretty(state)?;
+
+        match &self.config.kube_client {
+            Some(kube_client) => {
+                let config_maps: Api<ConfigMap> =
+                    Api::namespaced(kube_client.clone(), kube_client.default_namespace());
+
+                let mut config_map = config_maps
+                    .get(&self.config.state_file_config_map_name)
+                    .await?;
+
+                config_map
+                    .data
+                    .get_or_insert_with(BTreeMap::new)
+                    .insert(self.state_file_key()?, state_json);
+
+                config_maps
+                    .replace(
+                        &self.config.state_file_config_map_name,
+                        &PostParams::default(),
+                        &config_map,
+                    )
+                    .await?;
+
+                info!(
+                    "Stored vehicle state in configmap {}",
+                    self.config.state_file_config_map_name
+                );
+            }
+            None => {
+                fs::write(&self.config.state_file_path, state_json)?;
+
+                info!("Stored vehicle state in {}", self.config.state_file_path);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn read_account_states(&self) -> Result<AccountStates, Box<dyn Error>> {
+        let account_states = match &self.config.kube_client {
+            Some(kube_client) => {
+                let secrets: Api<Secret> =
+                    Api::namespaced(kube_client.clone(), kube_client.default_namespace());
+
+                let stored = secrets
+                    .get_opt(&self.config.token_secret_name)
+                    .await?
+                    .and_then(|secret| secret.data)
+                    .and_then(|mut data| data.remove(ACCOUNT_STATES_KEY));
+
+                match stored {
+                    Some(account_states) => serde_json::from_slice(&account_states.0)?,
+                    None => self.read_configmap_account_states(),
+                }
+            }
+            None => self
+                .account_states
+                .lock()
+                .unwrap()
+                .clone()
+                .unwrap_or_default(),
+        };
+
+        *self.account_states.lock().unwrap() = Some(account_states.clone());
+
+        Ok(account_states)
+    }
+
+    /// Writes the secret only if the tokens changed since they were last read or stored.
+    pub async fn store_account_states(
+        &self,
+        account_states: &AccountStates,
+    ) -> Result<(), Box<dyn Error>> {
+        {
+            let mut last_account_states = self.account_states.lock().unwrap();
+            if last_account_states.as_ref() == Some(account_states) {
+                return Ok(());
+            }
+            *last_account_states = Some(account_states.clone());
+        }
+
+        if self.config.read_only {
+            info!("Not storing account tokens, state is read only");
+            return Ok(());
+        }
+
+        if let Some(kube_client) = &self.config.kube_client {
+            let secrets: Api<Secret> =
+                Api::namespaced(kube_client.clone(), kube_client.default_namespace());
+
+            let mut secret = secrets.get(&self.config.token_secret_name).await?;
+            secret.data.get_or_insert_with(BTreeMap::new).insert(
+                ACCOUNT_STATES_KEY.to_string(),
+                ByteString(serde_json::to_vec(account_states)?),
+            );
+
+            secrets
+                .replace(
+                    &self.config.token_secret_name,
+                    &PostParams::default(),
+                    &secret,
+                )
+                .await?;
+
+            info!(
+                "Stored account tokens in secret {}",
+                self.config.token_secret_name
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Tokens stored in the configmap before they moved to the secret, so a rotated refresh token
+    /// isn't lost; the next stored state leaves them out.
+    fn read_configmap_account_states(&self) -> AccountStates {
+        let accounts = fs::read_to_string(&self.config.state_file_path)
+            .ok()
+            .and_then(|state_json| serde_json::from_str::<serde_json::Value>(&state_json).ok())
+            .and_then(|mut state| state.get_mut("accounts").map(serde_json::Value::take));
+
+        match accounts.map(serde_json::from_value) {
+            Some(Ok(account_states)) => account_states,
+            Some(Err(e)) => {
+                warn!("Failed reading account tokens from the configmap: {}", e);
+                AccountStates::default()
+            }
+            None => AccountStates::default(),
+        }
+    }
+
+    fn state_file_key(&self) -> Result<String, Box<dyn Error>> {
+        Path::new(&self.config.state_file_path)
+            .file_name()
+            .and_then(|f| f.to_str())
+            .map(str::to_string)
+            .ok_or_else(|| {
+                Box::<dyn Error>::from(format!(
+                    "State file path {} has no file name",
+                    self.config.state_file_path
+                ))
+            })
+    }
+}
@@ -0,0 +1,349 @@
+use crate::model::{ComparisonOperator, VehicleStatus, WebhookConditionConfig, WebhookRuleConfig};
+use crate::vehicle_events::VehicleEvent;
+use chrono::{DateTime, Duration, Utc};
+use reqwest::header::AUTHORIZATION;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use tracing::{debug, info, warn};
+
+/// Posts messages to webhooks for the rules a vehicle matches, like a chat bot or ntfy.
+pub struct WebhookClient {
+    http_client: reqwest::Client,
+}
+
+impl WebhookClient {
+    pub fn new(http_client: reqwest::Client) -> Self {
+        Self { http_client }
+    }
+
+    /// Fires the matching rules that aren't cooling down, recording when they were sent in `sent_at`.
+    pub async fn notify(
+        &self,
+        rules: &[WebhookRuleConfig],
+        sent_at: &mut HashMap<String, DateTime<Utc>>,
+        status: &VehicleStatus,
+        previous_status: Option<&VehicleStatus>,
+        events: &[VehicleEvent],
+    ) {
+        let context = rule_context(status, previous_status, events);
+        let now = Utc::now();
+
+        for rule in rules {
+            if !rule_matches(rule, &context) {
+                continue;
+            }
+
+            let key = format!("{}/{}", rule.name, status.vin);
+            if let Some(last_sent_at) = sent_at.get(&key) {
+                if now - *last_sent_at < Duration::minutes(rule.cooldown_minutes) {
+                    debug!(
+                        "Webhook rule {} matches vehicle {}, but is cooling down",
+                        rule.name, status.vin
+                    );
+                    continue;
+                }
+            }
+
+            match self.post(rule, &context).await {
+                Ok(()) => {
+                    info!(
+                        "Sent webhook for rule {} and vehicle {}",
+                        rule.name, status.vin
+                    );
+                    sent_at.insert(key, now);
+                }
+                Err(e) => warn!(
+                    "Failed sending webhook for rule {} and vehicle {}: {}",
+                    rule.name, status.vin, e
+                ),
+            }
+        }
+    }
+
+    async fn post(&self, rule: &WebhookRuleConfig, context: &Value) -> Result<(), Box<dyn Error>> {
+        let mut request = self
+            .http_client
+            .post(&rule.url)
+            .json(&render_body(rule, context));
+        if let Some(authorization) = &rule.authorization {
+            if let Some(authorization) = authorization.resolve().await? {
+                request = request.header(AUTHORIZATION, authorization);
+            }
+        }
+
+        let response = request.send().await?;
+
+        if !response.status().is_success() {
+            return Err(format!("Webhook returned status {}", response.status()).into());
+        }
+
+        Ok(())
+    }
+}
+
+/// The status with `previous` and the types of the `events` added, for conditions and templates.
+fn rule_context(
+    status: &VehicleStatus,
+    previous_status: Option<&VehicleStatus>,
+    events: &[VehicleEvent],
+) -> Value {
+    let mut context = serde_json::to_value(status).unwrap_or_else(|_| json!({}));
+    context["previous"] = previous_status
+        .and_then(|s| serde_json::to_value(s).ok())
+        .unwrap_or(Value::Null);
+    context["events"] = events
+        .iter()
+        .filter_map(|e| serde_json::to_value(e).ok())
+        .filter_map(|e| e.get("type").cloned())
+        .collect();
+
+    context
+}
+
+fn rule_matches(rule: &WebhookRuleConfig, context: &Value) -> bool {
+    if let Some(event) = &rule.event {
+        let occurred = context["events"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .any(|e| e == event);
+        if !occurred {
+            return false;
+        }
+    }
+
+    rule.conditions
+        .iter()
+        .all(|condition| condition_matches(condition, context))
+}
+
+fn condition_matches(condition: &WebhookConditionConfig, context: &Value) -> bool {
+    let left = lookup(context, &condition.field);
+    let right = match (&condition.other_field, &condition.value) {
+        (Some(other_field), _) => lookup(context, other_field),
+        (None, Some(value)) => value,
+        (None, None) => &Value::Null,
+    };
+
+    // unknown values, like the lock state of a vehicle that hasn't been awake, never match
+    if left.is_null() || right.is_null() {
+        return false;
+    }
+
+    match condition.operator {
+        ComparisonOperator::Equals => values_equal(left, right),
+        ComparisonOperator::NotEquals => !values_equal(left, right),
+        operator => match (left.as_f64(), right.as_f64()) {
+            (Some(left), Some(right)) => match operator {
+                ComparisonOperator::LessThan => left < right,
+                ComparisonOperator::LessThanOrEquals => left <= right,
+                ComparisonOperator::GreaterThan => left > right,
+                _ => left >= right,
+            },
+            _ => false,
+        },
+    }
+}
+
+/// Numbers compare by value, so `-2` in yaml equals an availability of `-2.0`.
+fn values_equal(left: &Value, right: &Value) -> bool {
+    match (left.as_f64(), right.as_f64()) {
+        (Some(left), Some(right)) => left == right,
+        _ => left == right,
+    }
+}
+
+/// Value at a dotted path like `previous.location`, or null.
+fn lookup<'a>(context: &'a Value, path: &str) -> &'a Value {
+    path.split('.')
+        .fold(context, |value, key| value.get(key).unwrap_or(&Value::Null))
+}
+
+/// Replaces `{field}` placeholders with the values from the context.
+fn render(template: &str, context: &Value) -> String {
+    let mut rendered = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        rendered.push_str(&rest[..start]);
+        rendered.push_str(&format_value(lookup(context, &rest[start + 1..end])));
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+
+    rendered
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::Null => "unknown".to_string(),
+        Value::String(s) => s.clone(),
+        Value::Number(n) => match n.as_f64() {
+            Some(f) if f.fract() != 0.0 => format!("{:.1}", f),
+            // a float like 80.0 reads better as 80
+            Some(f) => format!("{}", f),
+            None => n.to_string(),
+        },
+        other => other.to_string(),
+    }
+}
+
+fn render_body(rule: &WebhookRuleConfig, context: &Value) -> Value {
+    let message = render(&rule.message, context);
+
+    match &rule.body {
+        Some(body) => {
+            let mut context = context.clone();
+            context["message"] = json!(message);
+            render_strings(body, &context)
+        }
+        None => json!({
+            "rule": rule.name,
+            "vin": context["vin"],
+            "displayName": context["displayName"],
+            "message": message,
+        }),
+    }
+}
+
+fn render_strings(template: &Value, context: &Value) -> Value {
+    match template {
+        Value::String(s) => Value::String(render(s, context)),
+        Value::Array(values) => values.iter().map(|v| render_strings(v, context)).collect(),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), render_strings(v, context)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status() -> VehicleStatus {
+        VehicleStatus {
+            vin: "5YJ3E7EB2KF000000".into(),
+            display_name: "Tessie".into(),
+            location: "My Home".into(),
+            availability: 1.0,
+            soc: Some(62.5),
+            charge_limit_soc: Some(80.0),
+            ..Default::default()
+        }
+    }
+
+    fn rule(yaml: &str) -> WebhookRuleConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn rule_matches_event_with_condition_on_other_field() {
+        let rule = rule(
+            r#"
+name: charging-interrupted
+url: http://localhost/hook
+event: chargingStopped
+conditions:
+- field: soc
+  operator: lessThan
+  otherField: chargeLimitSoc
+message: "{displayName} stopped charging at {soc}% of {chargeLimitSoc}%"
+"#,
+        );
+        let previous = VehicleStatus {
+            charger_power: 11000.0,
+            ..status()
+        };
+        let events = crate::vehicle_events::detect_events(&previous, &status());
+        let context = rule_context(&status(), Some(&previous), &events);
+
+        // act
+        let matches = rule_matches(&rule, &context);
+
+        assert!(matches);
+        assert_eq!(
+            render_body(&rule, &context)["message"],
+            json!("Tessie stopped charging at 62.5% of 80%")
+        );
+    }
+
+    #[test]
+    fn rule_matches_requires_event() {
+        let rule = rule(
+            r#"
+name: charging-interrupted
+url: http://localhost/hook
+event: chargingStopped
+message: stopped
+"#,
+        );
+        let context = rule_context(&status(), Some(&status()), &[]);
+
+        // act
+        let matches = rule_matches(&rule, &context);
+
+        assert!(!matches);
+    }
+
+    #[test]
+    fn condition_matches_numbers_and_unknown_values() {
+        let context = rule_context(
+            &VehicleStatus {
+                availability: -2.0,
+                ..status()
+            },
+            None,
+            &[],
+        );
+        let in_service = rule(
+            "{name: in-service, url: x, message: m, conditions: [{field: availability, operator: equals, value: -2}]}",
+        );
+        let unlocked = rule(
+            "{name: unlocked, url: x, message: m, conditions: [{field: locked, operator: equals, value: false}]}",
+        );
+        let previous_home = rule(
+            "{name: left, url: x, message: m, conditions: [{field: previous.location, operator: equals, value: My Home}]}",
+        );
+
+        // act
+        let matches = (
+            rule_matches(&in_service, &context),
+            rule_matches(&unlocked, &context),
+            rule_matches(&previous_home, &context),
+        );
+
+        assert_eq!(matches, (true, false, false));
+    }
+
+    #[test]
+    fn render_body_fills_placeholders_in_custom_body() {
+        let rule = rule(
+            r#"
+name: unlocked
+url: https://ntfy.sh
+message: "{displayName} is unlocked at {location}"
+body:
+  topic: my-car
+  message: "{message}"
+  priority: 4
+"#,
+        );
+        let context = rule_context(&status(), None, &[]);
+
+        // act
+        let body = render_body(&rule, &context);
+
+        assert_eq!(
+            body,
+            json!({"topic": "my-car", "message": "Tessie is unlocked at My Home", "priority": 4})
+        );
+    }
+}